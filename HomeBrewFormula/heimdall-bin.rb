@@ -10,6 +10,7 @@ class HeimdallBin < Formula
 
   def install
     bin.install "heimdall"
+    bin.install "heimdall-agent"
     etc.install "etc/heimdall"
   end
 end
\ No newline at end of file
@@ -11,3 +11,11 @@ cp -f org.dubh.heimdall.plist /Library/LaunchDaemons/
 launchctl unload /Library/LaunchDaemons/org.dubh.heimdall.plist
 launchctl load /Library/LaunchDaemons/org.dubh.heimdall.plist
 launchctl start org.dubh.heimdall
+cp -f org.dubh.heimdall.agent.plist /Library/LaunchAgents/
+
+# Start the agent for anyone already logged in; it starts by itself at login.
+for user in $(who | awk '$2 == "console" { print $1 }' | sort -u); do
+  uid=$(id -u "$user")
+  launchctl bootout gui/$uid /Library/LaunchAgents/org.dubh.heimdall.agent.plist 2>/dev/null
+  launchctl bootstrap gui/$uid /Library/LaunchAgents/org.dubh.heimdall.agent.plist
+done
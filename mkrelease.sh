@@ -12,10 +12,13 @@ else
   cargo build --release
   cp target/release/heimdall release/$version/
   strip release/$version/heimdall
+  cp target/release/heimdall-agent release/$version/
+  strip release/$version/heimdall-agent
 
   mkdir -p release/$version/etc/heimdall
   cp install.sh release/$version/etc/heimdall
   cp org.dubh.heimdall.plist release/$version/etc/heimdall
+  cp org.dubh.heimdall.agent.plist release/$version/etc/heimdall
   cp -r static release/$version/etc/heimdall
 fi
 
@@ -0,0 +1,294 @@
+//! The daemon's side of the connection to heimdall-agent. The daemon runs as
+//! root outside of any user session, so anything that needs to appear on a
+//! user's desktop goes through the agent running in that user's session.
+
+use crate::os;
+use crate::protocol::{self, AgentMessage, DaemonMessage};
+use anyhow::{anyhow, Result};
+use log::{error, info, warn};
+use std::{
+  collections::HashMap,
+  fs,
+  io::BufReader,
+  os::unix::fs::PermissionsExt,
+  os::unix::net::{UnixListener, UnixStream},
+  path::Path,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+  },
+  thread,
+  time::{Duration, Instant},
+};
+
+/// An agent that hasn't sent a heartbeat for this long is presumed dead.
+const AGENT_TIMEOUT: Duration = Duration::from_secs(90);
+
+struct ConnectedAgent {
+  id: u64,
+  writer: UnixStream,
+  last_seen: Instant,
+}
+
+type Agents = Arc<Mutex<HashMap<String, ConnectedAgent>>>;
+
+pub struct AgentServer {
+  agents: Agents,
+}
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+
+impl AgentServer {
+  /// Starts listening for agents on the given socket path.
+  pub fn start(path: &Path) -> Result<AgentServer> {
+    if let Some(parent) = path.parent() {
+      fs::create_dir_all(parent)?;
+    }
+    if path.exists() {
+      // Left over from a previous run.
+      fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    // Agents run as the logged in user, not root, so anyone can connect; each
+    // agent is checked against the user it claims to be when it does.
+    fs::set_permissions(path, fs::Permissions::from_mode(0o666))?;
+    info!("Listening for agents on {}", path.display());
+
+    let agents: Agents = Arc::new(Mutex::new(HashMap::new()));
+    let accepted = Arc::clone(&agents);
+    thread::spawn(move || {
+      for stream in listener.incoming() {
+        match stream {
+          Ok(stream) => {
+            let agents = Arc::clone(&accepted);
+            thread::spawn(move || {
+              if let Err(e) = handle_connection(stream, agents) {
+                warn!("Agent connection failed: {}", e);
+              }
+            });
+          }
+          Err(e) => error!("Failed to accept agent connection: {}", e),
+        }
+      }
+    });
+
+    Ok(AgentServer { agents })
+  }
+
+  /// Is an agent for this user connected and recently heard from?
+  pub fn is_alive(&self, username: &str) -> bool {
+    match self.agents.lock().unwrap().get(username) {
+      Some(agent) => agent.last_seen.elapsed() < AGENT_TIMEOUT,
+      None => false,
+    }
+  }
+
+  /// Sends a message to the agent for the given user. Returns false if there
+  /// is no agent for that user.
+  pub fn send(&self, username: &str, message: &DaemonMessage) -> Result<bool> {
+    let mut agents = self.agents.lock().unwrap();
+    let result = match agents.get_mut(username) {
+      Some(agent) => protocol::write_message(&mut agent.writer, message),
+      None => return Ok(false),
+    };
+
+    if result.is_err() {
+      // The agent has gone away; forget about it.
+      agents.remove(username);
+    }
+
+    result.map(|_| true)
+  }
+}
+
+fn handle_connection(stream: UnixStream, agents: Agents) -> Result<()> {
+  let mut writer = stream.try_clone()?;
+  writer.set_write_timeout(Some(Duration::from_secs(5)))?;
+  let mut reader = BufReader::new(stream);
+
+  let username = match protocol::read_message(&mut reader) {
+    Ok(Some(AgentMessage::Hello { username })) => check_peer(reader.get_ref(), &username).map(|_| username),
+    Ok(Some(message)) => return Err(anyhow!("Expected hello, got {:?}", message)),
+    Ok(None) => return Ok(()),
+    Err(e) => Err(e),
+  };
+  let username = match username {
+    Ok(username) => username,
+    Err(e) => {
+      let reason = e.to_string();
+      protocol::write_message(&mut writer, &DaemonMessage::Rejected { reason })?;
+      return Err(e);
+    }
+  };
+
+  protocol::write_message(&mut writer, &DaemonMessage::Welcome)?;
+  info!("Agent connected for {}", username);
+
+  let id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::SeqCst);
+  agents.lock().unwrap().insert(
+    username.clone(),
+    ConnectedAgent {
+      id,
+      writer,
+      last_seen: Instant::now(),
+    },
+  );
+
+  let result = read_heartbeats(&mut reader, &username, &agents);
+
+  info!("Agent disconnected for {}", username);
+  let mut agents = agents.lock().unwrap();
+  // Only forget the agent if it hasn't since been replaced by a new one.
+  if agents.get(&username).map(|a| a.id) == Some(id) {
+    agents.remove(&username);
+  }
+
+  result
+}
+
+/// Checks the agent at the other end of the socket is running as the user it
+/// says it is, so it can't listen in on anybody else's warnings.
+fn check_peer(stream: &UnixStream, username: &str) -> Result<()> {
+  let uid = stream.peer_cred()?.uid;
+  match os::get_user_id(username) {
+    Ok(expected) if expected == uid => Ok(()),
+    _ => Err(anyhow!("Agent for {} is running as uid {}", username, uid)),
+  }
+}
+
+fn read_heartbeats(reader: &mut BufReader<UnixStream>, username: &str, agents: &Agents) -> Result<()> {
+  while let Some(message) = protocol::read_message(reader)? {
+    match message {
+      AgentMessage::Heartbeat => {
+        if let Some(agent) = agents.lock().unwrap().get_mut(username) {
+          agent.last_seen = Instant::now();
+        }
+      }
+      AgentMessage::Hello { .. } => warn!("Unexpected hello from agent for {}", username),
+    }
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::{env, io::Write, path::PathBuf};
+
+  fn socket_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("heimdall-test-{}-{}.sock", name, std::process::id()))
+  }
+
+  fn connect(path: &Path, username: &str) -> Result<(UnixStream, BufReader<UnixStream>)> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let reader = BufReader::new(stream.try_clone()?);
+    protocol::write_message(
+      &mut stream,
+      &AgentMessage::Hello {
+        username: username.to_owned(),
+      },
+    )?;
+
+    Ok((stream, reader))
+  }
+
+  /// Agents have to connect as the user running the tests.
+  fn me() -> String {
+    whoami::username()
+  }
+
+  fn wait_for_agent(server: &AgentServer, username: &str) {
+    for _ in 0..100 {
+      if server.is_alive(username) {
+        return;
+      }
+      thread::sleep(Duration::from_millis(10));
+    }
+    panic!("Agent for {} never connected", username);
+  }
+
+  #[test]
+  fn test_agent_receives_messages() -> Result<()> {
+    let path = socket_path("messages");
+    let server = AgentServer::start(&path)?;
+
+    let (_stream, mut reader) = connect(&path, &me())?;
+    assert_eq!(Some(DaemonMessage::Welcome), protocol::read_message(&mut reader)?);
+    wait_for_agent(&server, &me());
+
+    let warning = DaemonMessage::Warning {
+      title: "Heimdall".to_owned(),
+      message: "Five minutes left".to_owned(),
+      critical: false,
+    };
+    assert!(server.send(&me(), &warning)?);
+    assert_eq!(Some(warning), protocol::read_message(&mut reader)?);
+
+    assert!(!server.send("somebody_else", &DaemonMessage::Welcome)?);
+
+    fs::remove_file(&path)?;
+    Ok(())
+  }
+
+  #[test]
+  fn test_rejects_other_versions() -> Result<()> {
+    let path = socket_path("versions");
+    let _server = AgentServer::start(&path)?;
+
+    let mut stream = UnixStream::connect(&path)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    stream.write_all(b"{\"version\":0,\"message\":{\"type\":\"hello\",\"username\":\"test\"}}\n")?;
+
+    match protocol::read_message(&mut reader)? {
+      Some(DaemonMessage::Rejected { .. }) => {}
+      other => panic!("Expected rejection, got {:?}", other),
+    }
+
+    fs::remove_file(&path)?;
+    Ok(())
+  }
+
+  #[test]
+  fn test_rejects_other_users() -> Result<()> {
+    let path = socket_path("impostor");
+    let server = AgentServer::start(&path)?;
+
+    let someone_else = if me() == "root" { "nobody" } else { "root" };
+    let (_stream, mut reader) = connect(&path, someone_else)?;
+    match protocol::read_message(&mut reader)? {
+      Some(DaemonMessage::Rejected { .. }) => {}
+      other => panic!("Expected rejection, got {:?}", other),
+    }
+    assert!(!server.is_alive(someone_else));
+
+    fs::remove_file(&path)?;
+    Ok(())
+  }
+
+  #[test]
+  fn test_forgets_disconnected_agents() -> Result<()> {
+    let path = socket_path("disconnect");
+    let server = AgentServer::start(&path)?;
+
+    let (stream, mut reader) = connect(&path, &me())?;
+    protocol::read_message::<_, DaemonMessage>(&mut reader)?;
+    wait_for_agent(&server, &me());
+
+    drop(reader);
+    drop(stream);
+    for _ in 0..100 {
+      if !server.is_alive(&me()) {
+        break;
+      }
+      thread::sleep(Duration::from_millis(10));
+    }
+    assert!(!server.is_alive(&me()));
+
+    fs::remove_file(&path)?;
+    Ok(())
+  }
+}
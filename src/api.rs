@@ -1,22 +1,189 @@
 use anyhow::{anyhow, Result};
-use rocket::{Route, response::status};
+use rocket::{Request, Route, response::{self, status, Responder}};
+use rocket::http::{ContentType, Status as HttpStatus};
 use rocket_contrib::json::Json;
 use serde::{Deserialize, Serialize};
-use rocket::response::Debug;
+use rocket::response::{content::Content, Debug};
 
 use crate::config;
 use crate::constants;
+use crate::dsl;
+use crate::feed::Feeds;
+use crate::ical::{self, ScheduleDiff};
+use crate::ledger::Transaction;
 use crate::os;
+use crate::rules::Decision;
+use crate::schedule::{self, Policy, Source, Transition, Zone};
+use crate::state::{self, Flag, Override, Pause, SharedState};
+use crate::validate::{self, ValidationError, ValidationErrors};
+use crate::verdict::{self, Reason, Span};
 
 use os::User;
-use config::UserConfig;
+use config::{CronRule, OpenPeriod, Rule, ScheduleException, Template, UserConfig};
 
+use chrono::{DateTime, Duration, Local, NaiveDate};
 use log::info;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::Read;
+use std::net::SocketAddr;
 
 #[derive(Serialize, Deserialize, Debug)]
 struct Status {
     is_configured: bool,
     hostname: String,
+    overrides: HashMap<String, Override>,
+    pause: Option<Pause>,
+    /// What's wrong with the config file, if it's being ignored.
+    config_errors: Option<ValidationErrors>,
+}
+
+#[derive(Serialize, Debug)]
+struct Usage {
+    date: NaiveDate,
+    used_minutes: u64,
+    quota_minutes: Option<u32>,
+    remaining_minutes: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+struct LedgerSummary {
+    balance_minutes: u64,
+    transactions: Vec<Transaction>,
+}
+
+/// The API doesn't log anyone in, so there's no author to trust in the
+/// request; the credit records where the request came from instead.
+#[derive(Deserialize, Debug)]
+struct Grant {
+    minutes: u32,
+    reason: String,
+    #[serde(default)]
+    expires: Option<DateTime<Local>>,
+}
+
+/// Either `minutes` or `until` says when the override ends.
+#[derive(Deserialize, Debug)]
+struct OverrideRequest {
+    locked: bool,
+    #[serde(default)]
+    minutes: Option<u32>,
+    #[serde(default)]
+    until: Option<DateTime<Local>>,
+    #[serde(default)]
+    reason: String,
+}
+
+/// Without `minutes` or `until`, the pause lasts until resumed.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct PauseRequest {
+    #[serde(default)]
+    pub minutes: Option<u32>,
+    #[serde(default)]
+    pub until: Option<DateTime<Local>>,
+    #[serde(default)]
+    pub reason: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct PauseCodeRequest {
+    pub code: String,
+}
+
+/// What the runloop makes of a user right now, and when the schedule next
+/// locks or unlocks them.
+#[derive(Serialize, Debug)]
+struct NextTransition {
+    now: DateTime<Local>,
+    /// Whether the user should be locked right now, and what decided it.
+    locked: bool,
+    reason: Reason,
+    /// When they'll be locked, if they're unlocked and it's known. This
+    /// allows for the quota, banked time, rules and overrides.
+    lock_at: Option<DateTime<Local>>,
+    minutes_remaining: Option<i64>,
+    transition: Option<Transition>,
+    #[serde(rename = "override")]
+    manual: Option<Override>,
+    pause: Option<Pause>,
+}
+
+/// A user's rules, and what they say to do right now.
+#[derive(Serialize, Debug)]
+struct RulesStatus<'a> {
+    rules: &'a [Rule],
+    decision: Option<Decision>,
+}
+
+#[derive(Serialize, Debug)]
+struct TemplatePreview {
+    date: NaiveDate,
+    template: Option<String>,
+}
+
+/// A user's rules on a date, and where each came from.
+#[derive(Serialize, Debug)]
+struct EffectivePolicy<'a> {
+    date: NaiveDate,
+    group: Option<&'a str>,
+    template: Option<&'a str>,
+    periods: Vec<SourcedPeriod<'a>>,
+    cron_rules: Vec<&'a CronRule>,
+    blackout_periods: Vec<&'a OpenPeriod>,
+    removed_periods: &'a [OpenPeriod],
+    exceptions: Vec<&'a ScheduleException>,
+}
+
+#[derive(Serialize, Debug)]
+struct SourcedPeriod<'a> {
+    source: Source<'a>,
+    #[serde(flatten)]
+    period: &'a OpenPeriod,
+}
+
+/// What importing a calendar changes, or would change.
+#[derive(Serialize, Debug)]
+struct ImportPreview {
+    diff: ScheduleDiff,
+    exceptions: Vec<ScheduleException>,
+    warnings: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct FeedToken {
+    token: String,
+}
+
+/// An error from a handler that writes the config. Invalid configs are
+/// rejected with a 422 listing what's wrong; anything else is a 500.
+#[derive(Debug)]
+enum ApiError {
+    Invalid(ValidationErrors),
+    Other(anyhow::Error),
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast::<ValidationErrors>() {
+            Ok(errors) => ApiError::Invalid(errors),
+            Err(e) => ApiError::Other(e),
+        }
+    }
+}
+
+impl<'r> Responder<'r> for ApiError {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        match self {
+            ApiError::Invalid(errors) => status::Custom(HttpStatus::UnprocessableEntity, Json(errors)).respond_to(request),
+            ApiError::Other(e) => Debug(e).respond_to(request),
+        }
+    }
+}
+
+type WriteResult<T> = std::result::Result<T, ApiError>;
+
+fn is_configured_user(name: &str) -> Result<bool> {
+    Ok(config::load()?.user_config.contains_key(name))
 }
 
 // #[post("/say", data = "<message>")]
@@ -32,12 +199,23 @@ fn users() -> Result<Json<Vec<User>>> {
 }
 
 #[get("/status")]
-fn status() -> Result<Json<Status>> {
-    let config = config::load()?;
+fn status(state: rocket::State<SharedState>) -> Result<Json<Status>> {
+    let (config, config_errors) = config::load_enforced()?;
+    let now = Local::now();
+    let state = state.lock().unwrap();
+    let overrides = state
+        .overrides
+        .iter()
+        .filter(|(_, o)| now < o.until)
+        .map(|(user, o)| (user.to_owned(), o.clone()))
+        .collect();
 
     Ok(Json(Status {
         is_configured: !config.is_new(),
         hostname: hostname::get()?.to_str().unwrap().to_owned(),
+        overrides,
+        pause: state.get_pause(now).cloned(),
+        config_errors,
     }))
 }
 
@@ -52,14 +230,611 @@ fn index() -> Result<String> {
     .to_string())
 }
 
+#[get("/users/<name>/usage")]
+fn usage(name: String, state: rocket::State<SharedState>) -> Result<Option<Json<Usage>>> {
+    let (config, _) = config::load_enforced()?;
+    let user_config = match config.user_config.get(&name) {
+        Some(user_config) => user_config,
+        None => return Ok(None),
+    };
+
+    let today = Zone::for_config(&config).date(Local::now());
+    let used_minutes = state.lock().unwrap().get_usage(&name, today) / 60;
+    let quota_minutes = user_config.daily_quota_minutes;
+
+    Ok(Some(Json(Usage {
+        date: today,
+        used_minutes,
+        quota_minutes,
+        remaining_minutes: quota_minutes.map(|q| u64::from(q).saturating_sub(used_minutes)),
+    })))
+}
+
+#[get("/users/<name>/ledger")]
+fn ledger(name: String, state: rocket::State<SharedState>) -> Result<Option<Json<LedgerSummary>>> {
+    if !is_configured_user(&name)? {
+        return Ok(None);
+    }
+
+    let mut state = state.lock().unwrap();
+    let balance_minutes = state.get_balance(&name, Local::now()) / 60;
+    Ok(Some(Json(LedgerSummary {
+        balance_minutes,
+        transactions: state.ledger(&name).transactions.clone(),
+    })))
+}
+
+#[post("/users/<name>/ledger", data = "<grant>")]
+fn grant(
+    name: String,
+    grant: Json<Grant>,
+    remote: SocketAddr,
+    state: rocket::State<SharedState>,
+) -> Result<Option<Json<Transaction>>> {
+    if !is_configured_user(&name)? {
+        return Ok(None);
+    }
+
+    let grant = grant.into_inner();
+    let author = format!("api ({})", remote.ip());
+    let mut state = state.lock().unwrap();
+    info!("{} granted {} minutes to {}: {}", author, grant.minutes, name, grant.reason);
+    let transaction = state
+        .ledger(&name)
+        .grant(Local::now(), u64::from(grant.minutes) * 60, &grant.reason, &author, grant.expires)
+        .clone();
+    state::save(&state)?;
+
+    Ok(Some(Json(transaction)))
+}
+
+#[delete("/users/<name>/ledger/<id>")]
+fn revoke(name: String, id: u64, state: rocket::State<SharedState>) -> Result<Option<status::Accepted<String>>> {
+    if !is_configured_user(&name)? {
+        return Ok(None);
+    }
+
+    let mut state = state.lock().unwrap();
+    info!("Revoking transaction {} for {}", id, name);
+    if !state.ledger(&name).revoke(id)? {
+        return Ok(None);
+    }
+    state::save(&state)?;
+
+    Ok(Some(status::Accepted(None)))
+}
+
+#[put("/users/<name>/override", data = "<request>")]
+fn set_override(name: String, request: Json<OverrideRequest>, state: rocket::State<SharedState>) -> Result<Option<Json<Override>>> {
+    if !is_configured_user(&name)? {
+        return Ok(None);
+    }
+
+    let request = request.into_inner();
+    let until = match (request.until, request.minutes) {
+        (Some(until), _) => until,
+        (None, Some(minutes)) => Local::now() + chrono::Duration::minutes(minutes.into()),
+        (None, None) => return Err(anyhow!("An override needs either minutes or until")),
+    };
+    let manual = Override {
+        locked: request.locked,
+        until,
+        reason: request.reason,
+    };
+
+    let mut state = state.lock().unwrap();
+    info!("Overriding {} until {}: {:?}", name, until, manual);
+    state.overrides.insert(name, manual.clone());
+    state::save(&state)?;
+
+    Ok(Some(Json(manual)))
+}
+
+#[delete("/users/<name>/override")]
+fn cancel_override(name: String, state: rocket::State<SharedState>) -> Result<Option<status::Accepted<String>>> {
+    let mut state = state.lock().unwrap();
+    match state.overrides.remove(&name) {
+        Some(_) => {
+            info!("Cancelled override for {}", name);
+            state::save(&state)?;
+            Ok(Some(status::Accepted(None)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// The user's flags that are set now.
+#[get("/users/<name>/flags")]
+fn flags(name: String, state: rocket::State<SharedState>) -> Result<Option<Json<BTreeMap<String, Flag>>>> {
+    if !is_configured_user(&name)? {
+        return Ok(None);
+    }
+
+    let now = Local::now();
+    let state = state.lock().unwrap();
+    let flags = match state.flags.get(&name) {
+        Some(flags) => flags.iter().filter(|(_, f)| f.is_set(now)).map(|(n, f)| (n.clone(), f.clone())).collect(),
+        None => BTreeMap::new(),
+    };
+
+    Ok(Some(Json(flags)))
+}
+
+/// Sets a flag for the user's rules, for `minutes` or until it's cleared.
+#[put("/users/<name>/flags/<flag>?<minutes>")]
+fn set_flag(name: String, flag: String, minutes: Option<u32>, state: rocket::State<SharedState>) -> Result<Option<Json<Flag>>> {
+    if !is_configured_user(&name)? {
+        return Ok(None);
+    }
+
+    let now = Local::now();
+    let until = minutes.map(|minutes| now + Duration::minutes(minutes.into()));
+    let mut state = state.lock().unwrap();
+    info!("Setting flag {} for {} until {:?}", flag, name, until);
+    let set = state.set_flag(&name, &flag, now, until);
+    state::save(&state)?;
+
+    Ok(Some(Json(set)))
+}
+
+#[delete("/users/<name>/flags/<flag>")]
+fn clear_flag(name: String, flag: String, state: rocket::State<SharedState>) -> Result<Option<status::Accepted<String>>> {
+    let mut state = state.lock().unwrap();
+    match state.clear_flag(&name, &flag) {
+        true => {
+            info!("Cleared flag {} for {}", flag, name);
+            state::save(&state)?;
+            Ok(Some(status::Accepted(None)))
+        }
+        false => Ok(None),
+    }
+}
+
+fn start_pause(state: &SharedState, pause: Pause) -> Result<Json<Pause>> {
+    let mut state = state.lock().unwrap();
+    info!("Pausing everyone: {:?}", pause);
+    state.pause = Some(pause.clone());
+    state::save(&state)?;
+
+    Ok(Json(pause))
+}
+
+#[post("/pause", data = "<request>")]
+fn pause(request: Json<PauseRequest>, state: rocket::State<SharedState>) -> Result<Json<Pause>> {
+    let request = request.into_inner();
+    let now = Local::now();
+    let until = request
+        .until
+        .or_else(|| request.minutes.map(|minutes| now + chrono::Duration::minutes(minutes.into())));
+
+    let pause = Pause {
+        since: now,
+        until,
+        reason: request.reason,
+    };
+    start_pause(&state, pause)
+}
+
+#[post("/pause/code", data = "<request>")]
+fn pause_with_code(request: Json<PauseCodeRequest>, state: rocket::State<SharedState>) -> Result<Option<Json<Pause>>> {
+    // Only the codes are read from the config, so a mistake elsewhere in it
+    // doesn't get in the way.
+    let config = config::load()?;
+    let code = match config.pause_code(&request.code) {
+        Some(code) => code,
+        None => return Ok(None),
+    };
+    if !state.lock().unwrap().use_pause_code(&code.code) {
+        return Ok(None);
+    }
+
+    let now = Local::now();
+    let pause = Pause {
+        since: now,
+        until: code.minutes.map(|minutes| now + chrono::Duration::minutes(minutes.into())),
+        reason: "Pause code".to_owned(),
+    };
+    Ok(Some(start_pause(&state, pause)?))
+}
+
+#[delete("/pause")]
+fn resume(state: rocket::State<SharedState>) -> Result<Option<status::Accepted<String>>> {
+    let mut state = state.lock().unwrap();
+    match state.pause.take() {
+        Some(_) => {
+            info!("Resuming everyone");
+            state::save(&state)?;
+            Ok(Some(status::Accepted(None)))
+        }
+        None => Ok(None),
+    }
+}
+
+#[get("/templates")]
+fn templates() -> Result<Json<HashMap<String, Template>>> {
+    Ok(Json(config::load()?.templates))
+}
+
+#[put("/templates/<name>", data = "<template>")]
+fn save_template(name: String, template: Json<Template>) -> WriteResult<status::Accepted<String>> {
+    let mut config = config::load()?;
+    info!("Saving template {}", name);
+    config.templates.insert(name, template.into_inner());
+    config::save(&config)?;
+
+    Ok(status::Accepted(None))
+}
+
+#[delete("/templates/<name>")]
+fn delete_template(name: String) -> WriteResult<Option<status::Accepted<String>>> {
+    let mut config = config::load()?;
+    let users: Vec<&str> = config
+        .user_config
+        .values()
+        .filter(|u| u.templates.contains(&name))
+        .map(|u| u.username.as_str())
+        .collect();
+    if !users.is_empty() {
+        return Err(ApiError::Other(anyhow!("Template {} is still used by {}", name, users.join(", "))));
+    }
+
+    match config.templates.remove(&name) {
+        Some(_) => {
+            info!("Deleting template {}", name);
+            config::save(&config)?;
+            Ok(Some(status::Accepted(None)))
+        }
+        None => Ok(None),
+    }
+}
+
+#[get("/users/<name>/next")]
+fn next_transition(
+    name: String,
+    state: rocket::State<SharedState>,
+    feeds: rocket::State<Feeds>,
+) -> Result<Option<Json<NextTransition>>> {
+    let (config, _) = config::load_enforced()?;
+    let user_config = match config.user_config.get(&name) {
+        Some(user_config) => user_config,
+        None => return Ok(None),
+    };
+
+    let now = Local::now();
+    let feed = feeds.occurrences(&name);
+    let policy = Policy::for_user(&config, user_config).with_feed(&feed);
+    let verdict = verdict::decide(&name, user_config, &policy, &state.lock().unwrap(), now);
+
+    Ok(Some(Json(NextTransition {
+        now,
+        locked: verdict.locked,
+        reason: verdict.reason,
+        lock_at: verdict.lock_at,
+        minutes_remaining: verdict.lock_at.map(|at| (at - now).num_minutes()),
+        transition: schedule::next_transition(now, &policy),
+        manual: verdict.manual,
+        pause: verdict.pause,
+    })))
+}
+
+#[get("/users/<name>/rules")]
+fn get_rules(
+    name: String,
+    state: rocket::State<SharedState>,
+    feeds: rocket::State<Feeds>,
+) -> Result<Option<Json<serde_json::Value>>> {
+    let (config, _) = config::load_enforced()?;
+    let user_config = match config.user_config.get(&name) {
+        Some(user_config) => user_config,
+        None => return Ok(None),
+    };
+
+    let feed = feeds.occurrences(&name);
+    let policy = Policy::for_user(&config, user_config).with_feed(&feed);
+    let verdict = verdict::decide(&name, user_config, &policy, &state.lock().unwrap(), Local::now());
+    let status = RulesStatus {
+        rules: &user_config.rules,
+        decision: verdict.rule,
+    };
+
+    Ok(Some(Json(serde_json::to_value(&status)?)))
+}
+
+/// Replaces the user's rules. Conditions that don't parse are rejected.
+#[put("/users/<name>/rules", data = "<new_rules>")]
+fn set_rules(name: String, new_rules: Json<Vec<Rule>>) -> WriteResult<Option<status::Accepted<String>>> {
+    let mut config = config::load()?;
+    match config.user_config.get_mut(&name) {
+        Some(user_config) => {
+            info!("Setting rules for {}", name);
+            user_config.rules = new_rules.into_inner();
+            config::save(&config)?;
+            Ok(Some(status::Accepted(None)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// The longest timeline that can be previewed at once.
+const MAX_TIMELINE_DAYS: i64 = 62;
+
+/// What the runloop is expected to do from the start of `from` until the start
+/// of `to`, which default to today and a week later. The policy is built the
+/// same way the runloop builds it, calendar feed and all.
+fn preview_timeline(
+    config: &config::Config,
+    user_config: &UserConfig,
+    state: &SharedState,
+    feeds: &Feeds,
+    from: Option<String>,
+    to: Option<String>,
+) -> Result<Vec<Span>> {
+    let now = Local::now();
+    let from = match from {
+        Some(from) => from.parse::<NaiveDate>()?,
+        None => Zone::for_config(config).date(now),
+    };
+    let to = match to {
+        Some(to) => to.parse::<NaiveDate>()?,
+        None => from + Duration::weeks(1),
+    };
+    if to <= from || to - from > Duration::days(MAX_TIMELINE_DAYS) {
+        return Err(anyhow!("The timeline has to be between 1 and {} days long", MAX_TIMELINE_DAYS));
+    }
+
+    let name = &user_config.username;
+    // The cached feed only stands for the user's saved one.
+    let saved_feed = config.user_config.get(name).and_then(|u| u.calendar_feed.as_ref());
+    let feed = match saved_feed == user_config.calendar_feed.as_ref() {
+        true => feeds.occurrences(name),
+        false => Vec::new(),
+    };
+    let policy = Policy::for_user(config, user_config).with_feed(&feed);
+    let start = |date: NaiveDate| {
+        policy
+            .zone
+            .localize(&date.and_hms(0, 0, 0))
+            .ok_or_else(|| anyhow!("Bad date {}", date))
+    };
+    let (from, to) = (start(from)?, start(to)?);
+    // Work from a copy so the runloop isn't kept waiting.
+    let state = state.lock().unwrap().clone();
+
+    Ok(verdict::timeline(name, user_config, &policy, &state, now, from, to))
+}
+
+/// What the user's saved config looks like over a range of dates.
+#[get("/users/<name>/timeline?<from>&<to>")]
+fn timeline(
+    name: String,
+    from: Option<String>,
+    to: Option<String>,
+    state: rocket::State<SharedState>,
+    feeds: rocket::State<Feeds>,
+) -> Result<Option<Json<Vec<Span>>>> {
+    let (config, _) = config::load_enforced()?;
+    match config.user_config.get(&name) {
+        Some(user_config) => Ok(Some(Json(preview_timeline(&config, user_config, &state, &feeds, from, to)?))),
+        None => Ok(None),
+    }
+}
+
+/// What a user's timeline would look like with a config that hasn't been
+/// saved, e.g. while it's being edited. Nothing is changed.
+#[post("/timeline?<from>&<to>", data = "<candidate>")]
+fn preview_candidate_timeline(
+    candidate: Json<UserConfig>,
+    from: Option<String>,
+    to: Option<String>,
+    state: rocket::State<SharedState>,
+    feeds: rocket::State<Feeds>,
+) -> WriteResult<Json<Vec<Span>>> {
+    let config = config::load()?;
+    validate::check_user(&config, &candidate).map_err(ApiError::Invalid)?;
+
+    Ok(Json(preview_timeline(&config, &candidate, &state, &feeds, from, to)?))
+}
+
+/// Which template applies to the user on a date (today by default).
+#[get("/users/<name>/template?<date>")]
+fn preview_template(name: String, date: Option<String>) -> Result<Option<Json<TemplatePreview>>> {
+    let config = config::load()?;
+    let user_config = match config.user_config.get(&name) {
+        Some(user_config) => user_config,
+        None => return Ok(None),
+    };
+
+    let date = match date {
+        Some(date) => date.parse::<NaiveDate>()?,
+        None => Zone::for_config(&config).date(Local::now()),
+    };
+    let template = Policy::for_user(&config, user_config).template_on(date).map(str::to_owned);
+
+    Ok(Some(Json(TemplatePreview { date, template })))
+}
+
+#[get("/users/<name>/policy?<date>")]
+fn effective_policy(name: String, date: Option<String>) -> Result<Option<Json<serde_json::Value>>> {
+    let config = config::load()?;
+    let user_config = match config.user_config.get(&name) {
+        Some(user_config) => user_config,
+        None => return Ok(None),
+    };
+
+    let date = match date {
+        Some(date) => date.parse::<NaiveDate>()?,
+        None => Zone::for_config(&config).date(Local::now()),
+    };
+    let policy = Policy::for_user(&config, user_config);
+    let effective = EffectivePolicy {
+        date,
+        group: policy.group.map(|(name, _)| name),
+        template: policy.template_on(date),
+        periods: policy
+            .periods_on(date)
+            .into_iter()
+            .map(|(source, period)| SourcedPeriod { source, period })
+            .collect(),
+        cron_rules: policy.cron_rules_on(date),
+        blackout_periods: policy.blackouts_on(date),
+        removed_periods: policy.removed,
+        exceptions: policy.exceptions.iter().filter(|e| e.applies_to(date)).collect(),
+    };
+
+    Ok(Some(Json(serde_json::to_value(&effective)?)))
+}
+
+/// Replaces the user's weekly schedule with the one in an iCalendar file and
+/// adds its dated exceptions. Without `confirm=true` nothing is saved, so the
+/// result can be shown as a preview first.
+#[post("/users/<name>/schedule/import?<confirm>", data = "<calendar>")]
+fn import_schedule(name: String, confirm: Option<bool>, calendar: String) -> WriteResult<Option<Json<ImportPreview>>> {
+    let mut config = config::load()?;
+    let zone = Zone::for_config(&config);
+    let user_config = match config.user_config.get_mut(&name) {
+        Some(user_config) => user_config,
+        None => return Ok(None),
+    };
+
+    let import = ical::import(&calendar, zone);
+    let preview = ImportPreview {
+        diff: ical::diff(&user_config.schedule.open_periods, &import.open_periods),
+        exceptions: import.exceptions.clone(),
+        warnings: import.warnings,
+    };
+
+    if confirm == Some(true) {
+        info!("Importing schedule for {}", name);
+        user_config.schedule.open_periods = import.open_periods;
+        user_config.exceptions.extend(import.exceptions);
+        config::save(&config)?;
+    }
+
+    Ok(Some(Json(preview)))
+}
+
+/// The user's own weekly schedule as text, e.g.
+/// `Mon-Fri 15:00-17:30 "homework"; Sat,Sun 09:00-12:00`.
+#[get("/users/<name>/schedule")]
+fn get_schedule(name: String) -> Result<Option<String>> {
+    let config = config::load()?;
+
+    Ok(config.user_config.get(&name).map(|u| dsl::format(&u.schedule.open_periods)))
+}
+
+/// Replaces the user's own weekly schedule with one written as text.
+#[put("/users/<name>/schedule", data = "<text>")]
+fn set_schedule(name: String, text: String) -> WriteResult<Option<status::Accepted<String>>> {
+    let periods = dsl::parse(&text).map_err(|e| {
+        ApiError::Invalid(ValidationErrors(vec![ValidationError {
+            path: format!("user_config.{}.schedule", name),
+            message: e.to_string(),
+        }]))
+    })?;
+
+    let mut config = config::load()?;
+    match config.user_config.get_mut(&name) {
+        Some(user_config) => {
+            info!("Setting schedule for {}", name);
+            user_config.schedule.open_periods = periods;
+            config::save(&config)?;
+            Ok(Some(status::Accepted(None)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Compares every byte, so how long it takes doesn't give the token away.
+fn tokens_match(expected: Option<&str>, given: &str) -> bool {
+    match expected {
+        Some(expected) => {
+            expected.len() == given.len() && expected.bytes().zip(given.bytes()).fold(0, |d, (a, b)| d | (a ^ b)) == 0
+        }
+        None => false,
+    }
+}
+
+fn new_token() -> Result<String> {
+    let mut bytes = [0u8; 24];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+
+    Ok(base64::encode_config(bytes, base64::URL_SAFE_NO_PAD))
+}
+
+/// How far ahead the schedule feed goes. Calendar apps check back often, so
+/// they'll pick up anything further out later.
+const SCHEDULE_FEED_WEEKS: i64 = 8;
+
+/// The user's schedule as an iCalendar feed, to subscribe to in a calendar
+/// app, from a week ago to a few weeks ahead. Calendar apps can't log in, so
+/// the feed's secret token goes in the URL instead.
+#[get("/users/<name>/schedule.ics?<token>")]
+fn schedule_feed(name: String, token: String) -> Result<Option<Content<String>>> {
+    let config = config::load()?;
+    let user_config = match config.user_config.get(&name) {
+        Some(user_config) if tokens_match(user_config.schedule_feed_token.as_deref(), &token) => user_config,
+        _ => return Ok(None),
+    };
+
+    let policy = Policy::for_user(&config, user_config);
+    let now = Local::now();
+    let occurrences = schedule::occurrences(
+        &policy,
+        now - Duration::weeks(1),
+        now + Duration::weeks(SCHEDULE_FEED_WEEKS),
+    );
+
+    Ok(Some(Content(
+        ContentType::new("text", "calendar"),
+        ical::export_occurrences(&name, &occurrences),
+    )))
+}
+
+/// Makes a new token for the user's schedule feed. Anyone subscribed with the
+/// old one has to subscribe again.
+#[post("/users/<name>/schedule.ics/token")]
+fn reset_schedule_feed_token(name: String) -> WriteResult<Option<Json<FeedToken>>> {
+    let mut config = config::load()?;
+    let user_config = match config.user_config.get_mut(&name) {
+        Some(user_config) => user_config,
+        None => return Ok(None),
+    };
+
+    info!("Resetting schedule feed token for {}", name);
+    let token = new_token()?;
+    user_config.schedule_feed_token = Some(token.clone());
+    config::save(&config)?;
+
+    Ok(Some(Json(FeedToken { token })))
+}
+
+/// Turns off the user's schedule feed.
+#[delete("/users/<name>/schedule.ics/token")]
+fn delete_schedule_feed_token(name: String) -> WriteResult<Option<status::Accepted<String>>> {
+    let mut config = config::load()?;
+    let user_config = match config.user_config.get_mut(&name) {
+        Some(user_config) => user_config,
+        None => return Ok(None),
+    };
+
+    info!("Turning off schedule feed for {}", name);
+    user_config.schedule_feed_token = None;
+    config::save(&config)?;
+
+    Ok(Some(status::Accepted(None)))
+}
+
 #[post("/userconfig", data = "<config>")]
-fn create_user_config(config: Json<UserConfig>) -> std::result::Result<status::Accepted<String>, Debug<anyhow::Error>> {
+fn create_user_config(config: Json<UserConfig>) -> WriteResult<status::Accepted<String>> {
     let mut new_config = config.into_inner();
     let mut loaded_config = config::load()?;
 
     if loaded_config.user_config.contains_key(&new_config.username) {
-        Err(Debug(anyhow!("User {:?} already exists", &new_config.username)))
+        Err(ApiError::Other(anyhow!("User {:?} already exists", &new_config.username)))
     } else {
+        // Check the schedule before touching any passwords.
+        validate::check_user(&loaded_config, &new_config).map_err(ApiError::Invalid)?;
+
         match (new_config.normal_password, new_config.lockdown_password) {
             (Some(normal_password), Some(lockdown_password)) => {
                 let username = new_config.username.clone();
@@ -83,12 +858,45 @@ fn create_user_config(config: Json<UserConfig>) -> std::result::Result<status::A
 
             },
             _ => {
-                Err(Debug(anyhow!("No passwords provided")))
+                Err(ApiError::Other(anyhow!("No passwords provided")))
             }
         }
     }
 }
 
 pub fn get_routes() -> Vec<Route> {
-    routes![index, status, users, create_user_config]
+    routes![
+        index,
+        status,
+        users,
+        usage,
+        ledger,
+        grant,
+        revoke,
+        set_override,
+        cancel_override,
+        flags,
+        set_flag,
+        clear_flag,
+        pause,
+        pause_with_code,
+        resume,
+        templates,
+        save_template,
+        delete_template,
+        next_transition,
+        get_rules,
+        set_rules,
+        timeline,
+        preview_candidate_timeline,
+        preview_template,
+        effective_policy,
+        get_schedule,
+        set_schedule,
+        import_schedule,
+        schedule_feed,
+        reset_schedule_feed_token,
+        delete_schedule_feed_token,
+        create_user_config
+    ]
 }
@@ -0,0 +1,128 @@
+//! Runs in each user's session and shows messages from the heimdall daemon,
+//! which can't reliably reach the user's desktop itself.
+
+use anyhow::{bail, Result};
+use env_logger::Env;
+use log::{error, info};
+use std::{
+  io::BufReader,
+  os::unix::net::UnixStream,
+  thread,
+  time::{Duration, Instant},
+};
+
+#[allow(dead_code)]
+#[path = "../os.rs"]
+mod os;
+#[path = "../protocol.rs"]
+mod protocol;
+
+use protocol::{AgentMessage, DaemonMessage};
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often to remind the user how long they have left.
+const REMINDER_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// Shows the user how much time they have left when an open period starts,
+/// and again every REMINDER_INTERVAL until it ends. The daemon warns them as
+/// the end approaches.
+struct Indicator {
+  /// Whether the user was locked, and whether the daemon knew when the lock
+  /// would come.
+  shown: Option<(bool, bool)>,
+  last_reminder: Option<Instant>,
+}
+
+impl Indicator {
+  fn update(&mut self, locked: bool, minutes_remaining: Option<u32>) -> Result<()> {
+    let status = (locked, minutes_remaining.is_some());
+    let changed = self.shown != Some(status);
+    self.shown = Some(status);
+
+    let minutes = match (locked, minutes_remaining) {
+      (false, Some(minutes)) => minutes,
+      _ => return Ok(()),
+    };
+    let due = self.last_reminder.map_or(true, |at| at.elapsed() >= REMINDER_INTERVAL);
+    if changed || due {
+      self.last_reminder = Some(Instant::now());
+      os::show_notification("Heimdall", &format!("You have {} minutes remaining", minutes))?;
+    }
+
+    Ok(())
+  }
+}
+
+fn handle_message(message: DaemonMessage, indicator: &mut Indicator) -> Result<()> {
+  match message {
+    DaemonMessage::Warning {
+      title,
+      message,
+      critical,
+    } => match critical {
+      true => os::show_alert(&title, &message),
+      false => os::show_notification(&title, &message),
+    },
+    DaemonMessage::Status {
+      locked,
+      minutes_remaining,
+    } => indicator.update(locked, minutes_remaining),
+    DaemonMessage::Rejected { reason } => bail!("Rejected by daemon: {}", reason),
+    DaemonMessage::Welcome => Ok(()),
+  }
+}
+
+fn run(username: &str) -> Result<()> {
+  let mut stream = UnixStream::connect(protocol::get_socket_path())?;
+  let mut reader = BufReader::new(stream.try_clone()?);
+
+  protocol::write_message(
+    &mut stream,
+    &AgentMessage::Hello {
+      username: username.to_owned(),
+    },
+  )?;
+  match protocol::read_message(&mut reader)? {
+    Some(DaemonMessage::Welcome) => info!("Connected to heimdall as {}", username),
+    Some(DaemonMessage::Rejected { reason }) => bail!("Rejected by daemon: {}", reason),
+    other => bail!("Unexpected response from daemon: {:?}", other),
+  }
+
+  let mut heartbeat_stream = stream.try_clone()?;
+  thread::spawn(move || loop {
+    thread::sleep(HEARTBEAT_INTERVAL);
+    if let Err(e) = protocol::write_message(&mut heartbeat_stream, &AgentMessage::Heartbeat) {
+      error!("Failed to send heartbeat: {}", e);
+      break;
+    }
+  });
+
+  let mut indicator = Indicator {
+    shown: None,
+    last_reminder: None,
+  };
+  while let Some(message) = protocol::read_message(&mut reader)? {
+    if let Err(e) = handle_message(message, &mut indicator) {
+      error!("{}", e);
+    }
+  }
+
+  info!("Daemon hung up");
+  Ok(())
+}
+
+fn main() {
+  env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+
+  let username = whoami::username();
+  info!("Starting heimdall-agent for {}", username);
+
+  loop {
+    if let Err(e) = run(&username) {
+      error!("{}", e);
+    }
+    thread::sleep(RECONNECT_INTERVAL);
+  }
+}
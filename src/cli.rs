@@ -0,0 +1,132 @@
+//! Commands for controlling the running daemon from a terminal. These go
+//! through the daemon's API, since it owns the state.
+
+use crate::api::{PauseCodeRequest, PauseRequest};
+use crate::config::OpenPeriod;
+use crate::constants;
+use crate::dsl;
+use anyhow::{anyhow, bail, Result};
+
+static USAGE: &str = "Usage: heimdall [pause [minutes] [reason...] | pause-code <code> | resume | schedule <user> [schedule...]]";
+
+#[derive(Debug, PartialEq)]
+enum Command {
+  Pause(PauseRequest),
+  PauseCode(PauseCodeRequest),
+  Resume,
+  ShowSchedule(String),
+  SetSchedule(String, Vec<OpenPeriod>),
+}
+
+fn parse(args: &[String]) -> Result<Command> {
+  let args: Vec<&str> = args.iter().map(String::as_str).collect();
+  Ok(match args.as_slice() {
+    ["pause"] => Command::Pause(PauseRequest::default()),
+    ["pause", minutes, reason @ ..] => Command::Pause(PauseRequest {
+      minutes: Some(minutes.parse().map_err(|_| anyhow!("Not a number of minutes: {}\n{}", minutes, USAGE))?),
+      until: None,
+      reason: reason.join(" "),
+    }),
+    ["pause-code", code] => Command::PauseCode(PauseCodeRequest {
+      code: (*code).to_owned(),
+    }),
+    ["resume"] => Command::Resume,
+    ["schedule", user] => Command::ShowSchedule((*user).to_owned()),
+    ["schedule", user, schedule @ ..] => {
+      let text = schedule.join(" ");
+      let periods = dsl::parse(&text).map_err(|e| anyhow!("{}\n{}", e, e.pointer(&text)))?;
+      Command::SetSchedule((*user).to_owned(), periods)
+    }
+    _ => bail!(USAGE),
+  })
+}
+
+fn url(path: &str) -> String {
+  format!("{}{}", constants::API_URL, path)
+}
+
+/// Sends the request, returning the response's body. `not_found` explains a
+/// 404.
+fn send(request: ureq::Request, body: Option<String>, not_found: &str) -> Result<String> {
+  let result = match body {
+    Some(body) => request.send_string(&body),
+    None => request.call(),
+  };
+
+  match result {
+    Ok(response) => Ok(response.into_string()?),
+    Err(ureq::Error::Status(404, _)) => bail!("{}", not_found),
+    Err(ureq::Error::Status(422, response)) => bail!("Rejected: {}", response.into_string()?),
+    Err(e) => bail!("Couldn't reach heimdall at {}: {}", constants::API_URL, e),
+  }
+}
+
+fn send_json<T: serde::Serialize>(request: ureq::Request, body: &T, not_found: &str) -> Result<String> {
+  send(
+    request.set("Content-Type", "application/json"),
+    Some(serde_json::to_string(body)?),
+    not_found,
+  )
+}
+
+static NOT_PAUSED: &str = "Nothing to do: unknown code, or not paused";
+
+/// Runs the command given on the command line.
+pub fn run(args: &[String]) -> Result<()> {
+  let output = match parse(args)? {
+    Command::Pause(request) => send_json(ureq::post(&url("/pause")), &request, NOT_PAUSED).map(|_| "Done".to_owned()),
+    Command::PauseCode(request) => {
+      send_json(ureq::post(&url("/pause/code")), &request, NOT_PAUSED).map(|_| "Done".to_owned())
+    }
+    Command::Resume => send(ureq::delete(&url("/pause")), None, NOT_PAUSED).map(|_| "Done".to_owned()),
+    Command::ShowSchedule(user) => {
+      let not_found = format!("No user called {}", user);
+      send(ureq::get(&url(&format!("/users/{}/schedule", user))), None, &not_found)
+    }
+    Command::SetSchedule(user, periods) => {
+      let not_found = format!("No user called {}", user);
+      let request = ureq::put(&url(&format!("/users/{}/schedule", user))).set("Content-Type", "text/plain");
+      send(request, Some(dsl::format(&periods)), &not_found).map(|_| "Done".to_owned())
+    }
+  }?;
+  println!("{}", output);
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|a| a.to_string()).collect()
+  }
+
+  #[test]
+  fn test_parse() -> Result<()> {
+    assert_eq!(Command::Pause(PauseRequest::default()), parse(&args(&["pause"]))?);
+    assert_eq!(
+      Command::Pause(PauseRequest {
+        minutes: Some(30),
+        until: None,
+        reason: "Dinner time".to_owned(),
+      }),
+      parse(&args(&["pause", "30", "Dinner", "time"]))?
+    );
+    assert_eq!(Command::Resume, parse(&args(&["resume"]))?);
+    assert!(parse(&args(&["pause", "soon"])).is_err());
+    assert!(parse(&args(&["pause-code"])).is_err());
+
+    assert_eq!(Command::ShowSchedule("alice".to_owned()), parse(&args(&["schedule", "alice"]))?);
+    assert_eq!(
+      Command::SetSchedule("alice".to_owned(), dsl::parse("Mon-Fri 15:00-17:00")?),
+      parse(&args(&["schedule", "alice", "Mon-Fri", "15:00-17:00"]))?
+    );
+    assert_eq!(
+      "line 1, column 9: 25:00 is not a time of day\n  Mon-Fri 25:00-26:00\n          ^",
+      parse(&args(&["schedule", "alice", "Mon-Fri", "25:00-26:00"])).unwrap_err().to_string()
+    );
+
+    Ok(())
+  }
+}
@@ -1,5 +1,6 @@
 use anyhow::bail;
 use anyhow::Result;
+use chrono::{Datelike, NaiveDate};
 use serde::{Deserialize, Serialize};
 use std::{fs, path::PathBuf};
 use std::{
@@ -11,20 +12,85 @@ use std::{
 
 use log::info;
 
+use crate::cron::Cron;
+use crate::dsl;
+use crate::os;
+use crate::validate::{self, ValidationError, ValidationErrors};
+
 #[cfg(not(debug_assertions))]
 static CONFIG_FILE: &str = "/usr/local/etc/heimdall/config.json";
 #[cfg(debug_assertions)]
 static CONFIG_FILE: &str = "/tmp/heimdall/config.json";
 
+/// A copy of the last config that passed validation, to fall back on.
+#[cfg(not(debug_assertions))]
+static LAST_GOOD_CONFIG_FILE: &str = "/usr/local/etc/heimdall/config.good.json";
+#[cfg(debug_assertions)]
+static LAST_GOOD_CONFIG_FILE: &str = "/tmp/heimdall/config.good.json";
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
     pub user_config: HashMap<String, UserConfig>,
+
+    /// How many minutes before a lock the user is warned. A warning at one
+    /// minute or less is shown as a critical alert.
+    #[serde(default = "default_warning_minutes")]
+    pub warning_minutes: Vec<u32>,
+
+    /// The IANA timezone schedules are written in, e.g. "Europe/London".
+    /// Defaults to the computer's timezone.
+    #[serde(default)]
+    pub timezone: Option<String>,
+
+    /// One-time codes that pause the household without needing the API,
+    /// e.g. read out over the phone to a babysitter. Used codes are kept in
+    /// the state, so using one doesn't rewrite the config.
+    #[serde(default)]
+    pub pause_codes: Vec<PauseCode>,
+
+    /// Named schedules that users can share, e.g. "school term".
+    #[serde(default)]
+    pub templates: HashMap<String, Template>,
+
+    /// Groups of users, e.g. siblings, who share a schedule.
+    #[serde(default)]
+    pub groups: HashMap<String, Group>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PauseCode {
+    pub code: String,
+    /// How long the pause lasts. Without this it lasts until resumed.
+    #[serde(default)]
+    pub minutes: Option<u32>,
+}
+
+fn default_warning_minutes() -> Vec<u32> {
+    vec![15, 5, 1]
 }
 
 impl Config {
     pub fn is_new(&self) -> bool {
         self.user_config.is_empty()
     }
+
+    /// Removes schedule exceptions that ended before today. Returns true if
+    /// any were removed.
+    pub fn prune_expired_exceptions(&mut self, today: NaiveDate) -> bool {
+        let mut pruned = false;
+        for user_config in self.user_config.values_mut() {
+            let before = user_config.exceptions.len();
+            user_config.exceptions.retain(|e| e.last_date() >= today);
+            pruned |= user_config.exceptions.len() != before;
+        }
+
+        pruned
+    }
+
+    /// Looks up a pause code. Whether it's been used is kept in the state.
+    pub fn pause_code(&self, code: &str) -> Option<&PauseCode> {
+        self.pause_codes.iter().find(|c| c.code == code)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -33,50 +99,435 @@ pub struct UserConfig {
     pub normal_password: Option<String>,
     pub lockdown_password: Option<String>,
     pub schedule: Schedule,
+
+    /// Where to send notifications about this user.
+    #[serde(default = "default_notifications")]
+    pub notifications: Vec<NotificationRoute>,
+
+    /// Postpones locking while the user is in a meeting.
+    #[serde(default)]
+    pub meeting_deferral: Option<MeetingDeferral>,
+
+    /// Changes to the schedule on particular dates.
+    #[serde(default)]
+    pub exceptions: Vec<ScheduleException>,
+
+    /// The most time the user can spend unlocked each day, even during open
+    /// periods.
+    #[serde(default)]
+    pub daily_quota_minutes: Option<u32>,
+
+    /// The group whose schedule this user inherits. The user's own `schedule`
+    /// is then added on top of it.
+    #[serde(default)]
+    pub group: Option<String>,
+
+    /// Periods of the group's schedule that don't apply to this user.
+    #[serde(default, deserialize_with = "dsl::deserialize_periods")]
+    pub removed_periods: Vec<OpenPeriod>,
+
+    /// Names of templates to use instead of `schedule`, in order of priority.
+    /// On any date, the first template that's active is used.
+    #[serde(default)]
+    pub templates: Vec<String>,
+
+    /// Whether unused quota is banked for later.
+    #[serde(default)]
+    pub rollover: Option<Rollover>,
+
+    /// A calendar whose events open the account.
+    #[serde(default)]
+    pub calendar_feed: Option<CalendarFeed>,
+
+    /// The secret for subscribing to this user's schedule as a calendar at
+    /// `/api/users/<name>/schedule.ics?token=<token>`. No token, no feed.
+    #[serde(default)]
+    pub schedule_feed_token: Option<String>,
+
+    /// Stops the schedule flapping between locked and unlocked.
+    #[serde(default)]
+    pub hysteresis: Hysteresis,
+
+    /// Conditions that lock or unlock the user regardless of the schedule,
+    /// quota or banked time, checked in order; see the `rules` module. Only
+    /// an override or pause beats them.
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+}
+
+/// Locks or unlocks the user whenever `when` holds.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Rule {
+    /// A condition like `weekend && used_weekdays > 5h`.
+    pub when: String,
+    pub action: RuleAction,
+    /// Why, for the log and the API.
+    #[serde(default)]
+    pub reason: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleAction {
+    Lock,
+    Unlock,
+}
+
+/// The shortest the schedule will lock or unlock for. Both default to 0.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Hysteresis {
+    /// Gaps between open periods shorter than this stay open, unless a
+    /// blackout or locked day falls in them.
+    #[serde(default)]
+    pub min_locked_minutes: u32,
+    /// Open time shorter than this, after merging, stays locked.
+    #[serde(default)]
+    pub min_unlocked_minutes: u32,
+}
+
+/// Banks whatever is left of the daily quota at the end of the day.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Rollover {
+    /// The most rolled over time that can be in the bank at once.
+    pub max_minutes: u32,
+    /// How long rolled over time can be used for.
+    #[serde(default = "default_rollover_expiry_days")]
+    pub expiry_days: u32,
+}
+
+fn default_rollover_expiry_days() -> u32 {
+    7
+}
+
+/// An iCalendar feed, e.g. a shared family calendar, that opens the account
+/// during its events with a particular title.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CalendarFeed {
+    pub url: String,
+    /// Only events with this title (ignoring case) open the account.
+    #[serde(default = "default_feed_title")]
+    pub title: String,
+    #[serde(default = "default_feed_poll_minutes")]
+    pub poll_minutes: u32,
+}
+
+fn default_feed_title() -> String {
+    "Screen time".to_owned()
+}
+
+fn default_feed_poll_minutes() -> u32 {
+    15
+}
+
+/// Postpones locks while the user is in a video call. Calls are spotted from
+/// the command lines of the user's processes, so only desktop apps can be
+/// detected; a call in a browser tab, like Google Meet, can't be. A meeting
+/// app without a `call_process` counts as a call whenever it's running, so
+/// leaving an idle client open is enough to put off a lock.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MeetingDeferral {
+    pub meetings: Vec<Meeting>,
+    /// How long each postponement lasts.
+    #[serde(default = "default_deferral_increment_minutes")]
+    pub increment_minutes: u32,
+    /// The most a single lock can be postponed by in total.
+    pub max_minutes: u32,
+}
+
+fn default_deferral_increment_minutes() -> u32 {
+    5
+}
+
+/// A meeting app. Processes are matched by looking for these strings in the
+/// command lines of the user's processes.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Meeting {
+    pub name: String,
+    /// The app itself, e.g. "zoom.us".
+    pub process: String,
+    /// A process that only runs during a call, e.g. "CptHost" for Zoom. If
+    /// not set, the app running at all counts as being in a call.
+    #[serde(default)]
+    pub call_process: Option<String>,
+}
+
+/// Sends events to a notification channel. If events is empty, every event is
+/// sent.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NotificationRoute {
+    pub channel: Channel,
+    #[serde(default)]
+    pub events: Vec<EventKind>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Channel {
+    /// The heimdall-agent in the user's session, or a desktop notification if
+    /// the agent isn't running.
+    Agent,
+    Desktop,
+    Speech,
+    Webhook { url: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    WarningUpcomingLock,
+    Locked,
+    Unlocked,
+    EnforcementFailed,
+    LockDeferred,
+}
+
+fn default_notifications() -> Vec<NotificationRoute> {
+    vec![
+        NotificationRoute {
+            channel: Channel::Agent,
+            events: vec![EventKind::WarningUpcomingLock],
+        },
+    ]
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Schedule {
+    /// Either a list of periods or text like `Mon-Fri 15:00-17:00`; see
+    /// the `dsl` module.
+    #[serde(deserialize_with = "dsl::deserialize_periods")]
     pub open_periods: Vec<OpenPeriod>,
+
+    /// Periods that are easier to say as a cron expression, like 07:00 on
+    /// weekdays for 45 minutes.
+    #[serde(default)]
+    pub cron_rules: Vec<CronRule>,
+
+    /// Times that stay locked even when an open period or cron rule covers
+    /// them, like dinner. Dated exceptions still override them: the periods
+    /// an `add`, `open` or `replace` exception opens aren't blacked out, so a
+    /// parent can make a one-off exception to a bedtime. Locked days still
+    /// lock.
+    #[serde(default, deserialize_with = "dsl::deserialize_periods")]
+    pub blackout_periods: Vec<OpenPeriod>,
+}
+
+/// Opens for a while every time the cron expression matches.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CronRule {
+    pub cron: Cron,
+    pub duration_minutes: u32,
+    #[serde(default)]
+    pub note: String,
 }
 
+/// A schedule shared by several users.
 #[derive(Serialize, Deserialize, Debug)]
+pub struct Group {
+    pub schedule: Schedule,
+}
+
+/// A schedule kept once in the config and shared by users.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Template {
+    pub schedule: Schedule,
+    /// When the template applies. If empty, it always does.
+    #[serde(default)]
+    pub active: Vec<DateRange>,
+}
+
+impl Template {
+    pub fn is_active(&self, date: NaiveDate) -> bool {
+        self.active.is_empty() || self.active.iter().any(|r| r.contains(date))
+    }
+}
+
+/// The dates from start to end inclusive. A yearly range ignores the years,
+/// so Jul 1 to Aug 31 covers every summer, and Dec 20 to Jan 5 wraps around
+/// the new year.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DateRange {
+    pub start: NaiveDate,
+    pub end: NaiveDate,
+    #[serde(default)]
+    pub yearly: bool,
+}
+
+impl DateRange {
+    pub fn contains(&self, date: NaiveDate) -> bool {
+        if !self.yearly {
+            return self.start <= date && date <= self.end;
+        }
+
+        let day = |d: NaiveDate| (d.month(), d.day());
+        let (start, end, date) = (day(self.start), day(self.end), day(date));
+        match start <= end {
+            true => start <= date && date <= end,
+            false => start <= date || date <= end,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct OpenPeriod {
     pub start: Instant,
     pub end: Instant,
     pub note: String
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Instant {
     pub weekday: u8,
     pub hour: u8,
     pub minute: u8,
 }
 
+/// A change to the usual schedule for a date or range of dates, e.g. for a
+/// holiday or sick day.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ScheduleException {
+    pub start_date: NaiveDate,
+    /// The last date the exception applies to, if it's more than one day.
+    #[serde(default)]
+    pub end_date: Option<NaiveDate>,
+    #[serde(flatten)]
+    pub kind: ExceptionKind,
+    #[serde(default)]
+    pub note: String,
+}
+
+impl ScheduleException {
+    pub fn last_date(&self) -> NaiveDate {
+        self.end_date.unwrap_or(self.start_date)
+    }
+
+    pub fn applies_to(&self, date: NaiveDate) -> bool {
+        self.start_date <= date && date <= self.last_date()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ExceptionKind {
+    /// Use these periods instead of the usual ones.
+    Replace { periods: Vec<DayPeriod> },
+    /// Open these periods as well as the usual ones.
+    Add { periods: Vec<DayPeriod> },
+    /// Locked all day.
+    Locked,
+    /// Open all day.
+    Open,
+}
+
+/// A period within a single day. If the end is before the start, the period
+/// runs past midnight into the next day.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DayPeriod {
+    pub start: TimeOfDay,
+    pub end: TimeOfDay,
+    #[serde(default)]
+    pub note: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TimeOfDay {
+    pub hour: u8,
+    pub minute: u8,
+}
+
 pub fn get_config_path() -> PathBuf {
     PathBuf::from(CONFIG_FILE)
 }
 
+fn empty(user_config: HashMap<String, UserConfig>) -> Config {
+    Config {
+        user_config,
+        warning_minutes: default_warning_minutes(),
+        timezone: None,
+        pause_codes: Vec::new(),
+        templates: HashMap::new(),
+        groups: HashMap::new(),
+    }
+}
+
+fn read(path: &str) -> Result<Config> {
+    let reader = BufReader::new(File::open(path)?);
+    Ok(serde_json::from_reader(reader)?)
+}
+
+/// Loads the config file as it is, without validating it, so a broken one can
+/// still be fixed through the API. Saving checks it.
 pub fn load() -> Result<Config> {
     let path = get_config_path();
     match path.exists() {
         true => {
             info!("Loading config from {}", CONFIG_FILE);
-            let file = File::open(CONFIG_FILE)?;
-            let reader = BufReader::new(file);
-            let config: Config = serde_json::from_reader(reader)?;
-
-            Ok(config)
+            read(CONFIG_FILE)
         }
         false => {
             info!("Creating new config");
-            Ok(Config { user_config: HashMap::new()})
+            Ok(empty(HashMap::new()))
         },
     }
 }
 
+/// Loads the config the daemon enforces, along with what's wrong with the
+/// config file if that isn't it. A file that can't be read or doesn't pass
+/// validation is never enforced: the last one that did is used instead, or
+/// if there's never been one, everyone in the file is locked until it's fixed.
+/// This doesn't write anything, so it's safe to call on every request; the
+/// runloop keeps the copy of the last valid config up to date.
+pub fn load_enforced() -> Result<(Config, Option<ValidationErrors>)> {
+    let (config, errors) = match load() {
+        Ok(config) => match validate::check(&config) {
+            Ok(()) => return Ok((config, None)),
+            Err(errors) => (Some(config), errors),
+        },
+        Err(e) => (
+            None,
+            ValidationErrors(vec![ValidationError {
+                path: CONFIG_FILE.to_owned(),
+                message: e.to_string(),
+            }]),
+        ),
+    };
+
+    let fallback = match (read(LAST_GOOD_CONFIG_FILE), config) {
+        (Ok(good), _) => good,
+        (Err(_), Some(config)) => lockdown(&config),
+        (Err(_), None) => bail!("{}", errors),
+    };
+
+    Ok((fallback, Some(errors)))
+}
+
+/// Keeps a copy of a valid config, if it's changed, for load_enforced to fall
+/// back on.
+pub fn remember_good(config: &Config) -> Result<()> {
+    let text = serde_json::to_string_pretty(config)?;
+    if fs::read_to_string(LAST_GOOD_CONFIG_FILE).ok().as_deref() == Some(text.as_str()) {
+        return Ok(());
+    }
+
+    os::write_atomically(Path::new(LAST_GOOD_CONFIG_FILE), text.as_bytes())
+}
+
+/// Everyone in the config, always locked, and nothing else from it.
+fn lockdown(config: &Config) -> Config {
+    let user_config = config
+        .user_config
+        .keys()
+        .map(|name| {
+            let locked = serde_json::json!({ "username": name, "schedule": { "open_periods": [] } });
+            (name.clone(), serde_json::from_value(locked).unwrap())
+        })
+        .collect();
+
+    empty(user_config)
+}
+
 pub fn save(config: &Config) -> Result<()> {
+    validate::check(config)?;
+
     let path = get_config_path();
     match path.parent() {
         Some(parent) => {
@@ -93,3 +544,109 @@ pub fn save(config: &Config) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lockdown() -> Result<()> {
+        let config: Config = serde_json::from_str(
+            r#"{
+                "user_config": {
+                    "alice": {
+                        "username": "alice",
+                        "schedule": { "open_periods": "Mon-Fri 15:00-17:00" },
+                        "daily_quota_minutes": 60
+                    }
+                },
+                "timezone": "Europe/London"
+            }"#,
+        )?;
+
+        let locked = lockdown(&config);
+        let alice = &locked.user_config["alice"];
+        assert!(alice.schedule.open_periods.is_empty());
+        assert_eq!(None, alice.daily_quota_minutes);
+        assert_eq!(None, locked.timezone);
+        assert_eq!(Ok(()), validate::check(&locked));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_prune_expired_exceptions() -> Result<()> {
+        let mut config: Config = serde_json::from_str(
+            r#"{
+                "user_config": {
+                    "test": {
+                        "username": "test",
+                        "schedule": { "open_periods": [] },
+                        "exceptions": [
+                            { "start_date": "2020-12-24", "type": "open" },
+                            { "start_date": "2020-12-24", "end_date": "2021-01-04", "type": "locked" },
+                            { "start_date": "2021-01-10", "type": "add", "periods": [
+                                { "start": { "hour": 9, "minute": 0 }, "end": { "hour": 10, "minute": 0 } }
+                            ] }
+                        ]
+                    }
+                }
+            }"#,
+        )?;
+
+        assert!(config.prune_expired_exceptions(NaiveDate::from_ymd(2021, 1, 4)));
+        let exceptions = &config.user_config["test"].exceptions;
+        assert_eq!(2, exceptions.len());
+        assert_eq!(ExceptionKind::Locked, exceptions[0].kind);
+
+        assert!(!config.prune_expired_exceptions(NaiveDate::from_ymd(2021, 1, 4)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_date_range() {
+        let date = |m, d| NaiveDate::from_ymd(2021, m, d);
+        let summer = DateRange {
+            start: NaiveDate::from_ymd(2020, 7, 1),
+            end: NaiveDate::from_ymd(2020, 8, 31),
+            yearly: true,
+        };
+        assert!(summer.contains(date(7, 1)));
+        assert!(summer.contains(date(8, 31)));
+        assert!(!summer.contains(date(9, 1)));
+
+        let christmas = DateRange {
+            start: NaiveDate::from_ymd(2020, 12, 20),
+            end: NaiveDate::from_ymd(2021, 1, 5),
+            yearly: true,
+        };
+        assert!(christmas.contains(date(12, 25)));
+        assert!(christmas.contains(date(1, 2)));
+        assert!(!christmas.contains(date(6, 1)));
+
+        let exams = DateRange {
+            start: NaiveDate::from_ymd(2021, 6, 7),
+            end: NaiveDate::from_ymd(2021, 6, 11),
+            yearly: false,
+        };
+        assert!(exams.contains(date(6, 7)));
+        assert!(!exams.contains(NaiveDate::from_ymd(2022, 6, 7)));
+    }
+
+    #[test]
+    fn test_pause_code() -> Result<()> {
+        let config: Config = serde_json::from_str(
+            r#"{
+                "user_config": {},
+                "pause_codes": [ { "code": "1234", "minutes": 60 }, { "code": "5678" } ]
+            }"#,
+        )?;
+
+        assert_eq!(Some(60), config.pause_code("1234").and_then(|c| c.minutes));
+        assert_eq!(None, config.pause_code("5678").and_then(|c| c.minutes));
+        assert_eq!(None, config.pause_code("0000"));
+
+        Ok(())
+    }
+}
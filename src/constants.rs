@@ -1,2 +1,5 @@
 pub static KEYSTORE_NORMAL_PASSWORD_KEY: &str = "dubh_heimdall_normal";
 pub static KEYSTORE_LOCKDOWN_PASSWORD_KEY: &str = "dubh_heimdall_lockdown";
+
+/// Where the daemon serves its API, for the command line to talk to.
+pub static API_URL: &str = "http://localhost:8000/api";
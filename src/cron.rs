@@ -0,0 +1,269 @@
+//! Cron expressions for schedule rules, like `0 7 * * Mon-Fri` for 07:00 on
+//! weekdays.
+//!
+//! The standard five fields are supported: minute, hour, day of the month,
+//! month and day of the week. Each can be `*`, a number, a range like `1-5`,
+//! a step like `*/15` or `8-18/2`, or a list of those separated by commas.
+//! Months and days can be written by name, and Sunday is either 0 or 7. As in
+//! most crons, when both the day of the month and the day of the week are
+//! restricted, a day matching either will do.
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{Datelike, NaiveDate, NaiveDateTime, NaiveTime};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+struct Field {
+  name: &'static str,
+  min: u32,
+  max: u32,
+  /// Names for the values, starting from `min`.
+  names: &'static [&'static str],
+}
+
+static MINUTE: Field = Field {
+  name: "minute",
+  min: 0,
+  max: 59,
+  names: &[],
+};
+
+static HOUR: Field = Field {
+  name: "hour",
+  min: 0,
+  max: 23,
+  names: &[],
+};
+
+static DAY: Field = Field {
+  name: "day of the month",
+  min: 1,
+  max: 31,
+  names: &[],
+};
+
+static MONTH: Field = Field {
+  name: "month",
+  min: 1,
+  max: 12,
+  names: &["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"],
+};
+
+static WEEKDAY: Field = Field {
+  name: "day of the week",
+  min: 0,
+  max: 7,
+  names: &["sun", "mon", "tue", "wed", "thu", "fri", "sat"],
+};
+
+impl Field {
+  fn value(&self, text: &str) -> Result<u32> {
+    let value = match self.names.iter().position(|n| n.eq_ignore_ascii_case(text)) {
+      Some(i) => i as u32 + self.min,
+      None => text.parse().map_err(|_| anyhow!("{:?} is not a {}", text, self.name))?,
+    };
+    if value < self.min || value > self.max {
+      bail!("{} is not a {} between {} and {}", value, self.name, self.min, self.max);
+    }
+
+    Ok(value)
+  }
+
+  /// The values the field matches, as bits.
+  fn parse(&self, text: &str) -> Result<u64> {
+    let mut bits = 0;
+    for part in text.split(',') {
+      let (range, step) = match part.split_once('/') {
+        Some((range, step)) => match step.parse::<usize>() {
+          Ok(step) if step > 0 => (range, Some(step)),
+          _ => bail!("{:?} is not a step", step),
+        },
+        None => (part, None),
+      };
+      let (first, last) = match (range, range.split_once('-')) {
+        ("*", _) => (self.min, self.max),
+        (_, Some((first, last))) => (self.value(first)?, self.value(last)?),
+        // `5/15` means from 5 onwards.
+        (_, None) if step.is_some() => (self.value(range)?, self.max),
+        (_, None) => (self.value(range)?, self.value(range)?),
+      };
+      if last < first {
+        bail!("{} ends before it starts", part);
+      }
+
+      for value in (first..=last).step_by(step.unwrap_or(1)) {
+        bits |= 1 << value;
+      }
+    }
+
+    Ok(bits)
+  }
+}
+
+fn has(bits: u64, value: u32) -> bool {
+  bits & (1 << value) != 0
+}
+
+/// A parsed cron expression. It's written to the config as the text it was
+/// read from.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cron {
+  text: String,
+  minutes: u64,
+  hours: u64,
+  days: u64,
+  months: u64,
+  weekdays: u64,
+  /// Whether the day fields are `*`, for deciding how to combine them.
+  any_day: bool,
+  any_weekday: bool,
+}
+
+impl Cron {
+  pub fn parse(text: &str) -> Result<Cron> {
+    let fields: Vec<&str> = text.split_whitespace().collect();
+    if fields.len() != 5 {
+      bail!("{:?} should have 5 fields, not {}", text, fields.len());
+    }
+    let parse = |field: &Field, value: &str| {
+      field
+        .parse(value)
+        .map_err(|e| anyhow!("Bad {} in cron expression {:?}: {}", field.name, text, e))
+    };
+
+    let mut weekdays = parse(&WEEKDAY, fields[4])?;
+    // Sunday is 7 as well as 0.
+    if has(weekdays, 7) {
+      weekdays |= 1;
+    }
+
+    Ok(Cron {
+      text: text.to_owned(),
+      minutes: parse(&MINUTE, fields[0])?,
+      hours: parse(&HOUR, fields[1])?,
+      days: parse(&DAY, fields[2])?,
+      months: parse(&MONTH, fields[3])?,
+      weekdays,
+      any_day: fields[2].starts_with('*'),
+      any_weekday: fields[4].starts_with('*'),
+    })
+  }
+
+  pub fn matches_date(&self, date: NaiveDate) -> bool {
+    let day = has(self.days, date.day());
+    let weekday = has(self.weekdays, date.weekday().num_days_from_sunday());
+    let day_matches = match (self.any_day, self.any_weekday) {
+      (false, false) => day || weekday,
+      _ => day && weekday,
+    };
+
+    day_matches && has(self.months, date.month())
+  }
+
+  /// Every time on the date the expression matches, in order.
+  pub fn times_on(&self, date: NaiveDate) -> Vec<NaiveDateTime> {
+    if !self.matches_date(date) {
+      return Vec::new();
+    }
+
+    let mut times = Vec::new();
+    for hour in (0..24).filter(|h| has(self.hours, *h)) {
+      for minute in (0..60).filter(|m| has(self.minutes, *m)) {
+        times.push(date.and_time(NaiveTime::from_hms(hour, minute, 0)));
+      }
+    }
+
+    times
+  }
+}
+
+impl TryFrom<String> for Cron {
+  type Error = anyhow::Error;
+
+  fn try_from(text: String) -> Result<Cron> {
+    Cron::parse(&text)
+  }
+}
+
+impl From<Cron> for String {
+  fn from(cron: Cron) -> String {
+    cron.text
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn times(cron: &str, day: u32) -> Result<Vec<String>> {
+    let date = NaiveDate::from_ymd(2021, 3, day);
+
+    Ok(Cron::parse(cron)?.times_on(date).iter().map(|t| t.format("%H:%M").to_string()).collect())
+  }
+
+  #[test]
+  fn test_times() -> Result<()> {
+    // 2021-03-01 is a Monday and 2021-03-07 a Sunday.
+    assert_eq!(vec!["07:00"], times("0 7 * * Mon-Fri", 1)?);
+    assert_eq!(Vec::<String>::new(), times("0 7 * * Mon-Fri", 7)?);
+    assert_eq!(vec!["07:00"], times("0 7 * * 7", 7)?);
+    assert_eq!(vec!["07:00"], times("0 7 * * sun", 7)?);
+    assert_eq!(
+      vec!["08:00", "08:30", "10:00", "10:30", "12:00", "12:30"],
+      times("0,30 8-12/2 * * *", 1)?
+    );
+    assert_eq!(vec!["23:45"], times("45/20 23 * * *", 1)?);
+    assert_eq!(vec!["09:00"], times("0 9 1 MAR *", 1)?);
+    assert_eq!(Vec::<String>::new(), times("0 9 1 apr *", 1)?);
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_days() -> Result<()> {
+    // Either the day of the month or the day of the week will do.
+    let cron = Cron::parse("0 9 15 * Sun")?;
+    assert!(cron.matches_date(NaiveDate::from_ymd(2021, 3, 7)));
+    assert!(cron.matches_date(NaiveDate::from_ymd(2021, 3, 15)));
+    assert!(!cron.matches_date(NaiveDate::from_ymd(2021, 3, 16)));
+
+    // But both have to match if either is *.
+    let cron = Cron::parse("0 9 */2 * Sun")?;
+    assert!(cron.matches_date(NaiveDate::from_ymd(2021, 3, 7)));
+    assert!(!cron.matches_date(NaiveDate::from_ymd(2021, 3, 14)));
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_errors() {
+    let error = |cron: &str| Cron::parse(cron).unwrap_err().to_string();
+    assert_eq!("\"0 7 * *\" should have 5 fields, not 4", error("0 7 * *"));
+    assert_eq!(
+      "Bad minute in cron expression \"60 7 * * *\": 60 is not a minute between 0 and 59",
+      error("60 7 * * *")
+    );
+    assert_eq!(
+      "Bad day of the week in cron expression \"0 7 * * Fri-Mon\": Fri-Mon ends before it starts",
+      error("0 7 * * Fri-Mon")
+    );
+    assert_eq!(
+      "Bad hour in cron expression \"0 */0 * * *\": \"0\" is not a step",
+      error("0 */0 * * *")
+    );
+    assert_eq!(
+      "Bad month in cron expression \"0 7 * Smarch *\": \"Smarch\" is not a month",
+      error("0 7 * Smarch *")
+    );
+  }
+
+  #[test]
+  fn test_serde() -> Result<()> {
+    let cron: Cron = serde_json::from_str(r#""0 7 * * Mon-Fri""#)?;
+    assert_eq!(r#""0 7 * * Mon-Fri""#, serde_json::to_string(&cron)?);
+    assert!(serde_json::from_str::<Cron>(r#""0 7 * *""#).is_err());
+
+    Ok(())
+  }
+}
@@ -0,0 +1,523 @@
+//! A compact text syntax for weekly schedules, e.g.
+//!
+//! ```text
+//! Mon-Fri 15:00-17:30 "homework break"; Sat,Sun 09:00-12:00
+//! ```
+//!
+//! Entries are separated by semicolons or new lines. Each is a list of days
+//! (names or ranges like `Fri-Mon`, which wrap around the week), the times the
+//! period starts and ends, and an optional quoted note. A period that ends at
+//! or before its start time runs past midnight into the next day, and `24:00`
+//! means midnight at the end of the day. Periods lasting a day or more are
+//! written with a day on both ends, e.g. `Fri 18:00-Sun 20:00`.
+
+use crate::config::{Instant, OpenPeriod};
+use crate::schedule;
+use serde::{de, Deserialize, Deserializer};
+use std::fmt;
+
+static DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+
+static LONG_DAYS: [&str; 7] = ["sunday", "monday", "tuesday", "wednesday", "thursday", "friday", "saturday"];
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// Where and why a schedule couldn't be parsed. Lines and columns count
+/// from 1.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+  pub line: usize,
+  pub column: usize,
+  pub message: String,
+}
+
+impl fmt::Display for ParseError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "line {}, column {}: {}", self.line, self.column, self.message)
+  }
+}
+
+impl std::error::Error for ParseError {}
+
+impl ParseError {
+  /// The line of the text with the error, and a caret under where it is.
+  pub fn pointer(&self, text: &str) -> String {
+    let line = text.lines().nth(self.line - 1).unwrap_or_default();
+    format!("  {}\n  {}^", line, " ".repeat(self.column - 1))
+  }
+}
+
+type Result<T> = std::result::Result<T, ParseError>;
+
+struct Parser {
+  chars: Vec<char>,
+  pos: usize,
+}
+
+impl Parser {
+  fn error<T>(&self, at: usize, message: String) -> Result<T> {
+    let before = &self.chars[..at];
+    let line_start = before.iter().rposition(|c| *c == '\n').map_or(0, |i| i + 1);
+
+    Err(ParseError {
+      line: before.iter().filter(|c| **c == '\n').count() + 1,
+      column: at - line_start + 1,
+      message,
+    })
+  }
+
+  fn peek(&self) -> Option<char> {
+    self.chars.get(self.pos).copied()
+  }
+
+  fn skip_spaces(&mut self) {
+    while let Some(' ') | Some('\t') | Some('\r') = self.peek() {
+      self.pos += 1;
+    }
+  }
+
+  fn eat(&mut self, c: char) -> bool {
+    let found = self.peek() == Some(c);
+    if found {
+      self.pos += 1;
+    }
+    found
+  }
+
+  fn expect(&mut self, c: char, what: &str) -> Result<()> {
+    match self.eat(c) {
+      true => Ok(()),
+      false => self.error(self.pos, format!("expected {}", what)),
+    }
+  }
+
+  fn take_while(&mut self, f: impl Fn(char) -> bool) -> String {
+    let start = self.pos;
+    while self.peek().map_or(false, &f) {
+      self.pos += 1;
+    }
+    self.chars[start..self.pos].iter().collect()
+  }
+
+  /// A day of the week, numbered from Sunday.
+  fn day(&mut self) -> Result<u8> {
+    let start = self.pos;
+    let word = self.take_while(char::is_alphabetic).to_lowercase();
+    if word.is_empty() {
+      return self.error(start, "expected a day, like Mon".to_owned());
+    }
+
+    // Any abbreviation of at least three letters will do.
+    match LONG_DAYS.iter().position(|day| word.len() >= 3 && day.starts_with(&word)) {
+      Some(day) => Ok(day as u8),
+      None => self.error(start, format!("unknown day {:?}", word)),
+    }
+  }
+
+  /// A list of days and ranges of days, like `Mon-Wed,Fri`.
+  fn days(&mut self) -> Result<Vec<u8>> {
+    let mut days = Vec::new();
+    loop {
+      let first = self.day()?;
+      match self.peek() {
+        // A range, unless the dash is between times.
+        Some('-') if self.chars.get(self.pos + 1).map_or(false, |c| c.is_alphabetic()) => {
+          self.pos += 1;
+          let last = self.day()?;
+          let mut day = first;
+          days.push(day);
+          while day != last {
+            day = (day + 1) % 7;
+            days.push(day);
+          }
+        }
+        _ => days.push(first),
+      }
+      if !self.eat(',') {
+        return Ok(days);
+      }
+    }
+  }
+
+  /// A time of day, in minutes. `24:00` is only allowed for ends.
+  fn time(&mut self, end: bool) -> Result<u32> {
+    let start = self.pos;
+    let hour = self.take_while(|c| c.is_ascii_digit());
+    if hour.is_empty() || hour.len() > 2 {
+      return self.error(start, "expected a time, like 15:30".to_owned());
+    }
+    self.expect(':', "a colon between hours and minutes")?;
+    let minute_start = self.pos;
+    let minute = self.take_while(|c| c.is_ascii_digit());
+    if minute.len() != 2 {
+      return self.error(minute_start, "expected two digits of minutes".to_owned());
+    }
+
+    let (hour, minute): (u32, u32) = (hour.parse().unwrap(), minute.parse().unwrap());
+    match (hour, minute) {
+      (24, 0) if end => Ok(MINUTES_PER_DAY),
+      (0..=23, 0..=59) => Ok(hour * 60 + minute),
+      _ => self.error(start, format!("{}:{:02} is not a time of day", hour, minute)),
+    }
+  }
+
+  /// A quoted note, with backslash escapes.
+  fn note(&mut self) -> Result<String> {
+    let start = self.pos;
+    self.expect('"', "a quoted note")?;
+    let mut note = String::new();
+    loop {
+      match self.peek() {
+        Some('"') => {
+          self.pos += 1;
+          return Ok(note);
+        }
+        Some('\\') if self.pos + 1 < self.chars.len() => {
+          note.push(self.chars[self.pos + 1]);
+          self.pos += 2;
+        }
+        Some('\n') | None => return self.error(start, "note is missing its closing quote".to_owned()),
+        Some(c) => {
+          note.push(c);
+          self.pos += 1;
+        }
+      }
+    }
+  }
+
+  fn entry(&mut self) -> Result<Vec<OpenPeriod>> {
+    let days_start = self.pos;
+    let days = self.days()?;
+    self.skip_spaces();
+    let start = self.time(false)?;
+    self.skip_spaces();
+    self.expect('-', "a dash between the start and end")?;
+    self.skip_spaces();
+
+    // Either another day, for a period lasting a day or more, or just a time.
+    let end_pos = self.pos;
+    let end_day = match self.peek() {
+      Some(c) if c.is_alphabetic() => {
+        if days.len() != 1 {
+          return self.error(days_start, "a period ending on another day can only start on one day".to_owned());
+        }
+        let day = self.day()?;
+        self.skip_spaces();
+        Some(day)
+      }
+      _ => None,
+    };
+    let end_time_pos = self.pos;
+    let end = self.time(true)?;
+
+    self.skip_spaces();
+    let note = match self.peek() {
+      Some('"') => self.note()?,
+      _ => String::new(),
+    };
+
+    let instant = |day: u8, minutes: u32| Instant {
+      weekday: (day as u32 + minutes / MINUTES_PER_DAY) as u8 % 7,
+      hour: (minutes % MINUTES_PER_DAY / 60) as u8,
+      minute: (minutes % 60) as u8,
+    };
+    let mut periods = Vec::new();
+    for day in days {
+      let end = match end_day {
+        Some(end_day) => instant(end_day, end),
+        None if end > start => instant(day, end),
+        None if end == start => return self.error(end_time_pos, "ends at the same time it starts".to_owned()),
+        None => instant(day, end + MINUTES_PER_DAY),
+      };
+      let period = OpenPeriod {
+        start: instant(day, start),
+        end,
+        note: note.clone(),
+      };
+      if schedule::minute_of_week(&period.start) == schedule::minute_of_week(&period.end) {
+        return self.error(end_pos, "ends at the same time it starts".to_owned());
+      }
+      periods.push(period);
+    }
+
+    Ok(periods)
+  }
+
+  fn schedule(&mut self) -> Result<Vec<OpenPeriod>> {
+    let mut periods = Vec::new();
+    loop {
+      while let Some(' ') | Some('\t') | Some('\r') | Some('\n') | Some(';') = self.peek() {
+        self.pos += 1;
+      }
+      if self.peek().is_none() {
+        return Ok(periods);
+      }
+
+      periods.extend(self.entry()?);
+      self.skip_spaces();
+      match self.peek() {
+        Some(';') | Some('\n') | None => {}
+        _ => return self.error(self.pos, "expected a semicolon or a new line".to_owned()),
+      }
+    }
+  }
+}
+
+/// Parses a schedule written in the syntax described above.
+pub fn parse(text: &str) -> Result<Vec<OpenPeriod>> {
+  Parser {
+    chars: text.chars().collect(),
+    pos: 0,
+  }
+  .schedule()
+}
+
+fn format_time(hour: u8, minute: u8) -> String {
+  format!("{:02}:{:02}", hour, minute)
+}
+
+fn format_note(note: &str) -> String {
+  match note.is_empty() {
+    true => String::new(),
+    false => format!(" \"{}\"", note.replace('\\', "\\\\").replace('"', "\\\"")),
+  }
+}
+
+/// Days of the week counted from Monday, which reads better in ranges.
+fn from_monday(day: u8) -> u8 {
+  (day + 6) % 7
+}
+
+/// Formats days as short ranges, e.g. `Mon-Wed,Fri`.
+fn format_days(days: &[u8]) -> String {
+  let mut days: Vec<u8> = days.iter().map(|d| from_monday(*d)).collect();
+  days.sort_unstable();
+  days.dedup();
+
+  let name = |day: u8| DAYS[((day + 1) % 7) as usize];
+  let mut runs: Vec<(u8, u8)> = Vec::new();
+  for day in days {
+    match runs.last_mut() {
+      Some((_, last)) if *last + 1 == day => *last = day,
+      _ => runs.push((day, day)),
+    }
+  }
+  // A run through Sunday carries on into the Monday run, e.g. `Fri-Mon`.
+  if runs.len() > 1 && runs[0].0 == 0 && runs[runs.len() - 1].1 == 6 {
+    let (_, last) = runs.remove(0);
+    runs.last_mut().unwrap().1 = last;
+  }
+
+  let mut parts = Vec::new();
+  for (first, last) in runs {
+    match (last + 7 - first) % 7 {
+      0 => parts.push(name(first).to_owned()),
+      1 => parts.push(format!("{},{}", name(first), name(last))),
+      _ => parts.push(format!("{}-{}", name(first), name(last))),
+    }
+  }
+
+  parts.join(",")
+}
+
+/// Writes periods in the syntax described above, combining periods with the
+/// same times and note on different days.
+pub fn format(periods: &[OpenPeriod]) -> String {
+  // Entries keyed by the first day they happen on and when they start, so
+  // they come out in the order they happen in the week.
+  let mut entries: Vec<((u8, u8, u8), String)> = Vec::new();
+  // Periods lasting less than a day, grouped by their times and note.
+  let mut groups: Vec<(&OpenPeriod, Vec<u8>)> = Vec::new();
+
+  for period in periods {
+    if schedule::period_length(period) >= chrono::Duration::days(1) {
+      entries.push((
+        (from_monday(period.start.weekday), period.start.hour, period.start.minute),
+        format!(
+          "{} {}-{} {}{}",
+          DAYS[period.start.weekday as usize],
+          format_time(period.start.hour, period.start.minute),
+          DAYS[period.end.weekday as usize],
+          format_time(period.end.hour, period.end.minute),
+          format_note(&period.note)
+        ),
+      ));
+      continue;
+    }
+
+    let same = |other: &OpenPeriod| {
+      (other.start.hour, other.start.minute, other.end.hour, other.end.minute, &other.note)
+        == (period.start.hour, period.start.minute, period.end.hour, period.end.minute, &period.note)
+    };
+    match groups.iter_mut().find(|(other, _)| same(other)) {
+      Some((_, days)) => days.push(period.start.weekday),
+      None => groups.push((period, vec![period.start.weekday])),
+    }
+  }
+
+  for (period, days) in groups {
+    let first = days.iter().map(|d| from_monday(*d)).min().unwrap_or_default();
+    entries.push((
+      (first, period.start.hour, period.start.minute),
+      format!(
+        "{} {}-{}{}",
+        format_days(&days),
+        format_time(period.start.hour, period.start.minute),
+        format_time(period.end.hour, period.end.minute),
+        format_note(&period.note)
+      ),
+    ));
+  }
+  entries.sort_by_key(|(key, _)| *key);
+
+  entries.into_iter().map(|(_, entry)| entry).collect::<Vec<_>>().join("; ")
+}
+
+struct PeriodsVisitor;
+
+impl<'de> de::Visitor<'de> for PeriodsVisitor {
+  type Value = Vec<OpenPeriod>;
+
+  fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "a list of periods, or a schedule like \"Mon-Fri 15:00-17:00\"")
+  }
+
+  fn visit_str<E: de::Error>(self, text: &str) -> std::result::Result<Self::Value, E> {
+    parse(text).map_err(E::custom)
+  }
+
+  fn visit_seq<A: de::SeqAccess<'de>>(self, seq: A) -> std::result::Result<Self::Value, A::Error> {
+    Deserialize::deserialize(de::value::SeqAccessDeserializer::new(seq))
+  }
+}
+
+/// Lets a list of periods in the config be written either as JSON or in the
+/// syntax described above.
+pub fn deserialize_periods<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Vec<OpenPeriod>, D::Error> {
+  deserializer.deserialize_any(PeriodsVisitor)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::config::Schedule;
+
+  fn period(start: (u8, u8, u8), end: (u8, u8, u8), note: &str) -> OpenPeriod {
+    OpenPeriod {
+      start: Instant {
+        weekday: start.0,
+        hour: start.1,
+        minute: start.2,
+      },
+      end: Instant {
+        weekday: end.0,
+        hour: end.1,
+        minute: end.2,
+      },
+      note: note.to_owned(),
+    }
+  }
+
+  fn error(text: &str) -> String {
+    parse(text).unwrap_err().to_string()
+  }
+
+  #[test]
+  fn test_parse() -> Result<()> {
+    assert_eq!(
+      vec![
+        period((1, 15, 0), (1, 17, 30), "homework break"),
+        period((2, 15, 0), (2, 17, 30), "homework break"),
+        period((3, 15, 0), (3, 17, 30), "homework break"),
+        period((4, 15, 0), (4, 17, 30), "homework break"),
+        period((5, 15, 0), (5, 17, 30), "homework break"),
+        period((6, 9, 0), (6, 12, 0), ""),
+        period((0, 9, 0), (0, 12, 0), ""),
+      ],
+      parse(r#"Mon-Fri 15:00-17:30 "homework break"; Sat,Sun 09:00-12:00"#)?
+    );
+
+    // Past midnight, to midnight, wrapping ranges and periods over days.
+    assert_eq!(
+      vec![
+        period((6, 22, 0), (0, 1, 0), ""),
+        period((5, 20, 0), (6, 0, 0), "say \"hi\""),
+        period((6, 20, 0), (0, 0, 0), "say \"hi\""),
+        period((5, 18, 0), (0, 20, 0), ""),
+      ],
+      parse("saturday 22:00 - 1:00\nFri-sat 20:00-24:00 \"say \\\"hi\\\"\";\n\nFri 18:00-Sun 20:00;")?
+    );
+    assert_eq!(Vec::<OpenPeriod>::new(), parse(" ; \n")?);
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_errors() {
+    assert_eq!("line 1, column 1: unknown day \"mo\"", error("Mo 15:00-16:00"));
+    assert_eq!("line 2, column 10: 25:00 is not a time of day", error("Mon 15:00-16:00\nTue 9:00-25:00"));
+    assert_eq!("line 1, column 14: expected two digits of minutes", error("Mon 15:00-16:0"));
+    assert_eq!("line 1, column 11: ends at the same time it starts", error("Mon 15:00-15:00"));
+    assert_eq!("line 1, column 17: expected a semicolon or a new line", error("Mon 15:00-16:00 homework"));
+    assert_eq!("line 1, column 17: note is missing its closing quote", error("Mon 15:00-16:00 \"homework"));
+    assert_eq!(
+      "line 1, column 1: a period ending on another day can only start on one day",
+      error("Fri,Sat 18:00-Sun 20:00")
+    );
+    assert_eq!("line 1, column 5: 24:00 is not a time of day", error("Mon 24:00-01:00"));
+
+    let text = "Mon 15:00-16:00\nTue 9:00-25:00";
+    assert_eq!("  Tue 9:00-25:00\n           ^", parse(text).unwrap_err().pointer(text));
+  }
+
+  #[test]
+  fn test_format() -> Result<()> {
+    let text = r#"Mon-Fri 15:00-17:30 "homework break"; Sat,Sun 09:00-12:00"#;
+    assert_eq!(text, format(&parse(text)?));
+    assert_eq!(
+      "Wed,Sat-Mon 22:00-01:00; Fri 18:00-Sun 20:00 \"weekend\"",
+      format(&[
+        period((5, 18, 0), (0, 20, 0), "weekend"),
+        period((1, 22, 0), (2, 1, 0), ""),
+        period((3, 22, 0), (4, 1, 0), ""),
+        period((6, 22, 0), (0, 1, 0), ""),
+        period((0, 22, 0), (1, 1, 0), ""),
+      ])
+    );
+    assert_eq!("Fri-Mon 20:00-00:00", format(&parse("Fri-Mon 20:00-24:00")?));
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_round_trip() -> Result<()> {
+    let text = "Mon,Tue,Thu 15:30-17:00 \"a \\\"note\\\"\"; Wed 23:00-Fri 07:00; Sat 22:00-01:00; Sun 09:00-21:00";
+    let periods = parse(text)?;
+    assert_eq!(periods.len(), 6);
+    assert_eq!(text, format(&periods));
+
+    let mut reparsed = parse(&format(&periods))?;
+    let mut periods = periods;
+    let key = |p: &OpenPeriod| schedule::minute_of_week(&p.start);
+    periods.sort_by_key(key);
+    reparsed.sort_by_key(key);
+    assert_eq!(periods, reparsed);
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_deserialize() -> anyhow::Result<()> {
+    let text: Schedule = serde_json::from_str(r#"{ "open_periods": "Mon 15:00-16:00" }"#)?;
+    let json: Schedule = serde_json::from_str(
+      r#"{ "open_periods": [ { "start": { "weekday": 1, "hour": 15, "minute": 0 },
+                              "end": { "weekday": 1, "hour": 16, "minute": 0 }, "note": "" } ] }"#,
+    )?;
+    assert_eq!(json.open_periods, text.open_periods);
+
+    let error = serde_json::from_str::<Schedule>(r#"{ "open_periods": "Mon 15:00" }"#).unwrap_err();
+    assert!(error.to_string().starts_with("line 1, column 10: expected a dash"));
+
+    Ok(())
+  }
+}
@@ -0,0 +1,302 @@
+//! Follows a user's calendar feed, so their open time can be planned in a
+//! shared calendar. The feed is polled every few minutes and the last good
+//! copy is kept on disk, so the schedule keeps working while the network (or
+//! the calendar) is down. Polling happens on a thread of its own, so a slow
+//! or broken feed never holds up the runloop or the API; they only ever read
+//! the events found by the last poll.
+
+use crate::config::{CalendarFeed, Config};
+use crate::ical;
+use crate::os;
+use crate::schedule::{Occurrence, Zone};
+use anyhow::{bail, Result};
+use chrono::{DateTime, Duration, Local};
+use log::{error, info, warn};
+use std::{
+  collections::HashMap,
+  fs,
+  path::PathBuf,
+  sync::{Arc, Mutex},
+  thread,
+};
+
+#[cfg(not(debug_assertions))]
+static CACHE_DIR: &str = "/usr/local/etc/heimdall/feeds";
+#[cfg(debug_assertions)]
+static CACHE_DIR: &str = "/tmp/heimdall/feeds";
+
+/// How far ahead to work out the feed's events on each poll.
+const LOOKAHEAD_WEEKS: i64 = 2;
+
+/// How often the poller checks for feeds that are due.
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(15);
+
+/// Downloads a feed.
+pub fn fetch(url: &str) -> Result<String> {
+  let text = ureq::get(url)
+    .timeout(std::time::Duration::from_secs(10))
+    .call()?
+    .into_string()?;
+  if !text.trim_start().starts_with("BEGIN:VCALENDAR") {
+    bail!("{} is not an iCalendar file", url);
+  }
+
+  Ok(text)
+}
+
+/// A user's feed while the daemon is running.
+pub struct Feed {
+  cache_path: PathBuf,
+  url: Option<String>,
+  /// The last copy fetched successfully.
+  text: Option<String>,
+  last_poll: Option<DateTime<Local>>,
+  /// The feed's events from the last poll until a couple of weeks after it.
+  pub occurrences: Vec<Occurrence>,
+}
+
+impl Feed {
+  /// Starts from the cached copy of the user's feed, if there is one.
+  pub fn load(user: &str) -> Feed {
+    Feed::with_cache(PathBuf::from(CACHE_DIR).join(format!("{}.ics", user)))
+  }
+
+  fn with_cache(cache_path: PathBuf) -> Feed {
+    Feed {
+      text: fs::read_to_string(&cache_path).ok(),
+      cache_path,
+      url: None,
+      last_poll: None,
+      occurrences: Vec::new(),
+    }
+  }
+
+  fn save(&self, text: &str) -> Result<()> {
+    if let Some(parent) = self.cache_path.parent() {
+      fs::create_dir_all(parent)?;
+    }
+
+    os::write_atomically(&self.cache_path, text.as_bytes())
+  }
+
+  /// Fetches the feed again if it's due, and works out its events from now
+  /// on. If the feed can't be fetched, the last good copy is used instead.
+  pub fn poll(&mut self, config: &CalendarFeed, zone: Zone, now: DateTime<Local>) {
+    let url_changed = self.url.as_ref().map_or(false, |url| *url != config.url);
+    if url_changed {
+      info!("Calendar feed changed to {}", config.url);
+      self.text = None;
+    }
+    let due = self
+      .last_poll
+      .map_or(true, |last| now - last >= Duration::minutes(config.poll_minutes.into()));
+    if !due && !url_changed {
+      return;
+    }
+    self.url = Some(config.url.clone());
+    self.last_poll = Some(now);
+
+    match fetch(&config.url) {
+      Ok(text) => {
+        if self.text.as_ref() != Some(&text) {
+          info!("Calendar feed {} has changed", config.url);
+          if let Err(e) = self.save(&text) {
+            error!("Couldn't cache calendar feed: {}", e);
+          }
+        }
+        self.text = Some(text);
+      }
+      Err(e) => error!("Couldn't fetch calendar feed {}, using the last copy: {}", config.url, e),
+    }
+
+    let to = now + Duration::weeks(LOOKAHEAD_WEEKS);
+    let (occurrences, warnings) = match &self.text {
+      Some(text) => ical::feed_occurrences(text, &config.title, zone, now, to),
+      None => (Vec::new(), Vec::new()),
+    };
+    for warning in warnings {
+      warn!("In calendar feed {}: {}", config.url, warning);
+    }
+    self.occurrences = occurrences;
+  }
+}
+
+/// The feeds users follow and their events as of the last poll, shared
+/// between the poller, the runloop and the API.
+#[derive(Clone, Default)]
+pub struct Feeds(Arc<Mutex<Followed>>);
+
+#[derive(Default)]
+struct Followed {
+  /// Each user's feed, and the zone to read it in.
+  configs: HashMap<String, (CalendarFeed, Zone)>,
+  occurrences: HashMap<String, Vec<Occurrence>>,
+}
+
+impl Feeds {
+  /// Starts polling on a thread of its own.
+  pub fn start() -> Feeds {
+    let feeds = Feeds::default();
+    let poller = feeds.clone();
+    thread::spawn(move || {
+      let mut polled = HashMap::new();
+      loop {
+        poller.poll(&mut polled, Local::now());
+        thread::sleep(POLL_INTERVAL);
+      }
+    });
+
+    feeds
+  }
+
+  /// Follows the feeds in the config, forgetting any that have gone.
+  pub fn follow(&self, config: &Config) {
+    let zone = Zone::for_config(config);
+    let configs: HashMap<_, _> = config
+      .user_config
+      .iter()
+      .filter_map(|(user, user_config)| Some((user.clone(), (user_config.calendar_feed.clone()?, zone))))
+      .collect();
+
+    let mut followed = self.0.lock().unwrap();
+    followed.occurrences.retain(|user, _| configs.contains_key(user));
+    followed.configs = configs;
+  }
+
+  /// The user's events as of the last poll.
+  pub fn occurrences(&self, user: &str) -> Vec<Occurrence> {
+    self.0.lock().unwrap().occurrences.get(user).cloned().unwrap_or_default()
+  }
+
+  /// Polls every followed feed. The lock is only held to read what to poll
+  /// and to store what was found, never while fetching.
+  fn poll(&self, polled: &mut HashMap<String, Feed>, now: DateTime<Local>) {
+    let configs = self.0.lock().unwrap().configs.clone();
+    polled.retain(|user, _| configs.contains_key(user));
+
+    for (user, (config, zone)) in configs {
+      let feed = polled.entry(user.clone()).or_insert_with(|| Feed::load(&user));
+      feed.poll(&config, zone, now);
+
+      let mut followed = self.0.lock().unwrap();
+      // Unless it was unfollowed while we were fetching.
+      if followed.configs.get(&user).map_or(false, |(c, _)| *c == config) {
+        followed.occurrences.insert(user, feed.occurrences.clone());
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono::TimeZone;
+  use std::io::{Read, Write};
+  use std::net::TcpListener;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  static CALENDAR: &str = "BEGIN:VCALENDAR\r
+VERSION:2.0\r
+BEGIN:VEVENT\r
+SUMMARY:Screen time\r
+DTSTART:20210301T150000\r
+DTEND:20210301T170000\r
+RRULE:FREQ=WEEKLY;BYDAY=MO,WE\r
+END:VEVENT\r
+BEGIN:VEVENT\r
+SUMMARY:Homework\r
+DTSTART:20210302T150000\r
+DTEND:20210302T170000\r
+END:VEVENT\r
+END:VCALENDAR\r
+";
+
+  /// Serves the body to every request, standing in for a calendar server.
+  /// Returns the URL to fetch and the number of requests served so far.
+  fn serve(body: &'static str) -> (String, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/family.ics", listener.local_addr().unwrap());
+    let requests = Arc::new(AtomicUsize::new(0));
+    let served = Arc::clone(&requests);
+    thread::spawn(move || {
+      for stream in listener.incoming() {
+        let mut stream = stream.unwrap();
+        let mut request = [0; 4096];
+        let _ = stream.read(&mut request);
+        served.fetch_add(1, Ordering::SeqCst);
+        write!(
+          stream,
+          "HTTP/1.1 200 OK\r\nContent-Type: text/calendar\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+          body.len(),
+          body
+        )
+        .unwrap();
+      }
+    });
+
+    (url, requests)
+  }
+
+  /// A URL nothing is listening on.
+  fn unreachable() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    format!("http://{}/family.ics", listener.local_addr().unwrap())
+  }
+
+  fn feed_config(url: String) -> CalendarFeed {
+    CalendarFeed {
+      url,
+      title: "screen time".to_owned(),
+      poll_minutes: 15,
+    }
+  }
+
+  fn cache_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("heimdall-test-{}-{}.ics", std::process::id(), name));
+    let _ = fs::remove_file(&path);
+    path
+  }
+
+  #[test]
+  fn test_fetch() -> Result<()> {
+    assert_eq!(CALENDAR, fetch(&serve(CALENDAR).0)?);
+    assert!(fetch(&serve("<html>Not found</html>").0).is_err());
+    assert!(fetch(&unreachable()).is_err());
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_poll() {
+    // 2021-03-01 is a Monday.
+    let now = Local.ymd(2021, 3, 1).and_hms(12, 0, 0);
+    let path = cache_path("poll");
+    let (url, requests) = serve(CALENDAR);
+    let config = feed_config(url);
+    let mut feed = Feed::with_cache(path.clone());
+    feed.poll(&config, Zone::Local, now);
+
+    let starts: Vec<_> = feed.occurrences.iter().map(|o| o.start).collect();
+    let day = |d| Local.ymd(2021, 3, d).and_hms(15, 0, 0);
+    assert_eq!(vec![day(1), day(3), day(8), day(10)], starts);
+    assert_eq!(Some(CALENDAR), fs::read_to_string(&path).ok().as_deref());
+    assert_eq!(1, requests.load(Ordering::SeqCst));
+
+    // Not due yet, so it doesn't try to fetch again.
+    feed.poll(&config, Zone::Local, now + Duration::minutes(5));
+    assert_eq!(4, feed.occurrences.len());
+    assert_eq!(1, requests.load(Ordering::SeqCst));
+
+    // But it does once it's due.
+    feed.poll(&config, Zone::Local, now + Duration::minutes(15));
+    assert_eq!(2, requests.load(Ordering::SeqCst));
+
+    // Offline, so it uses the cached copy.
+    let mut feed = Feed::with_cache(path.clone());
+    let later = now + Duration::days(3);
+    feed.poll(&feed_config(unreachable()), Zone::Local, later);
+    assert_eq!(day(8), feed.occurrences[0].start);
+
+    let _ = fs::remove_file(&path);
+  }
+}
@@ -0,0 +1,1058 @@
+//! Converts between iCalendar (RFC 5545) files and schedules.
+//!
+//! Only the parts of the format that make sense for a weekly schedule are
+//! supported: events that repeat weekly or daily forever become open periods,
+//! whole days for all-day events, and EXDATEs on them become dated
+//! exceptions. One-off events and rules that end are expanded into dated
+//! exceptions instead. TZIDs must
+//! name IANA timezones, which is what most calendar apps use. Anything else is
+//! reported as a warning rather than silently dropped.
+
+use crate::config::{DayPeriod, ExceptionKind, Instant, OpenPeriod, ScheduleException, TimeOfDay};
+use crate::schedule::{self, Occurrence, Zone};
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, NaiveDateTime, TimeZone, Timelike, Utc, Weekday};
+use chrono_tz::Tz;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Rules that end are expanded into at most this many exceptions.
+const MAX_OCCURRENCES: usize = 500;
+
+/// Lines longer than this many bytes are folded.
+const MAX_LINE_LENGTH: usize = 75;
+
+struct Property {
+  name: String,
+  params: Vec<(String, String)>,
+  value: String,
+}
+
+impl Property {
+  fn param(&self, name: &str) -> Option<&str> {
+    self.params.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+  }
+}
+
+/// Joins folded lines back together.
+fn unfold(text: &str) -> Vec<String> {
+  let mut lines: Vec<String> = Vec::new();
+  for line in text.lines() {
+    match (line.strip_prefix(' ').or_else(|| line.strip_prefix('\t')), lines.last_mut()) {
+      (Some(rest), Some(last)) => last.push_str(rest),
+      _ if line.is_empty() => {}
+      _ => lines.push(line.to_owned()),
+    }
+  }
+
+  lines
+}
+
+fn parse_property(line: &str) -> Result<Property> {
+  // The value starts at the first colon that isn't in a quoted parameter.
+  let mut quoted = false;
+  let colon = line
+    .char_indices()
+    .find(|(_, c)| {
+      if *c == '"' {
+        quoted = !quoted;
+      }
+      *c == ':' && !quoted
+    })
+    .map(|(i, _)| i)
+    .ok_or_else(|| anyhow!("Not a property: {}", line))?;
+
+  let mut head = line[..colon].split(';');
+  let name = head.next().unwrap_or_default().to_ascii_uppercase();
+  let params = head
+    .filter_map(|p| p.split_once('='))
+    .map(|(n, v)| (n.to_ascii_uppercase(), v.trim_matches('"').to_owned()))
+    .collect();
+
+  Ok(Property {
+    name,
+    params,
+    value: line[colon + 1..].to_owned(),
+  })
+}
+
+fn escape(text: &str) -> String {
+  text
+    .replace('\\', "\\\\")
+    .replace(';', "\\;")
+    .replace(',', "\\,")
+    .replace('\n', "\\n")
+}
+
+fn unescape(text: &str) -> String {
+  let mut result = String::new();
+  let mut chars = text.chars();
+  while let Some(c) = chars.next() {
+    match (c, c == '\\') {
+      (_, true) => match chars.next() {
+        Some('n') | Some('N') => result.push('\n'),
+        Some(c) => result.push(c),
+        None => {}
+      },
+      (c, false) => result.push(c),
+    }
+  }
+
+  result
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TimeKind {
+  Floating,
+  Utc,
+  Zoned(Tz),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct EventTime {
+  naive: NaiveDateTime,
+  kind: TimeKind,
+  date_only: bool,
+}
+
+impl EventTime {
+  fn parse(value: &str, kind: TimeKind, date_only: bool) -> Result<EventTime> {
+    let (value, kind) = match value.strip_suffix('Z') {
+      Some(value) => (value, TimeKind::Utc),
+      None => (value, kind),
+    };
+
+    Ok(match date_only || value.len() == 8 {
+      true => EventTime {
+        naive: NaiveDate::parse_from_str(value, "%Y%m%d")?.and_hms(0, 0, 0),
+        kind,
+        date_only: true,
+      },
+      false => EventTime {
+        naive: NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S")?,
+        kind,
+        date_only: false,
+      },
+    })
+  }
+
+  /// Parses every time in a property, like DTSTART or EXDATE.
+  fn parse_all(property: &Property) -> Result<Vec<EventTime>> {
+    let kind = match property.param("TZID") {
+      Some(tzid) => TimeKind::Zoned(
+        tzid
+          .trim_start_matches('/')
+          .parse()
+          .map_err(|_| anyhow!("Unknown timezone {}", tzid))?,
+      ),
+      None => TimeKind::Floating,
+    };
+    let date_only = property.param("VALUE") == Some("DATE");
+
+    property.value.split(',').map(|v| EventTime::parse(v, kind, date_only)).collect()
+  }
+
+  /// The same time of day on another date.
+  fn on(&self, date: NaiveDate) -> EventTime {
+    EventTime {
+      naive: date.and_time(self.naive.time()),
+      ..*self
+    }
+  }
+
+  /// The instant, taking floating times to be in the schedule's timezone.
+  fn at(&self, zone: Zone) -> Result<DateTime<Local>> {
+    match self.kind {
+      TimeKind::Floating => zone.localize(&self.naive),
+      TimeKind::Utc => Some(Utc.from_utc_datetime(&self.naive).with_timezone(&Local)),
+      TimeKind::Zoned(tz) => tz.from_local_datetime(&self.naive).earliest().map(|t| t.with_timezone(&Local)),
+    }
+    .ok_or_else(|| anyhow!("{} doesn't exist", self.naive))
+  }
+
+  /// The time on the wall clock in the schedule's timezone.
+  fn in_zone(&self, zone: Zone) -> Result<NaiveDateTime> {
+    match self.kind {
+      TimeKind::Floating => Ok(self.naive),
+      _ => Ok(zone.naive(self.at(zone)?)),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Frequency {
+  Daily,
+  Weekly,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Rule {
+  frequency: Frequency,
+  interval: u32,
+  by_day: Vec<Weekday>,
+  until: Option<EventTime>,
+  count: Option<usize>,
+}
+
+fn parse_weekday(day: &str) -> Result<Weekday> {
+  Ok(match day {
+    "SU" => Weekday::Sun,
+    "MO" => Weekday::Mon,
+    "TU" => Weekday::Tue,
+    "WE" => Weekday::Wed,
+    "TH" => Weekday::Thu,
+    "FR" => Weekday::Fri,
+    "SA" => Weekday::Sat,
+    _ => bail!("Unsupported BYDAY={}", day),
+  })
+}
+
+impl Rule {
+  fn parse(value: &str, kind: TimeKind) -> Result<Rule> {
+    let mut rule = Rule {
+      frequency: Frequency::Weekly,
+      interval: 1,
+      by_day: Vec::new(),
+      until: None,
+      count: None,
+    };
+    let mut frequency = None;
+
+    for part in value.split(';') {
+      let (name, value) = part.split_once('=').ok_or_else(|| anyhow!("Bad RRULE part {}", part))?;
+      match name {
+        "FREQ" => {
+          frequency = Some(match value {
+            "DAILY" => Frequency::Daily,
+            "WEEKLY" => Frequency::Weekly,
+            _ => bail!("Unsupported FREQ={}", value),
+          })
+        }
+        "INTERVAL" => rule.interval = value.parse()?,
+        "BYDAY" => {
+          for day in value.split(',') {
+            rule.by_day.push(parse_weekday(day)?);
+          }
+        }
+        "UNTIL" => rule.until = Some(EventTime::parse(value, kind, false)?),
+        "COUNT" => rule.count = Some(value.parse()?),
+        "WKST" => {}
+        _ => bail!("Unsupported RRULE part {}", part),
+      }
+    }
+
+    rule.frequency = frequency.ok_or_else(|| anyhow!("RRULE without FREQ"))?;
+    if rule.interval == 0 {
+      bail!("RRULE with INTERVAL=0");
+    }
+
+    Ok(rule)
+  }
+
+  fn is_endless(&self) -> bool {
+    self.until.is_none() && self.count.is_none()
+  }
+
+  /// The days of the week the rule happens on.
+  fn days(&self, start: Weekday) -> Vec<Weekday> {
+    match (self.frequency, self.by_day.is_empty()) {
+      (_, false) => self.by_day.clone(),
+      (Frequency::Weekly, true) => vec![start],
+      (Frequency::Daily, true) => vec![
+        Weekday::Sun,
+        Weekday::Mon,
+        Weekday::Tue,
+        Weekday::Wed,
+        Weekday::Thu,
+        Weekday::Fri,
+        Weekday::Sat,
+      ],
+    }
+  }
+}
+
+/// Parses an RFC 5545 duration like `PT1H30M` or `P1D`.
+fn parse_duration(value: &str) -> Result<Duration> {
+  let (negative, value) = match value.strip_prefix('-') {
+    Some(value) => (true, value),
+    None => (false, value.trim_start_matches('+')),
+  };
+  let value = value.strip_prefix('P').ok_or_else(|| anyhow!("Bad duration {}", value))?;
+
+  let mut duration = Duration::zero();
+  let mut number = String::new();
+  for c in value.chars() {
+    match c {
+      '0'..='9' => number.push(c),
+      'T' => {}
+      'W' | 'D' | 'H' | 'M' | 'S' => {
+        let n: i64 = number.parse()?;
+        number.clear();
+        duration = duration
+          + match c {
+            'W' => Duration::weeks(n),
+            'D' => Duration::days(n),
+            'H' => Duration::hours(n),
+            'M' => Duration::minutes(n),
+            _ => Duration::seconds(n),
+          };
+      }
+      _ => bail!("Bad duration {}", value),
+    }
+  }
+
+  Ok(if negative { -duration } else { duration })
+}
+
+#[derive(Debug)]
+struct Event {
+  summary: String,
+  start: EventTime,
+  end: Option<EventTime>,
+  duration: Option<Duration>,
+  rule: Option<Rule>,
+  exdates: Vec<EventTime>,
+}
+
+impl Event {
+  fn parse(properties: &[Property]) -> Result<Event> {
+    let mut summary = String::new();
+    let mut start = None;
+    let mut end = None;
+    let mut duration = None;
+    let mut rule = None;
+    let mut exdates = Vec::new();
+
+    for property in properties {
+      match property.name.as_str() {
+        "SUMMARY" => summary = unescape(&property.value),
+        "DTSTART" => start = EventTime::parse_all(property)?.into_iter().next(),
+        "DTEND" => end = EventTime::parse_all(property)?.into_iter().next(),
+        "DURATION" => duration = Some(parse_duration(&property.value)?),
+        "RRULE" => rule = Some(property),
+        "EXDATE" => exdates.extend(EventTime::parse_all(property)?),
+        "RDATE" | "EXRULE" => bail!("{} is not supported", property.name),
+        _ => {}
+      }
+    }
+
+    let start = start.ok_or_else(|| anyhow!("Event without DTSTART"))?;
+    let rule = match rule {
+      Some(property) => Some(Rule::parse(&property.value, start.kind)?),
+      None => None,
+    };
+
+    Ok(Event {
+      summary,
+      start,
+      end,
+      duration,
+      rule,
+      exdates,
+    })
+  }
+
+  fn length(&self) -> Duration {
+    match (&self.end, self.duration) {
+      (Some(end), _) => end.naive - self.start.naive,
+      (None, Some(duration)) => duration,
+      (None, None) if self.start.date_only => Duration::days(1),
+      (None, None) => Duration::zero(),
+    }
+  }
+
+  fn is_excluded(&self, time: &EventTime, zone: Zone) -> bool {
+    self.exdates.iter().any(|exdate| match exdate.date_only {
+      true => exdate.naive.date() == time.naive.date(),
+      false => exdate.in_zone(zone).ok() == time.in_zone(zone).ok(),
+    })
+  }
+
+  /// Every time the event starts, or only the times starting on the dates in
+  /// a window for events that might repeat forever. Floating times, like an
+  /// EXDATE without a TZID, are taken to be in the schedule's timezone.
+  fn occurrences(&self, window: Option<(NaiveDate, NaiveDate)>, zone: Zone) -> Result<Vec<EventTime>> {
+    let rule = match &self.rule {
+      Some(rule) => rule,
+      None => return Ok(vec![self.start]),
+    };
+    let in_window = |date: NaiveDate| window.map_or(true, |(first, last)| first <= date && date <= last);
+
+    let first = self.start.naive.date();
+    let mut days = rule.days(first.weekday());
+    days.sort_by_key(|d| d.num_days_from_monday());
+    // Every INTERVAL days lands on the same weekday each time when INTERVAL
+    // is a multiple of 7, so BYDAY has to include it.
+    if rule.frequency == Frequency::Daily && rule.interval % 7 == 0 && !days.contains(&first.weekday()) {
+      bail!("Repeats every {} days but never on one of its BYDAY days", rule.interval);
+    }
+    let (mut period, step) = match rule.frequency {
+      Frequency::Daily => (first, Duration::days(rule.interval.into())),
+      Frequency::Weekly => (
+        first - Duration::days(first.weekday().num_days_from_monday().into()),
+        Duration::weeks(rule.interval.into()),
+      ),
+    };
+
+    let until = match &rule.until {
+      Some(until) => Some(until.in_zone(zone)?),
+      None => None,
+    };
+    let mut generated = 0;
+    let mut occurrences = Vec::new();
+    loop {
+      // Checked for each period as well as each date, so a period without any
+      // dates can't keep the loop going.
+      if rule.count.map_or(false, |count| generated >= count)
+        || until.map_or(false, |until| period > until.date())
+        || window.map_or(false, |(_, last)| period > last)
+      {
+        return Ok(occurrences);
+      }
+
+      let dates: Vec<NaiveDate> = match rule.frequency {
+        Frequency::Daily if days.contains(&period.weekday()) => vec![period],
+        Frequency::Daily => vec![],
+        Frequency::Weekly => days
+          .iter()
+          .map(|d| period + Duration::days(d.num_days_from_monday().into()))
+          .collect(),
+      };
+
+      for date in dates.into_iter().filter(|d| *d >= first) {
+        let time = self.start.on(date);
+        if rule.count.map_or(false, |count| generated >= count) {
+          return Ok(occurrences);
+        }
+        if let Some(until) = until {
+          if time.in_zone(zone)? > until {
+            return Ok(occurrences);
+          }
+        }
+        if window.map_or(false, |(_, last)| date > last) {
+          return Ok(occurrences);
+        }
+        generated += 1;
+
+        if in_window(date) && !self.is_excluded(&time, zone) {
+          occurrences.push(time);
+        }
+        if occurrences.len() > MAX_OCCURRENCES {
+          bail!("Repeats more than {} times", MAX_OCCURRENCES);
+        }
+      }
+
+      period += step;
+    }
+  }
+}
+
+/// Parses every VEVENT in the file. Events that can't be parsed are skipped
+/// with a warning.
+fn parse_events(text: &str) -> (Vec<Event>, Vec<String>) {
+  let mut events = Vec::new();
+  let mut warnings = Vec::new();
+  let mut properties: Option<Vec<Property>> = None;
+  // How deep we are in components inside an event, like VALARM.
+  let mut nested = 0;
+
+  for line in unfold(text) {
+    let property = match parse_property(&line) {
+      Ok(property) => property,
+      Err(e) => {
+        warnings.push(e.to_string());
+        continue;
+      }
+    };
+
+    match (property.name.as_str(), property.value.as_str(), &mut properties) {
+      ("BEGIN", "VEVENT", None) => properties = Some(Vec::new()),
+      ("END", "VEVENT", Some(_)) if nested == 0 => {
+        let event = properties.take().unwrap_or_default();
+        match Event::parse(&event) {
+          Ok(event) => events.push(event),
+          Err(e) => {
+            let summary = event.iter().find(|p| p.name == "SUMMARY").map(|p| unescape(&p.value));
+            warnings.push(format!("Skipped {}: {}", summary.unwrap_or_default(), e));
+          }
+        }
+      }
+      ("BEGIN", _, Some(_)) => nested += 1,
+      ("END", _, Some(_)) => nested -= 1,
+      (_, _, Some(event)) if nested == 0 => event.push(property),
+      _ => {}
+    }
+  }
+
+  (events, warnings)
+}
+
+fn instant(time: NaiveDateTime) -> Instant {
+  Instant {
+    weekday: time.weekday().num_days_from_sunday() as u8,
+    hour: time.hour() as u8,
+    minute: time.minute() as u8,
+  }
+}
+
+fn time_of_day(time: NaiveDateTime) -> TimeOfDay {
+  TimeOfDay {
+    hour: time.hour() as u8,
+    minute: time.minute() as u8,
+  }
+}
+
+fn weekday(date: NaiveDate) -> u8 {
+  date.weekday().num_days_from_sunday() as u8
+}
+
+/// The period as a single day's period, if it's short enough to be one.
+fn day_period(period: &OpenPeriod) -> Option<DayPeriod> {
+  if schedule::period_length(period) >= Duration::days(1) {
+    return None;
+  }
+
+  Some(DayPeriod {
+    start: TimeOfDay {
+      hour: period.start.hour,
+      minute: period.start.minute,
+    },
+    end: TimeOfDay {
+      hour: period.end.hour,
+      minute: period.end.minute,
+    },
+    note: period.note.clone(),
+  })
+}
+
+/// What an iCalendar file turns into.
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct Import {
+  pub open_periods: Vec<OpenPeriod>,
+  pub exceptions: Vec<ScheduleException>,
+  pub warnings: Vec<String>,
+}
+
+impl Import {
+  fn add_endless(&mut self, excluded: &mut BTreeMap<NaiveDate, Vec<OpenPeriod>>, event: &Event, zone: Zone) -> Result<()> {
+    let rule = event.rule.as_ref().unwrap();
+    if rule.interval != 1 {
+      bail!("Repeats every {} periods, which a weekly schedule can't do", rule.interval);
+    }
+    if event.length() >= Duration::weeks(1) {
+      bail!("Lasts a week or more");
+    }
+
+    let first = event.start.naive.date();
+    let mut periods = Vec::new();
+    for day in rule.days(first.weekday()) {
+      let days_ahead = (day.num_days_from_sunday() + 7 - first.weekday().num_days_from_sunday()) % 7;
+      let start = event.start.on(first + Duration::days(days_ahead.into())).in_zone(zone)?;
+      periods.push(OpenPeriod {
+        start: instant(start),
+        end: instant(start + event.length()),
+        note: event.summary.clone(),
+      });
+    }
+
+    for exdate in &event.exdates {
+      let date = event.start.on(exdate.naive.date());
+      let start = match exdate.date_only {
+        true => date.in_zone(zone)?,
+        false => exdate.in_zone(zone)?,
+      };
+      if let Some(period) = periods.iter().find(|p| p.start.weekday == weekday(start.date())) {
+        excluded.entry(start.date()).or_default().push(period.clone());
+      }
+    }
+
+    self.open_periods.extend(periods);
+    Ok(())
+  }
+
+  fn add_dated(&mut self, event: &Event, zone: Zone) -> Result<()> {
+    let length = event.length();
+    if !event.start.date_only && length >= Duration::days(1) {
+      bail!("Lasts a day or more");
+    }
+
+    for occurrence in event.occurrences(None, zone)? {
+      let start = occurrence.in_zone(zone)?;
+      let end = start + length;
+      self.exceptions.push(match event.start.date_only {
+        true => ScheduleException {
+          start_date: start.date(),
+          end_date: Some((end - Duration::days(1)).date()).filter(|d| *d > start.date()),
+          kind: ExceptionKind::Open,
+          note: event.summary.clone(),
+        },
+        false => ScheduleException {
+          start_date: start.date(),
+          end_date: None,
+          kind: ExceptionKind::Add {
+            periods: vec![DayPeriod {
+              start: time_of_day(start),
+              end: time_of_day(end),
+              note: event.summary.clone(),
+            }],
+          },
+          note: event.summary.clone(),
+        },
+      });
+    }
+
+    Ok(())
+  }
+}
+
+/// Converts an iCalendar file into open periods and dated exceptions, with
+/// times in the schedule's timezone.
+pub fn import(text: &str, zone: Zone) -> Import {
+  let (events, warnings) = parse_events(text);
+  let mut import = Import {
+    warnings,
+    ..Import::default()
+  };
+
+  // Weekly occurrences removed by EXDATEs, by date.
+  let mut excluded: BTreeMap<NaiveDate, Vec<OpenPeriod>> = BTreeMap::new();
+  for event in &events {
+    let result = match &event.rule {
+      _ if event.length() <= Duration::zero() => Err(anyhow!("Has no length")),
+      // All-day events that repeat forever become whole days in the weekly
+      // schedule, like any other endless event.
+      Some(rule) if rule.is_endless() => import.add_endless(&mut excluded, event, zone),
+      _ => import.add_dated(event, zone),
+    };
+    if let Err(e) = result {
+      import.warnings.push(format!("Skipped {}: {}", event.summary, e));
+    }
+  }
+
+  // An excluded day keeps the rest of its usual periods.
+  for (date, periods) in excluded {
+    let kept: Option<Vec<DayPeriod>> = import
+      .open_periods
+      .iter()
+      .filter(|p| p.start.weekday == weekday(date) && !periods.contains(p))
+      .map(day_period)
+      .collect();
+    match kept {
+      Some(kept) => import.exceptions.push(ScheduleException {
+        start_date: date,
+        end_date: None,
+        kind: ExceptionKind::Replace { periods: kept },
+        note: "Excluded in calendar".to_owned(),
+      }),
+      None => import
+        .warnings
+        .push(format!("Can't exclude {}, since it has periods lasting a day or more", date)),
+    }
+  }
+  import.exceptions.sort_by_key(|e| e.start_date);
+
+  import
+}
+
+/// The occurrences of an event starting on dates in the window that overlap
+/// the time between from and to.
+fn event_occurrences(
+  event: &Event,
+  zone: Zone,
+  window: (NaiveDate, NaiveDate),
+  from: DateTime<Local>,
+  to: DateTime<Local>,
+) -> Result<Vec<Occurrence>> {
+  let length = event.length();
+  if length <= Duration::zero() || length > Duration::weeks(1) {
+    bail!("Lasts {} minutes", length.num_minutes());
+  }
+
+  let mut occurrences = Vec::new();
+  for time in event.occurrences(Some(window), zone)? {
+    let end = EventTime {
+      naive: time.naive + length,
+      ..time
+    };
+    let (start, end) = (time.at(zone)?, end.at(zone)?);
+    if end > from && start < to {
+      occurrences.push(Occurrence {
+        start,
+        end,
+        period: OpenPeriod {
+          start: instant(zone.naive(start)),
+          end: instant(zone.naive(end)),
+          note: event.summary.clone(),
+        },
+      });
+    }
+  }
+
+  Ok(occurrences)
+}
+
+/// The occurrences of events with the given title (ignoring case) that
+/// overlap the time between from and to, for following a calendar feed.
+pub fn feed_occurrences(
+  text: &str,
+  title: &str,
+  zone: Zone,
+  from: DateTime<Local>,
+  to: DateTime<Local>,
+) -> (Vec<Occurrence>, Vec<String>) {
+  let (events, mut warnings) = parse_events(text);
+
+  // No event lasts longer than a week, so anything overlapping from must have
+  // started at most a week before.
+  let window = (zone.date(from - Duration::weeks(1)), zone.date(to));
+  let mut occurrences = Vec::new();
+  for event in events.iter().filter(|e| e.summary.trim().eq_ignore_ascii_case(title)) {
+    match event_occurrences(event, zone, window, from, to) {
+      Ok(found) => occurrences.extend(found),
+      Err(e) => warnings.push(format!("Skipped {}: {}", event.summary, e)),
+    }
+  }
+  occurrences.sort_by_key(|o| o.start);
+
+  (occurrences, warnings)
+}
+
+/// How an imported schedule differs from the current one.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ScheduleDiff {
+  pub added: Vec<OpenPeriod>,
+  pub removed: Vec<OpenPeriod>,
+  pub unchanged: Vec<OpenPeriod>,
+}
+
+/// Compares periods by their times, ignoring notes.
+pub fn diff(current: &[OpenPeriod], imported: &[OpenPeriod]) -> ScheduleDiff {
+  let same = |a: &OpenPeriod, b: &OpenPeriod| a.start == b.start && a.end == b.end;
+  let contains = |periods: &[OpenPeriod], period: &OpenPeriod| periods.iter().any(|p| same(p, period));
+
+  ScheduleDiff {
+    added: imported.iter().filter(|p| !contains(current, p)).cloned().collect(),
+    removed: current.iter().filter(|p| !contains(imported, p)).cloned().collect(),
+    unchanged: imported.iter().filter(|p| contains(current, p)).cloned().collect(),
+  }
+}
+
+/// Splits a line into folded lines of at most MAX_LINE_LENGTH bytes.
+fn fold(line: &str) -> String {
+  let mut result = String::new();
+  let mut length = 0;
+  for c in line.chars() {
+    if length + c.len_utf8() > MAX_LINE_LENGTH {
+      result.push_str("\r\n ");
+      length = 1;
+    }
+    result.push(c);
+    length += c.len_utf8();
+  }
+
+  result
+}
+
+/// The UID stays the same as long as the occurrence's times do, so calendar
+/// apps update their copy of the event rather than adding another.
+fn occurrence_event(name: &str, occurrence: &Occurrence) -> Vec<String> {
+  let utc = |time: DateTime<Local>| time.with_timezone(&Utc).format("%Y%m%dT%H%M%SZ").to_string();
+  let (start, end) = (utc(occurrence.start), utc(occurrence.end));
+
+  vec![
+    "BEGIN:VEVENT".to_owned(),
+    format!("UID:{}-{}-{}@heimdall", start, end, name),
+    // Fixed, so exporting the same occurrences gives the same file.
+    "DTSTAMP:20210103T000000Z".to_owned(),
+    format!("DTSTART:{}", start),
+    format!("DTEND:{}", end),
+    format!("SUMMARY:{}", escape(&occurrence.period.note)),
+    "END:VEVENT".to_owned(),
+  ]
+}
+
+fn calendar(name: &str, events: impl Iterator<Item = Vec<String>>) -> String {
+  let mut lines = vec![
+    "BEGIN:VCALENDAR".to_owned(),
+    "VERSION:2.0".to_owned(),
+    "PRODID:-//heimdall//schedule//EN".to_owned(),
+    format!("X-WR-CALNAME:{}", escape(name)),
+  ];
+  lines.extend(events.flatten());
+  lines.push("END:VCALENDAR".to_owned());
+
+  lines.iter().map(|l| fold(l) + "\r\n").collect()
+}
+
+/// Converts open times into an iCalendar file with an event for each, so it
+/// shows everything that changes the schedule from week to week, like
+/// blackouts, templates and exceptions.
+pub fn export_occurrences(name: &str, occurrences: &[Occurrence]) -> String {
+  calendar(name, occurrences.iter().map(|o| occurrence_event(name, o)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono_tz::America::New_York;
+
+  fn period(start: (u8, u8, u8), end: (u8, u8, u8), note: &str) -> OpenPeriod {
+    OpenPeriod {
+      start: Instant {
+        weekday: start.0,
+        hour: start.1,
+        minute: start.2,
+      },
+      end: Instant {
+        weekday: end.0,
+        hour: end.1,
+        minute: end.2,
+      },
+      note: note.to_owned(),
+    }
+  }
+
+  fn day_period(start: (u8, u8), end: (u8, u8), note: &str) -> DayPeriod {
+    DayPeriod {
+      start: TimeOfDay {
+        hour: start.0,
+        minute: start.1,
+      },
+      end: TimeOfDay {
+        hour: end.0,
+        minute: end.1,
+      },
+      note: note.to_owned(),
+    }
+  }
+
+  static CALENDAR: &str = "BEGIN:VCALENDAR\r
+VERSION:2.0\r
+BEGIN:VTIMEZONE\r
+TZID:America/New_York\r
+BEGIN:STANDARD\r
+DTSTART:19701101T020000\r
+TZOFFSETFROM:-0400\r
+TZOFFSETTO:-0500\r
+END:STANDARD\r
+END:VTIMEZONE\r
+BEGIN:VEVENT\r
+UID:1\r
+SUMMARY:After school\\, weekdays\r
+DTSTART;TZID=America/New_York:20210301T153000\r
+DTEND;TZID=America/New_York:20210301T1\r
+ 70000\r
+RRULE:FREQ=WEEKLY;BYDAY=MO,WE\r
+EXDATE;TZID=America/New_York:20210310T153000\r
+BEGIN:VALARM\r
+TRIGGER:-PT5M\r
+END:VALARM\r
+END:VEVENT\r
+BEGIN:VEVENT\r
+UID:2\r
+SUMMARY:Movie night\r
+DTSTART:20210306T010000Z\r
+DURATION:PT3H\r
+RRULE:FREQ=WEEKLY\r
+END:VEVENT\r
+BEGIN:VEVENT\r
+UID:3\r
+SUMMARY:Birthday\r
+DTSTART;VALUE=DATE:20210320\r
+DTEND;VALUE=DATE:20210322\r
+END:VEVENT\r
+BEGIN:VEVENT\r
+UID:4\r
+SUMMARY:Revision\r
+DTSTART;TZID=America/New_York:20210601T100000\r
+DTEND;TZID=America/New_York:20210601T120000\r
+RRULE:FREQ=DAILY;COUNT=3\r
+EXDATE;TZID=America/New_York:20210602T100000\r
+END:VEVENT\r
+BEGIN:VEVENT\r
+UID:5\r
+SUMMARY:Monthly\r
+DTSTART:20210601T100000\r
+DURATION:PT1H\r
+RRULE:FREQ=MONTHLY\r
+END:VEVENT\r
+END:VCALENDAR\r
+";
+
+  #[test]
+  fn test_import() {
+    let import = import(CALENDAR, Zone::Named(New_York));
+
+    assert_eq!(
+      vec![
+        period((1, 15, 30), (1, 17, 0), "After school, weekdays"),
+        period((3, 15, 30), (3, 17, 0), "After school, weekdays"),
+        // 01:00 UTC on Saturday is 20:00 on Friday in New York.
+        period((5, 20, 0), (5, 23, 0), "Movie night"),
+      ],
+      import.open_periods
+    );
+
+    let date = |m, d| NaiveDate::from_ymd(2021, m, d);
+    assert_eq!(
+      vec![
+        ScheduleException {
+          start_date: date(3, 10),
+          end_date: None,
+          kind: ExceptionKind::Replace { periods: vec![] },
+          note: "Excluded in calendar".to_owned(),
+        },
+        ScheduleException {
+          start_date: date(3, 20),
+          end_date: Some(date(3, 21)),
+          kind: ExceptionKind::Open,
+          note: "Birthday".to_owned(),
+        },
+        ScheduleException {
+          start_date: date(6, 1),
+          end_date: None,
+          kind: ExceptionKind::Add {
+            periods: vec![day_period((10, 0), (12, 0), "Revision")],
+          },
+          note: "Revision".to_owned(),
+        },
+        ScheduleException {
+          start_date: date(6, 3),
+          end_date: None,
+          kind: ExceptionKind::Add {
+            periods: vec![day_period((10, 0), (12, 0), "Revision")],
+          },
+          note: "Revision".to_owned(),
+        },
+      ],
+      import.exceptions
+    );
+
+    assert_eq!(vec!["Skipped Monthly: Unsupported FREQ=MONTHLY"], import.warnings);
+  }
+
+  #[test]
+  fn test_floating_exdate() {
+    // The EXDATE is in the schedule's timezone, not the computer's.
+    let calendar = "BEGIN:VCALENDAR\r
+BEGIN:VEVENT\r
+SUMMARY:Tennis\r
+DTSTART;TZID=America/New_York:20210301T153000\r
+DURATION:PT1H\r
+RRULE:FREQ=WEEKLY;COUNT=3\r
+EXDATE:20210308T153000\r
+END:VEVENT\r
+END:VCALENDAR\r
+";
+    let import = import(calendar, Zone::Named(New_York));
+    assert_eq!(Vec::<String>::new(), import.warnings);
+    assert_eq!(
+      vec![NaiveDate::from_ymd(2021, 3, 1), NaiveDate::from_ymd(2021, 3, 15)],
+      import.exceptions.iter().map(|e| e.start_date).collect::<Vec<_>>()
+    );
+  }
+
+  #[test]
+  fn test_endless_all_day() {
+    let calendar = "BEGIN:VCALENDAR\r
+BEGIN:VEVENT\r
+SUMMARY:Saturday\r
+DTSTART;VALUE=DATE:20210306\r
+RRULE:FREQ=WEEKLY\r
+END:VEVENT\r
+END:VCALENDAR\r
+";
+    let import = import(calendar, Zone::Named(New_York));
+    assert_eq!(Vec::<String>::new(), import.warnings);
+    assert_eq!(vec![period((6, 0, 0), (0, 0, 0), "Saturday")], import.open_periods);
+    assert!(import.exceptions.is_empty());
+  }
+
+  #[test]
+  fn test_never_repeats() {
+    // Every 7 days from a Sunday is never a Monday.
+    let calendar = "BEGIN:VCALENDAR\r
+BEGIN:VEVENT\r
+SUMMARY:Screen time\r
+DTSTART:20210307T150000\r
+DURATION:PT1H\r
+RRULE:FREQ=DAILY;INTERVAL=7;BYDAY=MO;UNTIL=20210401T000000Z\r
+END:VEVENT\r
+END:VCALENDAR\r
+";
+    let warning = "Skipped Screen time: Repeats every 7 days but never on one of its BYDAY days";
+    assert_eq!(vec![warning], import(calendar, Zone::Local).warnings);
+
+    let from = Local.ymd(2021, 3, 1).and_hms(0, 0, 0);
+    let to = from + Duration::weeks(2);
+    let (occurrences, warnings) = feed_occurrences(calendar, "screen time", Zone::Local, from, to);
+    assert!(occurrences.is_empty());
+    assert_eq!(vec![warning], warnings);
+  }
+
+  #[test]
+  fn test_parse_duration() -> Result<()> {
+    assert_eq!(Duration::minutes(90), parse_duration("PT1H30M")?);
+    assert_eq!(Duration::days(8), parse_duration("P1W1D")?);
+    assert_eq!(-Duration::minutes(5), parse_duration("-PT5M")?);
+    assert!(parse_duration("1H").is_err());
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_diff() {
+    let current = vec![period((1, 15, 0), (1, 17, 0), "Old"), period((2, 15, 0), (2, 17, 0), "")];
+    let imported = vec![period((1, 15, 0), (1, 17, 0), "New"), period((3, 15, 0), (3, 17, 0), "")];
+
+    let diff = diff(&current, &imported);
+    assert_eq!(vec![period((3, 15, 0), (3, 17, 0), "")], diff.added);
+    assert_eq!(vec![period((2, 15, 0), (2, 17, 0), "")], diff.removed);
+    assert_eq!(vec![period((1, 15, 0), (1, 17, 0), "New")], diff.unchanged);
+  }
+
+  #[test]
+  fn test_round_trip() {
+    let note = "A long note that goes on and on so that the summary line has to be folded";
+    let start = Utc.ymd(2021, 3, 1).and_hms(15, 30, 0);
+    let occurrence = Occurrence {
+      start: start.with_timezone(&Local),
+      end: (start + Duration::minutes(90)).with_timezone(&Local),
+      period: period((1, 15, 30), (1, 17, 0), note),
+    };
+
+    let text = export_occurrences("alice", &[occurrence]);
+    assert!(text.lines().all(|l| l.len() <= MAX_LINE_LENGTH));
+
+    // 15:30 UTC is 10:30 in New York.
+    let import = import(&text, Zone::Named(New_York));
+    assert_eq!(Vec::<String>::new(), import.warnings);
+    assert!(import.open_periods.is_empty());
+    assert_eq!(
+      vec![ScheduleException {
+        start_date: NaiveDate::from_ymd(2021, 3, 1),
+        end_date: None,
+        kind: ExceptionKind::Add {
+          periods: vec![day_period((10, 30), (12, 0), note)],
+        },
+        note: note.to_owned(),
+      }],
+      import.exceptions
+    );
+  }
+
+  #[test]
+  fn test_export_occurrences() {
+    let occurrence = Occurrence {
+      start: Utc.ymd(2021, 3, 1).and_hms(15, 30, 0).with_timezone(&Local),
+      end: Utc.ymd(2021, 3, 1).and_hms(17, 0, 0).with_timezone(&Local),
+      period: period((1, 15, 30), (1, 17, 0), "Homework, then games"),
+    };
+
+    let text = export_occurrences("alice", &[occurrence]);
+    assert!(text.contains("UID:20210301T153000Z-20210301T170000Z-alice@heimdall\r\n"));
+    assert!(text.contains("DTSTART:20210301T153000Z\r\nDTEND:20210301T170000Z\r\n"));
+    assert!(text.contains("SUMMARY:Homework\\, then games\r\n"));
+    assert!(!text.contains("RRULE"));
+  }
+}
@@ -0,0 +1,270 @@
+//! A per-user bank of extra time. Parents grant credits (bonus time, or unused
+//! quota rolled over from yesterday), and the daemon debits them while the user
+//! is unlocked outside their schedule.
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+/// How long to keep transactions that no longer affect the balance.
+const LEDGER_HISTORY_DAYS: i64 = 60;
+
+/// The author of transactions made by the daemon itself.
+pub const DAEMON_AUTHOR: &str = "heimdall";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TransactionKind {
+  /// Time added to the bank. `remaining_seconds` is what hasn't been spent.
+  Credit {
+    remaining_seconds: u64,
+    #[serde(default)]
+    expires: Option<DateTime<Local>>,
+    #[serde(default)]
+    revoked: bool,
+  },
+  /// Time spent from the bank.
+  Debit,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Transaction {
+  pub id: u64,
+  pub time: DateTime<Local>,
+  pub seconds: u64,
+  pub reason: String,
+  pub author: String,
+  #[serde(flatten)]
+  pub kind: TransactionKind,
+}
+
+impl Transaction {
+  /// The unspent seconds of a credit that can still be used at `now`.
+  pub fn available(&self, now: DateTime<Local>) -> u64 {
+    match &self.kind {
+      TransactionKind::Credit {
+        remaining_seconds,
+        expires,
+        revoked: false,
+      } if expires.map_or(true, |expires| now < expires) => *remaining_seconds,
+      _ => 0,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Ledger {
+  #[serde(default)]
+  pub transactions: Vec<Transaction>,
+  /// The last id handed out. Ids are never reused, even once their
+  /// transactions have been pruned.
+  #[serde(default)]
+  last_id: u64,
+}
+
+impl Ledger {
+  fn next_id(&mut self) -> u64 {
+    // Ledgers saved before there was a counter only have their transactions.
+    let last = self.transactions.iter().map(|t| t.id).max().unwrap_or(0);
+    self.last_id = self.last_id.max(last) + 1;
+    self.last_id
+  }
+
+  /// The seconds that can be spent at `now`.
+  pub fn balance(&self, now: DateTime<Local>) -> u64 {
+    self.transactions.iter().map(|t| t.available(now)).sum()
+  }
+
+  pub fn grant(
+    &mut self,
+    now: DateTime<Local>,
+    seconds: u64,
+    reason: &str,
+    author: &str,
+    expires: Option<DateTime<Local>>,
+  ) -> &Transaction {
+    let transaction = Transaction {
+      id: self.next_id(),
+      time: now,
+      seconds,
+      reason: reason.to_owned(),
+      author: author.to_owned(),
+      kind: TransactionKind::Credit {
+        remaining_seconds: seconds,
+        expires,
+        revoked: false,
+      },
+    };
+    self.transactions.push(transaction);
+    self.transactions.last().unwrap()
+  }
+
+  /// Takes back whatever is left of a credit. Returns false if there's no
+  /// such transaction.
+  pub fn revoke(&mut self, id: u64) -> Result<bool> {
+    match self.transactions.iter_mut().find(|t| t.id == id) {
+      Some(Transaction {
+        kind: TransactionKind::Credit { revoked, .. },
+        ..
+      }) => {
+        *revoked = true;
+        Ok(true)
+      }
+      Some(_) => bail!("Transaction {} is not a credit", id),
+      None => Ok(false),
+    }
+  }
+
+  /// Spends up to `seconds` from the credits that expire soonest, returning
+  /// how much was actually spent. If `session` is the id of an earlier debit,
+  /// it's extended rather than adding a new transaction every run.
+  pub fn spend(&mut self, now: DateTime<Local>, seconds: u64, session: Option<u64>) -> (u64, Option<u64>) {
+    let mut credits: Vec<&mut Transaction> = self
+      .transactions
+      .iter_mut()
+      .filter(|t| t.available(now) > 0)
+      .collect();
+    credits.sort_by_key(|t| match t.kind {
+      TransactionKind::Credit { expires, .. } => (expires.is_none(), expires, t.time),
+      TransactionKind::Debit => unreachable!(),
+    });
+
+    let mut spent = 0;
+    for credit in credits {
+      if let TransactionKind::Credit { remaining_seconds, .. } = &mut credit.kind {
+        let amount = (*remaining_seconds).min(seconds - spent);
+        *remaining_seconds -= amount;
+        spent += amount;
+      }
+      if spent == seconds {
+        break;
+      }
+    }
+
+    if spent == 0 {
+      return (0, session);
+    }
+
+    let debit = session.and_then(|id| {
+      self
+        .transactions
+        .iter_mut()
+        .find(|t| t.id == id && t.kind == TransactionKind::Debit)
+    });
+    if let Some(debit) = debit {
+      debit.seconds += spent;
+      return (spent, session);
+    }
+
+    let id = self.next_id();
+    self.transactions.push(Transaction {
+      id,
+      time: now,
+      seconds: spent,
+      reason: "Used outside schedule".to_owned(),
+      author: DAEMON_AUTHOR.to_owned(),
+      kind: TransactionKind::Debit,
+    });
+    (spent, Some(id))
+  }
+
+  /// Forgets old transactions that no longer count towards the balance.
+  pub fn prune(&mut self, now: DateTime<Local>) {
+    let oldest = now - chrono::Duration::days(LEDGER_HISTORY_DAYS);
+    self.transactions.retain(|t| t.time >= oldest || t.available(now) > 0);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono::TimeZone;
+
+  fn at(hour: u32) -> DateTime<Local> {
+    Local.ymd(2021, 3, 1).and_hms(hour, 0, 0)
+  }
+
+  #[test]
+  fn test_grant_and_revoke() -> Result<()> {
+    let mut ledger = Ledger::default();
+    let chores = ledger.grant(at(9), 1800, "Chores", "mum", None).id;
+    ledger.grant(at(9), 600, "Homework", "dad", None);
+    assert_eq!(2400, ledger.balance(at(10)));
+
+    assert!(ledger.revoke(chores)?);
+    assert_eq!(600, ledger.balance(at(10)));
+    assert!(!ledger.revoke(42)?);
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_ids_not_reused() {
+    let mut ledger = Ledger::default();
+    ledger.grant(at(9), 60, "Chores", "mum", Some(at(10)));
+    let old = ledger.grant(at(9), 60, "Chores", "mum", Some(at(10))).id;
+    ledger.prune(at(10) + chrono::Duration::days(LEDGER_HISTORY_DAYS + 1));
+    assert!(ledger.transactions.is_empty());
+    assert!(ledger.grant(at(11), 60, "Homework", "dad", None).id > old);
+
+    // Nor after a round trip.
+    let mut ledger: Ledger = serde_json::from_str(&serde_json::to_string(&ledger).unwrap()).unwrap();
+    ledger.prune(at(11) + chrono::Duration::days(LEDGER_HISTORY_DAYS + 1));
+    assert!(ledger.grant(at(12), 60, "Homework", "dad", None).id > old + 1);
+  }
+
+  #[test]
+  fn test_expiry() {
+    let mut ledger = Ledger::default();
+    ledger.grant(at(9), 1800, "Chores", "mum", Some(at(12)));
+    assert_eq!(1800, ledger.balance(at(11)));
+    assert_eq!(0, ledger.balance(at(12)));
+  }
+
+  #[test]
+  fn test_spend_soonest_expiring_first() -> Result<()> {
+    let mut ledger = Ledger::default();
+    let forever = ledger.grant(at(8), 600, "Forever", "mum", None).id;
+    let today = ledger.grant(at(9), 60, "Today", "mum", Some(at(20))).id;
+
+    let (spent, session) = ledger.spend(at(10), 90, None);
+    assert_eq!(90, spent);
+    assert_eq!(570, ledger.balance(at(10)));
+
+    // Revoking the used up credit doesn't change anything.
+    ledger.revoke(today)?;
+    assert_eq!(570, ledger.balance(at(10)));
+
+    // The same session keeps a single debit.
+    let (spent, session) = ledger.spend(at(10), 600, session);
+    assert_eq!(570, spent);
+    assert_eq!(0, ledger.balance(at(10)));
+    let debit = ledger.transactions.iter().find(|t| Some(t.id) == session).unwrap();
+    assert_eq!(660, debit.seconds);
+    assert_eq!(3, ledger.transactions.len());
+
+    assert!(ledger.revoke(session.unwrap()).is_err());
+    assert_eq!((0, None), ledger.spend(at(10), 15, None));
+    assert!(ledger.transactions.iter().any(|t| t.id == forever));
+
+    // A session that isn't a debit starts a new one rather than adding to it.
+    ledger.grant(at(11), 60, "Chores", "mum", None);
+    let (_, session) = ledger.spend(at(11), 30, Some(forever));
+    assert_ne!(Some(forever), session);
+    assert_eq!(600, ledger.transactions.iter().find(|t| t.id == forever).unwrap().seconds);
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_prune() {
+    let mut ledger = Ledger::default();
+    let old = at(9) - chrono::Duration::days(LEDGER_HISTORY_DAYS + 1);
+    ledger.grant(old, 60, "Unspent", "mum", None);
+    ledger.grant(old, 60, "Expired", "mum", Some(old));
+
+    ledger.prune(at(9));
+    assert_eq!(1, ledger.transactions.len());
+    assert_eq!("Unspent", ledger.transactions[0].reason);
+  }
+}
@@ -1,4 +1,4 @@
-#![feature(proc_macro_hygiene, decl_macro, let_chains, backtrace)]
+#![feature(proc_macro_hygiene, decl_macro, let_chains, backtrace, peer_credentials_unix_socket)]
 
 #[macro_use]
 extern crate rocket;
@@ -7,16 +7,37 @@ use anyhow::Result;
 use env_logger::Env;
 use rocket_contrib::serve::StaticFiles;
 
+mod agent;
 mod api;
+mod cli;
 mod config;
 mod constants;
+mod cron;
+mod dsl;
+mod feed;
+mod ical;
+mod ledger;
+mod meeting;
+mod notify;
 mod os;
+mod protocol;
+mod rules;
 mod runloop;
+mod schedule;
 mod scratch;
+mod state;
+mod validate;
+mod verdict;
 
-use log::info;
+use log::{error, info};
+use std::sync::{Arc, Mutex};
 
 fn main() -> Result<()> {
+  let args: Vec<String> = std::env::args().skip(1).collect();
+  if !args.is_empty() {
+    return cli::run(&args);
+  }
+
   env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
 
   // let mut bar = sysbar::Sysbar::new("Hello");
@@ -32,11 +53,31 @@ fn main() -> Result<()> {
     "/usr/local/etc/heimdall/static"
   };
 
-  let _scheduler = runloop::start();
+  let agents = match agent::AgentServer::start(&protocol::get_socket_path()) {
+    Ok(agents) => Some(Arc::new(agents)),
+    Err(e) => {
+      error!("Failed to start agent server: {}", e);
+      None
+    }
+  };
+
+  let state = match state::load() {
+    Ok(state) => state,
+    Err(e) => {
+      error!("Failed to load state, starting afresh: {}", e);
+      state::State::default()
+    }
+  };
+  let state: state::SharedState = Arc::new(Mutex::new(state));
+
+  let feeds = feed::Feeds::start();
+  let _scheduler = runloop::start(agents, Arc::clone(&state), feeds.clone());
 
   println!("HELLO");
   // bar.set_title("Starting Rocket");
   rocket::ignite()
+    .manage(state)
+    .manage(feeds)
     .mount("/api/", api::get_routes())
     .mount("/", StaticFiles::from(static_path))
     .launch();
@@ -0,0 +1,81 @@
+use crate::config::{Meeting, MeetingDeferral};
+use crate::os;
+use log::error;
+
+fn is_running(processes: &[String], pattern: &str) -> bool {
+  processes.iter().any(|p| p.contains(pattern))
+}
+
+/// Returns the first meeting with a call in progress, given the command lines
+/// of the running processes.
+fn find_active_meeting<'a>(processes: &[String], meetings: &'a [Meeting]) -> Option<&'a Meeting> {
+  meetings.iter().find(|m| {
+    is_running(processes, &m.process)
+      && m
+        .call_process
+        .as_ref()
+        .map_or(true, |call| is_running(processes, call))
+  })
+}
+
+/// Returns the meeting the user is in a call with, if any. If their processes
+/// can't be listed they're taken not to be in one, rather than holding up
+/// everyone else's run.
+pub fn get_active_meeting<'a>(username: &str, deferral: &'a MeetingDeferral) -> Option<&'a Meeting> {
+  match os::get_user_processes(username) {
+    Ok(processes) => find_active_meeting(&processes, &deferral.meetings),
+    Err(e) => {
+      error!("Couldn't check {} for meetings: {}", username, e);
+      None
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn meetings() -> Vec<Meeting> {
+    vec![
+      Meeting {
+        name: "Zoom".to_owned(),
+        process: "zoom.us".to_owned(),
+        call_process: Some("CptHost".to_owned()),
+      },
+      Meeting {
+        name: "Teams".to_owned(),
+        process: "Microsoft Teams".to_owned(),
+        call_process: None,
+      },
+    ]
+  }
+
+  fn processes(lines: &[&str]) -> Vec<String> {
+    lines.iter().map(|l| l.to_string()).collect()
+  }
+
+  #[test]
+  fn test_find_active_meeting() {
+    let meetings = meetings();
+    let zoom_open = "/Applications/zoom.us.app/Contents/MacOS/zoom.us";
+    let zoom_call = "/Applications/zoom.us.app/Contents/Frameworks/cpthost.app/Contents/MacOS/CptHost";
+
+    assert!(find_active_meeting(&processes(&["/bin/zsh"]), &meetings).is_none());
+    assert!(find_active_meeting(&processes(&[zoom_open]), &meetings).is_none());
+    assert_eq!(
+      "Zoom",
+      find_active_meeting(&processes(&[zoom_open, zoom_call]), &meetings)
+        .unwrap()
+        .name
+    );
+    assert_eq!(
+      "Teams",
+      find_active_meeting(
+        &processes(&["/Applications/Microsoft Teams.app/Contents/MacOS/Teams"]),
+        &meetings
+      )
+      .unwrap()
+      .name
+    );
+  }
+}
@@ -0,0 +1,237 @@
+use crate::agent::AgentServer;
+use crate::config::{Channel, EventKind, NotificationRoute};
+use crate::os;
+use crate::protocol::DaemonMessage;
+use anyhow::Result;
+use log::{error, info};
+use serde::Serialize;
+use std::time::Duration;
+
+/// Something that happened to a user that somebody might want to hear about.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+  WarningUpcomingLock { user: String, minutes: u32 },
+  Locked { user: String },
+  Unlocked { user: String },
+  EnforcementFailed { user: String, error: String },
+  LockDeferred { user: String, minutes: u32, reason: String },
+}
+
+impl Event {
+  pub fn kind(&self) -> EventKind {
+    match self {
+      Event::WarningUpcomingLock { .. } => EventKind::WarningUpcomingLock,
+      Event::Locked { .. } => EventKind::Locked,
+      Event::Unlocked { .. } => EventKind::Unlocked,
+      Event::EnforcementFailed { .. } => EventKind::EnforcementFailed,
+      Event::LockDeferred { .. } => EventKind::LockDeferred,
+    }
+  }
+
+  pub fn user(&self) -> &str {
+    match self {
+      Event::WarningUpcomingLock { user, .. }
+      | Event::Locked { user }
+      | Event::Unlocked { user }
+      | Event::EnforcementFailed { user, .. }
+      | Event::LockDeferred { user, .. } => user,
+    }
+  }
+
+  /// Critical events should interrupt whatever the user is doing.
+  pub fn is_critical(&self) -> bool {
+    matches!(self, Event::WarningUpcomingLock { minutes, .. } if *minutes <= 1)
+  }
+
+  pub fn title(&self) -> String {
+    match self {
+      Event::WarningUpcomingLock { .. } if self.is_critical() => "Your time is up!".to_owned(),
+      Event::EnforcementFailed { .. } => "Heimdall problem".to_owned(),
+      _ => "Heimdall".to_owned(),
+    }
+  }
+
+  pub fn message(&self) -> String {
+    match self {
+      Event::WarningUpcomingLock { user, minutes: 1 } => {
+        format!("{}, you will be logged out in 1 minute", user)
+      }
+      Event::WarningUpcomingLock { user, minutes } => {
+        format!("{}, you will be logged out in {} minutes", user, minutes)
+      }
+      Event::Locked { user } => format!("{} is currently locked out", user),
+      Event::Unlocked { user } => format!("{} is now unlocked", user),
+      Event::EnforcementFailed { user, error } => {
+        format!("Failed to update the lock for {}: {}", user, error)
+      }
+      Event::LockDeferred {
+        user,
+        minutes,
+        reason,
+      } => format!("Locking {} postponed by {} minutes: {}", user, minutes, reason),
+    }
+  }
+}
+
+/// A way of getting an event in front of a person.
+pub trait Notifier {
+  fn notify(&self, event: &Event) -> Result<()>;
+}
+
+/// Desktop notifications for the currently logged in user.
+pub struct DesktopNotifier;
+
+impl Notifier for DesktopNotifier {
+  fn notify(&self, event: &Event) -> Result<()> {
+    if event.is_critical() {
+      os::show_alert(&event.title(), &event.message())
+    } else {
+      os::show_notification(&event.title(), &event.message())
+    }
+  }
+}
+
+/// Shows the event through the agent running in the user's session, falling
+/// back to a desktop notification if the user has no agent.
+pub struct AgentNotifier<'a> {
+  pub agents: Option<&'a AgentServer>,
+}
+
+impl<'a> Notifier for AgentNotifier<'a> {
+  fn notify(&self, event: &Event) -> Result<()> {
+    let message = DaemonMessage::Warning {
+      title: event.title(),
+      message: event.message(),
+      critical: event.is_critical(),
+    };
+
+    match self.agents {
+      Some(agents) if agents.is_alive(event.user()) && agents.send(event.user(), &message)? => Ok(()),
+      _ => DesktopNotifier.notify(event),
+    }
+  }
+}
+
+/// Reads the event out loud.
+pub struct SpeechNotifier;
+
+impl Notifier for SpeechNotifier {
+  fn notify(&self, event: &Event) -> Result<()> {
+    os::say(&event.message())
+  }
+}
+
+/// Posts the event as JSON to a URL.
+pub struct WebhookNotifier {
+  pub url: String,
+}
+
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+  event: &'a Event,
+  title: String,
+  message: String,
+}
+
+impl Notifier for WebhookNotifier {
+  fn notify(&self, event: &Event) -> Result<()> {
+    let payload = WebhookPayload {
+      event,
+      title: event.title(),
+      message: event.message(),
+    };
+
+    ureq::post(&self.url)
+      .timeout(Duration::from_secs(10))
+      .set("Content-Type", "application/json")
+      .send_string(&serde_json::to_string(&payload)?)?;
+
+    Ok(())
+  }
+}
+
+pub fn notifier_for<'a>(channel: &Channel, agents: Option<&'a AgentServer>) -> Box<dyn Notifier + 'a> {
+  match channel {
+    Channel::Agent => Box::new(AgentNotifier { agents }),
+    Channel::Desktop => Box::new(DesktopNotifier),
+    Channel::Speech => Box::new(SpeechNotifier),
+    Channel::Webhook { url } => Box::new(WebhookNotifier { url: url.to_owned() }),
+  }
+}
+
+fn is_routed(route: &NotificationRoute, event: &Event) -> bool {
+  route.events.is_empty() || route.events.contains(&event.kind())
+}
+
+/// Sends the event to every channel routed to receive it. Failures are logged
+/// rather than returned, so one broken channel doesn't stop the others.
+pub fn dispatch(routes: &[NotificationRoute], event: &Event, agents: Option<&AgentServer>) {
+  info!("Notify {}: {}", event.user(), event.message());
+  for route in routes.iter().filter(|r| is_routed(r, event)) {
+    if let Err(e) = notifier_for(&route.channel, agents).notify(event) {
+      error!("Failed to notify {:?} of {:?}: {}", route.channel, event.kind(), e);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_routing() {
+    let warning = Event::WarningUpcomingLock {
+      user: "test".to_owned(),
+      minutes: 5,
+    };
+    let locked = Event::Locked {
+      user: "test".to_owned(),
+    };
+
+    let all = NotificationRoute {
+      channel: Channel::Desktop,
+      events: vec![],
+    };
+    assert!(is_routed(&all, &warning));
+    assert!(is_routed(&all, &locked));
+
+    let locks_only = NotificationRoute {
+      channel: Channel::Speech,
+      events: vec![EventKind::Locked, EventKind::Unlocked],
+    };
+    assert!(!is_routed(&locks_only, &warning));
+    assert!(is_routed(&locks_only, &locked));
+  }
+
+  #[test]
+  fn test_critical() {
+    let warning = |minutes| Event::WarningUpcomingLock {
+      user: "test".to_owned(),
+      minutes,
+    };
+
+    assert!(!warning(5).is_critical());
+    assert!(warning(1).is_critical());
+    assert_eq!("test, you will be logged out in 1 minute", warning(1).message());
+  }
+
+  #[test]
+  fn test_webhook_payload() -> Result<()> {
+    let event = Event::Locked {
+      user: "test".to_owned(),
+    };
+    let payload = WebhookPayload {
+      event: &event,
+      title: event.title(),
+      message: event.message(),
+    };
+
+    assert_eq!(
+      r#"{"event":{"type":"locked","user":"test"},"title":"Heimdall","message":"test is currently locked out"}"#,
+      serde_json::to_string(&payload)?
+    );
+
+    Ok(())
+  }
+}
@@ -159,11 +159,20 @@ pub fn change_password(
   Ok(())
 }
 
+/// The numeric id of the given user.
+pub fn get_user_id(username: &str) -> Result<u32> {
+  let output = Command::new("id").args(&["-u", username]).output()?;
+  if !output.status.success() {
+    return Err(anyhow!("No such user {}", username));
+  }
+
+  Ok(str::from_utf8(&output.stdout)?.trim().parse()?)
+}
+
 /// Logs the given user out of this computer immediately.
 pub fn boot_user_out(username: &str) -> Result<()> {
   // First get the uid of this user.
-  let output = Command::new("id").args(&["-u", username]).output()?;
-  let user_id = str::from_utf8(&output.stdout)?;
+  let user_id = get_user_id(username)?;
 
   // Now issue the launchctl command that kicks out a user
   let user_string = format!("user/{}", user_id);
@@ -190,6 +199,31 @@ where
   Ok(String::from_utf8(output.stdout)?)
 }
 
+/// Like run_command, but doesn't wait for the program to finish.
+fn spawn_command<I, S>(program: &str, args: I) -> Result<()>
+where
+  I: IntoIterator<Item = S>,
+  S: AsRef<OsStr> + Display,
+{
+  let vec: Vec<S> = args.into_iter().collect();
+  info!("Spawn: {} {}", program, vec.iter().join(" "));
+
+  Command::new(program).args(vec).spawn()?;
+
+  Ok(())
+}
+
+/// Writes the file by way of a temporary file next to it, so a crash can't
+/// leave it half written.
+pub fn write_atomically(path: &Path, bytes: &[u8]) -> Result<()> {
+  let mut temp_path = path.as_os_str().to_owned();
+  temp_path.push(".tmp");
+  fs::write(&temp_path, bytes)?;
+  fs::rename(&temp_path, path)?;
+
+  Ok(())
+}
+
 /// Shows a user notification to the currently logged in user. Note that
 /// this only transiently appears on the screen.
 pub fn show_notification(title: &str, message: &str) -> Result<()> {
@@ -205,26 +239,70 @@ pub fn show_notification(title: &str, message: &str) -> Result<()> {
   Ok(())
 }
 
-/// Say something
+/// Say something. Uses espeak where the mac say command isn't available. We
+/// don't wait for it to finish speaking.
 pub fn say(message: &str) -> Result<()> {
-  run_command("say", &[message])?;
+  let program = if cfg!(target_os = "macos") {
+    "say"
+  } else {
+    "espeak"
+  };
+  spawn_command(program, &[message])?;
 
   Ok(())
 }
 
+/// Shows a critical alert to the currently logged in user. The alert stays on
+/// screen until it's dismissed, so we don't wait for it.
 pub fn show_alert(title: &str, message: &str) -> Result<()> {
   let message_s = santize_for_quotes(message);
   let title_s = santize_for_quotes(title);
   let script_command = format!(
-    "display alert\"{}\" message \"{}\" as critical",
+    "display alert \"{}\" message \"{}\" as critical",
     title_s, message_s
   );
 
-  run_command("osascript", &["-e", &script_command])?;
+  spawn_command("osascript", &["-e", &script_command])?;
 
   Ok(())
 }
 
+/// Returns the users with a login session on this computer.
+pub fn get_logged_in_users() -> Result<Vec<String>> {
+  let output = run_command("who", &[] as &[&str])?;
+
+  Ok(
+    output
+      .lines()
+      .filter_map(|line| line.split_whitespace().next())
+      .map(|user| user.to_owned())
+      .unique()
+      .collect(),
+  )
+}
+
+/// Returns the command lines of the processes run by the given user.
+pub fn get_user_processes(username: &str) -> Result<Vec<String>> {
+  let output = run_command("ps", &["-U", username, "-ww", "-o", "args="])?;
+
+  Ok(output.lines().map(|line| line.trim().to_owned()).collect())
+}
+
+/// Returns the message currently shown on the login screen.
+pub fn get_loginscreen_message() -> Result<String> {
+  // This fails (printing nothing) if there's no message set.
+  let output = run_command(
+    "defaults",
+    &[
+      "read",
+      "/Library/Preferences/com.apple.loginwindow",
+      "LoginwindowText",
+    ],
+  )?;
+
+  Ok(output.trim_end_matches('\n').to_owned())
+}
+
 /// Shows a message on the login screen.
 pub fn show_loginscreen_message(message: &str, force: bool) -> Result<()> {
   run_command(
@@ -0,0 +1,140 @@
+//! The protocol spoken between the daemon and the heimdall-agent running in
+//! each user session. Messages are newline delimited JSON, each wrapped in an
+//! envelope carrying the protocol version.
+
+use anyhow::{anyhow, bail, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+
+/// Bump this whenever a message changes incompatibly.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[cfg(not(debug_assertions))]
+static SOCKET_FILE: &str = "/var/run/heimdall/agent.sock";
+#[cfg(debug_assertions)]
+static SOCKET_FILE: &str = "/tmp/heimdall/agent.sock";
+
+pub fn get_socket_path() -> PathBuf {
+  PathBuf::from(SOCKET_FILE)
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Envelope<T> {
+  version: u32,
+  message: T,
+}
+
+/// Messages sent from the agent to the daemon.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AgentMessage {
+  /// The first message on every connection.
+  Hello { username: String },
+  /// Sent periodically so the daemon knows the agent is still alive.
+  Heartbeat,
+}
+
+/// Messages sent from the daemon to the agent.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DaemonMessage {
+  Welcome,
+  Rejected {
+    reason: String,
+  },
+  Warning {
+    title: String,
+    message: String,
+    critical: bool,
+  },
+  Status {
+    locked: bool,
+    minutes_remaining: Option<u32>,
+  },
+}
+
+pub fn write_message<W: Write, T: Serialize>(writer: &mut W, message: &T) -> Result<()> {
+  let envelope = Envelope {
+    version: PROTOCOL_VERSION,
+    message,
+  };
+  let mut line = serde_json::to_string(&envelope)?;
+  line.push('\n');
+  writer.write_all(line.as_bytes())?;
+  writer.flush()?;
+
+  Ok(())
+}
+
+/// Reads the next message, or None if the other end has hung up. Fails if the
+/// message was written with a different protocol version.
+pub fn read_message<R: BufRead, T: DeserializeOwned>(reader: &mut R) -> Result<Option<T>> {
+  let mut line = String::new();
+  if reader.read_line(&mut line)? == 0 {
+    return Ok(None);
+  }
+
+  let envelope: Envelope<serde_json::Value> = serde_json::from_str(&line)?;
+  if envelope.version != PROTOCOL_VERSION {
+    bail!(
+      "Unsupported protocol version {} (expected {})",
+      envelope.version,
+      PROTOCOL_VERSION
+    );
+  }
+
+  serde_json::from_value(envelope.message)
+    .map(Some)
+    .map_err(|e| anyhow!("Bad message: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Cursor;
+
+  #[test]
+  fn test_round_trip() -> Result<()> {
+    let mut buffer = Vec::new();
+    write_message(
+      &mut buffer,
+      &AgentMessage::Hello {
+        username: "test".to_owned(),
+      },
+    )?;
+    write_message(&mut buffer, &AgentMessage::Heartbeat)?;
+
+    let mut reader = Cursor::new(buffer);
+    assert_eq!(
+      Some(AgentMessage::Hello {
+        username: "test".to_owned()
+      }),
+      read_message(&mut reader)?
+    );
+    assert_eq!(Some(AgentMessage::Heartbeat), read_message(&mut reader)?);
+    assert_eq!(None, read_message::<_, AgentMessage>(&mut reader)?);
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_wire_format() -> Result<()> {
+    let mut buffer = Vec::new();
+    write_message(&mut buffer, &AgentMessage::Heartbeat)?;
+
+    assert_eq!(
+      "{\"version\":1,\"message\":{\"type\":\"heartbeat\"}}\n",
+      String::from_utf8(buffer)?
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_version_mismatch() {
+    let mut reader = Cursor::new("{\"version\":99,\"message\":{\"type\":\"heartbeat\"}}\n");
+
+    assert!(read_message::<_, AgentMessage>(&mut reader).is_err());
+  }
+}
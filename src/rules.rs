@@ -0,0 +1,691 @@
+//! Rules parents write to lock or unlock a user on conditions a schedule
+//! can't express, like `weekend && used_weekdays > 5h`. They're checked on
+//! every run after the schedule, quota and banked time, and the first rule
+//! whose condition holds decides. Only an override or pause beats them.
+//!
+//! A condition can use these facts:
+//!
+//! - `time`: the time of day, to compare with times like `17:30`
+//! - `weekday`: the day, like `"mon"`, and `weekend`
+//! - `open`: whether the schedule is open, and `period`: the open period's note
+//! - `used_today`, `used_week` and `used_weekdays`: the time used today, since
+//!   Monday and on this week's weekdays, to compare with durations like `90m`
+//!   or `1h30m`
+//! - `flag("homework")`: whether a parent has set the flag
+//!
+//! combined with `!`, `&&`, `||`, `==`, `!=`, `<`, `<=`, `>`, `>=`, `+`, `-`
+//! and brackets. There are no loops or side effects, and conditions are type
+//! checked when they're parsed, so any that pass validation can be evaluated.
+
+use crate::config::{Rule, RuleAction};
+use crate::schedule::Zone;
+use crate::state::State;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Datelike, Local, NaiveDateTime, Timelike, Weekday};
+use log::error;
+use serde::Serialize;
+use std::fmt;
+
+/// How deeply brackets and `!` can nest.
+const MAX_DEPTH: usize = 32;
+
+/// The biggest number that can be written, so sums can't overflow.
+const MAX_NUMBER: i64 = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Type {
+  Number,
+  Bool,
+  Text,
+}
+
+impl fmt::Display for Type {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str(match self {
+      Type::Number => "a number",
+      Type::Bool => "true or false",
+      Type::Text => "text",
+    })
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+  Number(i64),
+  Bool(bool),
+  Text(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Fact {
+  Time,
+  Weekday,
+  Weekend,
+  Open,
+  Period,
+  UsedToday,
+  UsedWeek,
+  UsedWeekdays,
+}
+
+static FACTS: &[(&str, Fact, Type)] = &[
+  ("time", Fact::Time, Type::Number),
+  ("weekday", Fact::Weekday, Type::Text),
+  ("weekend", Fact::Weekend, Type::Bool),
+  ("open", Fact::Open, Type::Bool),
+  ("period", Fact::Period, Type::Text),
+  ("used_today", Fact::UsedToday, Type::Number),
+  ("used_week", Fact::UsedWeek, Type::Number),
+  ("used_weekdays", Fact::UsedWeekdays, Type::Number),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+  Or,
+  And,
+  Eq,
+  Ne,
+  Lt,
+  Le,
+  Gt,
+  Ge,
+  Add,
+  Sub,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+  Literal(Value),
+  Fact(Fact),
+  Flag(String),
+  Not(Box<Node>),
+  Binary(Op, Box<Node>, Box<Node>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+  Number(i64),
+  Text(String),
+  Name(String),
+  Symbol(&'static str),
+}
+
+impl fmt::Display for Token {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Token::Number(n) => write!(f, "{}", n),
+      Token::Text(text) => write!(f, "{:?}", text),
+      Token::Name(name) => f.write_str(name),
+      Token::Symbol(symbol) => f.write_str(symbol),
+    }
+  }
+}
+
+/// Longer symbols first, so `<=` isn't read as `<`.
+static SYMBOLS: &[&str] = &["||", "&&", "==", "!=", "<=", ">=", "<", ">", "!", "+", "-", "(", ")"];
+
+fn error(column: usize, message: impl fmt::Display) -> anyhow::Error {
+  anyhow!("column {}: {}", column, message)
+}
+
+/// Splits the text into tokens, each with the column it starts at. Times and
+/// durations become numbers of minutes.
+fn tokenize(text: &str) -> Result<Vec<(usize, Token)>> {
+  let chars: Vec<char> = text.chars().collect();
+  let digits = |i: &mut usize| -> Result<i64> {
+    let start = *i;
+    while chars.get(*i).map_or(false, char::is_ascii_digit) {
+      *i += 1;
+    }
+    match chars[start..*i].iter().collect::<String>().parse() {
+      Ok(n) if n <= MAX_NUMBER => Ok(n),
+      Ok(_) => Err(error(start + 1, format!("numbers can't be more than {}", MAX_NUMBER))),
+      Err(_) => Err(error(start + 1, "expected a number")),
+    }
+  };
+
+  let mut tokens = Vec::new();
+  let mut i = 0;
+  while i < chars.len() {
+    let column = i + 1;
+    let c = chars[i];
+    if c.is_whitespace() {
+      i += 1;
+    } else if c.is_ascii_digit() {
+      let mut value = digits(&mut i)?;
+      if chars.get(i) == Some(&':') {
+        i += 1;
+        let minute_start = i;
+        let minute = digits(&mut i)?;
+        if i - minute_start != 2 || minute > 59 || value * 60 + minute > 24 * 60 {
+          return Err(error(column, "not a time between 00:00 and 24:00"));
+        }
+        value = value * 60 + minute;
+      } else {
+        // A duration like 1h30m, or a plain number of minutes.
+        let mut total = 0;
+        loop {
+          match chars.get(i) {
+            Some('h') => total += value * 60,
+            Some('m') => total += value,
+            _ => {
+              total += value;
+              break;
+            }
+          }
+          i += 1;
+          if !chars.get(i).map_or(false, char::is_ascii_digit) {
+            break;
+          }
+          value = digits(&mut i)?;
+        }
+        value = total;
+      }
+      if chars.get(i).map_or(false, |c| c.is_alphanumeric() || *c == '_') {
+        return Err(error(column, "not a number, time or duration"));
+      }
+      tokens.push((column, Token::Number(value)));
+    } else if c == '"' {
+      let length = chars[i + 1..]
+        .iter()
+        .position(|c| *c == '"')
+        .ok_or_else(|| error(column, "text without a closing quote"))?;
+      tokens.push((column, Token::Text(chars[i + 1..i + 1 + length].iter().collect())));
+      i += length + 2;
+    } else if c.is_alphabetic() || c == '_' {
+      let start = i;
+      while chars.get(i).map_or(false, |c| c.is_alphanumeric() || *c == '_') {
+        i += 1;
+      }
+      tokens.push((column, Token::Name(chars[start..i].iter().collect())));
+    } else {
+      let rest: String = chars[i..].iter().take(2).collect();
+      match SYMBOLS.iter().find(|s| rest.starts_with(*s)) {
+        Some(symbol) => {
+          tokens.push((column, Token::Symbol(symbol)));
+          i += symbol.len();
+        }
+        None => return Err(error(column, format!("unexpected {}", c))),
+      }
+    }
+  }
+
+  Ok(tokens)
+}
+
+fn expect(column: usize, found: Type, expected: Type) -> Result<()> {
+  match found == expected {
+    true => Ok(()),
+    false => Err(error(column, format!("expected {}, not {}", expected, found))),
+  }
+}
+
+/// A recursive descent parser that checks types as it goes.
+struct Parser {
+  tokens: Vec<(usize, Token)>,
+  position: usize,
+  /// The column just past the end of the text.
+  end: usize,
+  depth: usize,
+}
+
+impl Parser {
+  fn peek(&self) -> Option<&Token> {
+    self.tokens.get(self.position).map(|(_, token)| token)
+  }
+
+  fn column(&self) -> usize {
+    self.tokens.get(self.position).map_or(self.end, |(column, _)| *column)
+  }
+
+  fn eat(&mut self, symbol: &str) -> bool {
+    let found = matches!(self.peek(), Some(Token::Symbol(s)) if *s == symbol);
+    if found {
+      self.position += 1;
+    }
+    found
+  }
+
+  fn nest(&mut self) -> Result<()> {
+    self.depth += 1;
+    match self.depth > MAX_DEPTH {
+      true => Err(error(self.column(), "too deeply nested")),
+      false => Ok(()),
+    }
+  }
+
+  fn or(&mut self) -> Result<(Node, Type)> {
+    self.logical("||", Op::Or, Parser::and)
+  }
+
+  fn and(&mut self) -> Result<(Node, Type)> {
+    self.logical("&&", Op::And, Parser::unary)
+  }
+
+  fn logical(
+    &mut self,
+    symbol: &str,
+    op: Op,
+    operand: fn(&mut Parser) -> Result<(Node, Type)>,
+  ) -> Result<(Node, Type)> {
+    let column = self.column();
+    let (mut node, node_type) = operand(self)?;
+    if !self.eat(symbol) {
+      return Ok((node, node_type));
+    }
+    expect(column, node_type, Type::Bool)?;
+
+    loop {
+      let column = self.column();
+      let (right, right_type) = operand(self)?;
+      expect(column, right_type, Type::Bool)?;
+      node = Node::Binary(op, Box::new(node), Box::new(right));
+      if !self.eat(symbol) {
+        return Ok((node, Type::Bool));
+      }
+    }
+  }
+
+  fn unary(&mut self) -> Result<(Node, Type)> {
+    if !self.eat("!") {
+      return self.comparison();
+    }
+
+    self.nest()?;
+    let column = self.column();
+    let (node, node_type) = self.unary()?;
+    expect(column, node_type, Type::Bool)?;
+    self.depth -= 1;
+
+    Ok((Node::Not(Box::new(node)), Type::Bool))
+  }
+
+  fn comparison(&mut self) -> Result<(Node, Type)> {
+    let column = self.column();
+    let (left, left_type) = self.sum()?;
+    let op = match self.peek() {
+      Some(Token::Symbol("==")) => Op::Eq,
+      Some(Token::Symbol("!=")) => Op::Ne,
+      Some(Token::Symbol("<")) => Op::Lt,
+      Some(Token::Symbol("<=")) => Op::Le,
+      Some(Token::Symbol(">")) => Op::Gt,
+      Some(Token::Symbol(">=")) => Op::Ge,
+      _ => return Ok((left, left_type)),
+    };
+    self.position += 1;
+
+    let right_column = self.column();
+    let (right, right_type) = self.sum()?;
+    match op {
+      Op::Eq | Op::Ne if left_type != right_type => {
+        return Err(error(
+          right_column,
+          format!("can't compare {} with {}", left_type, right_type),
+        ));
+      }
+      Op::Eq | Op::Ne => {}
+      _ => {
+        expect(column, left_type, Type::Number)?;
+        expect(right_column, right_type, Type::Number)?;
+      }
+    }
+
+    Ok((Node::Binary(op, Box::new(left), Box::new(right)), Type::Bool))
+  }
+
+  fn sum(&mut self) -> Result<(Node, Type)> {
+    let column = self.column();
+    let (mut node, node_type) = self.primary()?;
+    loop {
+      let op = if self.eat("+") {
+        Op::Add
+      } else if self.eat("-") {
+        Op::Sub
+      } else {
+        return Ok((node, node_type));
+      };
+      expect(column, node_type, Type::Number)?;
+      let right_column = self.column();
+      let (right, right_type) = self.primary()?;
+      expect(right_column, right_type, Type::Number)?;
+      node = Node::Binary(op, Box::new(node), Box::new(right));
+    }
+  }
+
+  fn primary(&mut self) -> Result<(Node, Type)> {
+    let column = self.column();
+    let token = match self.peek() {
+      Some(token) => token.clone(),
+      None => return Err(error(column, "expected a value")),
+    };
+    self.position += 1;
+
+    match token {
+      Token::Number(n) => Ok((Node::Literal(Value::Number(n)), Type::Number)),
+      Token::Text(text) => Ok((Node::Literal(Value::Text(text)), Type::Text)),
+      Token::Name(name) if name == "true" || name == "false" => {
+        Ok((Node::Literal(Value::Bool(name == "true")), Type::Bool))
+      }
+      Token::Name(name) if name == "flag" => {
+        if !self.eat("(") {
+          return Err(error(self.column(), "expected ( after flag"));
+        }
+        let flag = match self.peek() {
+          Some(Token::Text(flag)) => flag.clone(),
+          _ => {
+            return Err(error(
+              self.column(),
+              "flag() takes a name in quotes, like flag(\"homework\")",
+            ))
+          }
+        };
+        self.position += 1;
+        if !self.eat(")") {
+          return Err(error(self.column(), "expected )"));
+        }
+        Ok((Node::Flag(flag), Type::Bool))
+      }
+      Token::Name(name) => match FACTS.iter().find(|(n, _, _)| *n == name) {
+        Some((_, fact, fact_type)) => Ok((Node::Fact(*fact), *fact_type)),
+        None => Err(error(column, format!("unknown fact {}", name))),
+      },
+      Token::Symbol("(") => {
+        self.nest()?;
+        let inner = self.or()?;
+        if !self.eat(")") {
+          return Err(error(self.column(), "expected )"));
+        }
+        self.depth -= 1;
+        Ok(inner)
+      }
+      Token::Symbol(symbol) => Err(error(column, format!("expected a value, not {}", symbol))),
+    }
+  }
+}
+
+/// A rule's condition, parsed and type checked.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr(Node);
+
+impl Expr {
+  pub fn parse(text: &str) -> Result<Expr> {
+    let mut parser = Parser {
+      tokens: tokenize(text)?,
+      position: 0,
+      end: text.chars().count() + 1,
+      depth: 0,
+    };
+    let (node, node_type) = parser.or()?;
+    if let Some(token) = parser.peek() {
+      return Err(error(parser.column(), format!("unexpected {}", token)));
+    }
+    expect(1, node_type, Type::Bool)?;
+
+    Ok(Expr(node))
+  }
+
+  pub fn evaluate(&self, facts: &Facts) -> bool {
+    truth(&self.0, facts)
+  }
+}
+
+fn truth(node: &Node, facts: &Facts) -> bool {
+  matches!(evaluate(node, facts), Value::Bool(true))
+}
+
+fn number(node: &Node, facts: &Facts) -> i64 {
+  match evaluate(node, facts) {
+    Value::Number(n) => n,
+    _ => 0,
+  }
+}
+
+fn evaluate(node: &Node, facts: &Facts) -> Value {
+  match node {
+    Node::Literal(value) => value.clone(),
+    Node::Fact(fact) => facts.value(*fact),
+    Node::Flag(flag) => Value::Bool(facts.flags.contains(&flag.as_str())),
+    Node::Not(node) => Value::Bool(!truth(node, facts)),
+    Node::Binary(op, left, right) => match op {
+      Op::Or => Value::Bool(truth(left, facts) || truth(right, facts)),
+      Op::And => Value::Bool(truth(left, facts) && truth(right, facts)),
+      Op::Eq => Value::Bool(evaluate(left, facts) == evaluate(right, facts)),
+      Op::Ne => Value::Bool(evaluate(left, facts) != evaluate(right, facts)),
+      Op::Lt => Value::Bool(number(left, facts) < number(right, facts)),
+      Op::Le => Value::Bool(number(left, facts) <= number(right, facts)),
+      Op::Gt => Value::Bool(number(left, facts) > number(right, facts)),
+      Op::Ge => Value::Bool(number(left, facts) >= number(right, facts)),
+      Op::Add => Value::Number(number(left, facts).saturating_add(number(right, facts))),
+      Op::Sub => Value::Number(number(left, facts).saturating_sub(number(right, facts))),
+    },
+  }
+}
+
+fn is_weekend(day: Weekday) -> bool {
+  day == Weekday::Sat || day == Weekday::Sun
+}
+
+/// What a rule's condition can see.
+pub struct Facts<'a> {
+  /// The time on the wall clock, in the config's timezone.
+  pub now: NaiveDateTime,
+  /// The note of the schedule's open period, if it's open.
+  pub period: Option<&'a str>,
+  /// Minutes used today, since Monday, and on this week's weekdays.
+  pub used_today: i64,
+  pub used_week: i64,
+  pub used_weekdays: i64,
+  pub flags: Vec<&'a str>,
+}
+
+impl<'a> Facts<'a> {
+  pub fn gather(
+    user: &str,
+    zone: Zone,
+    now: DateTime<Local>,
+    period: Option<&'a str>,
+    state: &'a State,
+  ) -> Facts<'a> {
+    // Usage is recorded against the dates in the schedule's timezone.
+    let today = zone.date(now);
+    let monday = today - chrono::Duration::days(today.weekday().num_days_from_monday().into());
+    let (mut week, mut weekdays) = (0, 0);
+    let mut date = monday;
+    while date <= today {
+      let seconds = state.get_usage(user, date);
+      week += seconds;
+      if !is_weekend(date.weekday()) {
+        weekdays += seconds;
+      }
+      date = date.succ();
+    }
+
+    Facts {
+      now: zone.naive(now),
+      period,
+      used_today: (state.get_usage(user, today) / 60) as i64,
+      used_week: (week / 60) as i64,
+      used_weekdays: (weekdays / 60) as i64,
+      flags: state.get_flags(user, now),
+    }
+  }
+
+  fn value(&self, fact: Fact) -> Value {
+    match fact {
+      Fact::Time => Value::Number((self.now.hour() * 60 + self.now.minute()).into()),
+      Fact::Weekday => Value::Text(self.now.weekday().to_string().to_lowercase()),
+      Fact::Weekend => Value::Bool(is_weekend(self.now.weekday())),
+      Fact::Open => Value::Bool(self.period.is_some()),
+      Fact::Period => Value::Text(self.period.unwrap_or_default().to_owned()),
+      Fact::UsedToday => Value::Number(self.used_today),
+      Fact::UsedWeek => Value::Number(self.used_week),
+      Fact::UsedWeekdays => Value::Number(self.used_weekdays),
+    }
+  }
+}
+
+/// What the rules decided, and which of them decided it.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Decision {
+  pub rule: usize,
+  pub action: RuleAction,
+  pub reason: String,
+}
+
+/// What the first rule whose condition holds says to do, if any. A rule that
+/// doesn't parse is skipped, though validation should have kept it out of the
+/// config.
+pub fn decide(rules: &[Rule], facts: &Facts) -> Option<Decision> {
+  rules
+    .iter()
+    .enumerate()
+    .find_map(|(i, rule)| match Expr::parse(&rule.when) {
+      Ok(expr) if expr.evaluate(facts) => Some(Decision {
+        rule: i,
+        action: rule.action,
+        reason: rule.reason.clone(),
+      }),
+      Ok(_) => None,
+      Err(e) => {
+        error!("Skipping rule {:?}: {}", rule.when, e);
+        None
+      }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono::{NaiveDate, TimeZone, Utc};
+
+  // 2021-03-06 is a Saturday.
+  fn facts(period: Option<&str>) -> Facts<'_> {
+    Facts {
+      now: NaiveDate::from_ymd(2021, 3, 6).and_hms(17, 30, 0),
+      period,
+      used_today: 45,
+      used_week: 400,
+      used_weekdays: 355,
+      flags: vec!["homework"],
+    }
+  }
+
+  #[test]
+  fn test_evaluate() -> Result<()> {
+    let facts = facts(Some("homework break"));
+    let cases = [
+      ("true", true),
+      ("false", false),
+      ("time >= 17:00", true),
+      ("time < 17:00", false),
+      ("time >= 24:00", false),
+      ("weekday == \"sat\"", true),
+      ("weekday != \"sun\"", true),
+      ("weekend", true),
+      ("open && period == \"homework break\"", true),
+      ("!open", false),
+      ("used_today < 1h", true),
+      ("used_today + 15m >= 1h", true),
+      ("used_today - 45 == 0", true),
+      ("used_week == 6h40m", true),
+      ("weekend && used_weekdays > 5h", true),
+      ("flag(\"homework\")", true),
+      ("!flag(\"chores\")", true),
+      ("(time < 9:00 || time > 17:00) && !flag(\"chores\")", true),
+      ("false || !(used_today > 30m && weekend)", false),
+      ("true || false && false", true),
+    ];
+    for (text, expected) in cases.iter() {
+      assert_eq!(*expected, Expr::parse(text)?.evaluate(&facts), "{}", text);
+    }
+
+    assert!(Expr::parse("period == \"\"")?.evaluate(&self::facts(None)));
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_errors() {
+    let cases = [
+      ("", "column 1: expected a value"),
+      ("time >", "column 7: expected a value"),
+      ("time >= 25:00", "column 9: not a time between 00:00 and 24:00"),
+      ("time >= 17:5", "column 9: not a time between 00:00 and 24:00"),
+      ("5x > 1", "column 1: not a number, time or duration"),
+      ("bedtime", "column 1: unknown fact bedtime"),
+      ("time", "column 1: expected true or false, not a number"),
+      ("weekend && time", "column 12: expected true or false, not a number"),
+      ("weekday == 3", "column 12: can't compare text with a number"),
+      ("used_today > \"lots\"", "column 14: expected a number, not text"),
+      ("weekend + 1 > 0", "column 1: expected a number, not true or false"),
+      (
+        "flag(homework)",
+        "column 6: flag() takes a name in quotes, like flag(\"homework\")",
+      ),
+      ("(weekend", "column 9: expected )"),
+      ("weekend)", "column 8: unexpected )"),
+      ("\"homework", "column 1: text without a closing quote"),
+      ("weekend; rm -rf /", "column 8: unexpected ;"),
+      ("used_today > 9999999", "column 14: numbers can't be more than 1000000"),
+    ];
+    for (text, expected) in cases.iter() {
+      assert_eq!(*expected, Expr::parse(text).unwrap_err().to_string(), "{}", text);
+    }
+
+    let deep = format!("{}true", "!".repeat(40));
+    assert_eq!(
+      "column 34: too deeply nested",
+      Expr::parse(&deep).unwrap_err().to_string()
+    );
+  }
+
+  #[test]
+  fn test_decide() {
+    let rules = vec![
+      Rule {
+        when: "weekend && used_weekdays > 5h".to_owned(),
+        action: RuleAction::Lock,
+        reason: "Too much screen time this week".to_owned(),
+      },
+      Rule {
+        when: "!flag(\"homework\") && time >= 17:00 && time < 18:00".to_owned(),
+        action: RuleAction::Unlock,
+        reason: "No homework".to_owned(),
+      },
+    ];
+
+    let mut facts = facts(None);
+    let decision = decide(&rules, &facts).unwrap();
+    assert_eq!((0, RuleAction::Lock), (decision.rule, decision.action));
+    assert_eq!("Too much screen time this week", decision.reason);
+
+    facts.used_weekdays = 100;
+    assert_eq!(None, decide(&rules, &facts));
+
+    facts.flags.clear();
+    let decision = decide(&rules, &facts).unwrap();
+    assert_eq!((1, RuleAction::Unlock), (decision.rule, decision.action));
+  }
+
+  #[test]
+  fn test_gather() {
+    let mut state = State::default();
+    // Monday, Tuesday and Saturday, and the Sunday before, which doesn't count.
+    for (day, minutes) in [(1, 60), (2, 30), (6, 15), (28, 600)].iter() {
+      let month = if *day == 28 { 2 } else { 3 };
+      state.add_usage("alice", NaiveDate::from_ymd(2021, month, *day), minutes * 60);
+    }
+    let now = Local.ymd(2021, 3, 6).and_hms(17, 30, 0);
+    state.set_flag("alice", "homework", now, None);
+
+    let facts = Facts::gather("alice", Zone::Local, now, None, &state);
+    assert_eq!((15, 105, 90), (facts.used_today, facts.used_week, facts.used_weekdays));
+    assert_eq!(vec!["homework"], facts.flags);
+    assert_eq!(now.naive_local(), facts.now);
+
+    // Usage goes by the schedule's dates, where it's already Sunday.
+    let now = Utc.ymd(2021, 3, 6).and_hms(17, 30, 0).with_timezone(&Local);
+    let facts = Facts::gather("alice", Zone::Named(chrono_tz::Pacific::Kiritimati), now, None, &state);
+    assert_eq!((0, 105, 90), (facts.used_today, facts.used_week, facts.used_weekdays));
+  }
+}
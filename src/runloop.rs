@@ -1,14 +1,21 @@
-use crate::config::Config;
-use crate::config::OpenPeriod;
-use crate::config::{self, Instant, Schedule};
+use crate::agent::AgentServer;
+use crate::config::{self, Config, MeetingDeferral};
+use crate::feed::Feeds;
+use crate::meeting;
+use crate::notify::{self, Event};
 use crate::os;
 use crate::constants;
+use crate::protocol::DaemonMessage;
+use crate::schedule::{self, TransitionKind};
+use crate::state::{self, Pause, SharedState};
+use crate::verdict;
 use anyhow::Result;
-use chrono::{DateTime, Datelike, Local, Timelike};
+use chrono::{DateTime, Local};
 use clokwerk::{ScheduleHandle, Scheduler, TimeUnits};
 use std::{
   backtrace::BacktraceStatus,
   collections::HashMap,
+  convert::TryFrom,
   fs,
   sync::{Arc, Mutex, MutexGuard},
   time::SystemTime,
@@ -16,27 +23,80 @@ use std::{
 use std::{path::Path, time::Duration};
 use log::{info, error};
 
+/// The most time we'll count as used between two runs.
+const MAX_ELAPSED_SECONDS: i64 = 30;
+
 // Running state for polling. This lets us keep
 // track of things that are happening while the program
 // is running.
 struct RunState {
   config: Option<Config>,
+  // Whether the config file is invalid, so we're enforcing a fallback that
+  // mustn't be saved over it.
+  config_is_fallback: bool,
   config_last_modified: Option<SystemTime>,
   config_len: Option<u64>,
   user_state: HashMap<String, UserInMemoryState>,
+  agents: Option<Arc<AgentServer>>,
+  // The last message we wrote to the login window, and whether the login
+  // window still needs restarting to show it.
+  loginwindow_text: Option<String>,
+  loginwindow_stale: bool,
+  state: SharedState,
+  feeds: Feeds,
+  last_run: Option<DateTime<Local>>,
 }
 
 struct UserInMemoryState {
   is_locked: Option<bool>,
+  // The end of the period we last warned about, and the warnings (in
+  // minutes) already shown for it.
+  warning_period_end: Option<DateTime<Local>>,
+  warnings_sent: Vec<u32>,
+  // Whether we've already told anyone that enforcement is failing.
+  enforcement_failed: bool,
+  // When a lock has been postponed until because of a meeting, and by how
+  // much in total.
+  deferred_until: Option<DateTime<Local>>,
+  deferred_minutes: u32,
+  // Whether the user is unlocked on time from their bank, and the debit
+  // recording what they've spent so far.
+  spending_bank: bool,
+  bank_session: Option<u64>,
+  // When a manual lock runs out.
+  locked_until: Option<DateTime<Local>>,
+}
+
+impl UserInMemoryState {
+  fn new() -> UserInMemoryState {
+    UserInMemoryState {
+      is_locked: None,
+      warning_period_end: None,
+      warnings_sent: Vec::new(),
+      enforcement_failed: false,
+      deferred_until: None,
+      deferred_minutes: 0,
+      spending_bank: false,
+      bank_session: None,
+      locked_until: None,
+    }
+  }
 }
 
 impl RunState {
-  pub fn new() -> RunState {
+  pub fn new(agents: Option<Arc<AgentServer>>, state: SharedState, feeds: Feeds) -> RunState {
     RunState {
       config: None,
+      config_is_fallback: false,
       config_last_modified: None,
       config_len: None,
       user_state: HashMap::new(),
+      agents,
+      loginwindow_text: None,
+      loginwindow_stale: false,
+      state,
+      feeds,
+      last_run: None,
     }
   }
 }
@@ -68,96 +128,343 @@ fn set_locked(user: &str, locked: bool) -> Result<()> {
   if locked {
     info!("Force logging out user {}", user);
     os::boot_user_out(user)?;
-    os::show_loginscreen_message(&format!("{} is currently locked out", user), true)?;
-  } else {
-    // TODO: we need to make this handle multi user
-    os::show_loginscreen_message("", false)?;
   }
 
   Ok(())
 }
 
-fn run_with_result(run_state: &mut RunState) -> Result<()> {
-  info!("Run loop started");
-  check_config_loaded(run_state)?;
+/// Returns the warning (in minutes) that should be shown now, given the time
+/// remaining before a lock and the warnings already shown. Only the most
+/// urgent warning is returned, so if we start out with three minutes left we
+/// don't also show the fifteen and five minute warnings.
+fn due_warning(remaining: chrono::Duration, thresholds: &[u32], sent: &[u32]) -> Option<u32> {
+  thresholds
+    .iter()
+    .filter(|t| remaining <= chrono::Duration::minutes(**t as i64))
+    .min()
+    .filter(|t| !sent.contains(t))
+    .copied()
+}
 
-  if let Some(config) = &mut run_state.config {
-    for (user, user_config) in &mut config.user_config {
-      info!("Checking config for {}", user);
-      // Check if the user should be locked out right now.
-      let state = run_state
-        .user_state
-        .entry(user.to_owned())
-        .or_insert(UserInMemoryState { is_locked: None });
+/// Records and returns the warning due for the period ending at period_end, if
+/// it hasn't already been shown.
+fn check_warnings(
+  state: &mut UserInMemoryState,
+  now: DateTime<Local>,
+  period_end: DateTime<Local>,
+  thresholds: &[u32],
+) -> Option<u32> {
+  if state.warning_period_end != Some(period_end) {
+    state.warning_period_end = Some(period_end);
+    state.warnings_sent.clear();
+  }
 
-      let open_period = find_max_open_period(Local::now(), &user_config.schedule);
-      let should_lock = open_period.is_none();
-      let is_locked = state.is_locked.unwrap_or(!should_lock);
+  let minutes = due_warning(period_end - now, thresholds, &state.warnings_sent)?;
+  // Anything longer than this warning is now moot.
+  state
+    .warnings_sent
+    .extend(thresholds.iter().filter(|t| **t >= minutes));
 
-      info!("should_lock={}, is_locked={}", should_lock, is_locked);
+  Some(minutes)
+}
 
-      if should_lock != is_locked {
-        set_locked(user, should_lock)?;
-        state.is_locked = Some(should_lock);
-      }
-    }
+/// Can the lock be postponed again without going over the limit?
+fn can_defer(deferral: &MeetingDeferral, state: &UserInMemoryState) -> bool {
+  state.deferred_minutes + deferral.increment_minutes <= deferral.max_minutes
+}
+
+/// Called when the user is due to be locked. If they're in a meeting, postpones
+/// the lock and returns the reason.
+fn defer_lock(
+  user: &str,
+  deferral: &MeetingDeferral,
+  state: &mut UserInMemoryState,
+  now: DateTime<Local>,
+) -> Option<String> {
+  if !can_defer(deferral, state) {
+    return None;
   }
 
-  Ok(())
+  let meeting = meeting::get_active_meeting(user, deferral)?;
+  let reason = format!("{} call in progress", meeting.name);
+
+  let increment = chrono::Duration::minutes(deferral.increment_minutes.into());
+  state.deferred_until = Some(now + increment);
+  state.deferred_minutes += deferral.increment_minutes;
+  info!(
+    "Postponed locking {} until {} ({} minutes so far): {}",
+    user,
+    now + increment,
+    state.deferred_minutes,
+    reason
+  );
+
+  Some(reason)
 }
 
-fn get_start_of_week(now: &DateTime<Local>) -> Option<DateTime<Local>> {
-  let weekday = now.weekday().num_days_from_sunday();
-  let sunday = *now - chrono::Duration::days(weekday.into());
-  sunday
-    .with_hour(0)
-    .and_then(|d| d.with_minute(0))
-    .and_then(|d| d.with_hour(0))
-    .and_then(|d| d.with_minute(0))
-    .and_then(|d| d.with_second(0))
-    .and_then(|d| d.with_nanosecond(0))
+/// Builds the login window message describing every locked user.
+fn loginwindow_message(
+  now: DateTime<Local>,
+  config: &Config,
+  user_state: &HashMap<String, UserInMemoryState>,
+  feeds: &Feeds,
+  pause: Option<&Pause>,
+) -> String {
+  let mut lines: Vec<String> = config
+    .user_config
+    .iter()
+    .filter(|(user, _)| user_state.get(*user).and_then(|s| s.is_locked) == Some(true))
+    .map(|(user, user_config)| {
+      let state = user_state.get(user);
+      let feed = feeds.occurrences(user);
+      let policy = schedule::Policy::for_user(config, user_config).with_feed(&feed);
+      let locked_until = state.and_then(|s| s.locked_until);
+      let unlock = match pause.map(|p| p.until) {
+        // Paused until further notice.
+        Some(None) => None,
+        paused_until => match paused_until.flatten().max(locked_until) {
+          // Once that runs out the schedule takes over, so it unlocks then if
+          // the schedule is open.
+          Some(until) => schedule::next_transition(until, &policy).map(|t| match t.kind {
+            TransitionKind::Lock => until,
+            TransitionKind::Unlock => t.at,
+          }),
+          None => schedule::find_next_unlock(now, &policy),
+        },
+      };
+      match unlock {
+        Some(unlock) => format!("{}: unlocks {}", user, unlock.format("%H:%M %a")),
+        None => format!("{}: locked", user),
+      }
+    })
+    .collect();
+  lines.sort();
+
+  lines.join("\n")
 }
 
-fn from_instant(start_of_week: &DateTime<Local>, instant: &Instant) -> Option<DateTime<Local>> {
-  (*start_of_week + chrono::Duration::days(instant.weekday.into()))
-    .with_hour(instant.hour.into())
-    .and_then(|d| d.with_minute(instant.minute.into()))
+/// Rewrites the login window message if it's changed. The login window only
+/// picks up the new message when it's restarted, which would kill the session
+/// of anybody logged in, so in that case we wait until everyone has logged out.
+fn update_loginwindow(run_state: &mut RunState, logged_in: &[String]) -> Result<()> {
+  let now = Local::now();
+  let pause = run_state.state.lock().unwrap().get_pause(now).cloned();
+  let text = match &run_state.config {
+    Some(config) => loginwindow_message(now, config, &run_state.user_state, &run_state.feeds, pause.as_ref()),
+    None => return Ok(()),
+  };
+
+  if run_state.loginwindow_text.is_none() {
+    // Pick up where we left off if we've been restarted.
+    run_state.loginwindow_text = Some(os::get_loginscreen_message()?);
+  }
+
+  let changed = run_state.loginwindow_text.as_deref() != Some(text.as_str());
+  let force = logged_in.is_empty();
+  if changed || (run_state.loginwindow_stale && force) {
+    os::show_loginscreen_message(&text, force)?;
+    run_state.loginwindow_text = Some(text);
+    run_state.loginwindow_stale = !force;
+  }
+
+  Ok(())
+}
+
+/// How much time to count as used since the last run. If it's been much
+/// longer than the usual interval, the computer was probably asleep, so we
+/// don't count all of it.
+fn get_elapsed_seconds(last_run: Option<DateTime<Local>>, now: DateTime<Local>) -> u64 {
+  match last_run {
+    Some(last_run) => (now - last_run).num_seconds().clamp(0, MAX_ELAPSED_SECONDS) as u64,
+    None => 0,
+  }
 }
 
-fn get_local_period(
-  now: &DateTime<Local>,
-  period: &OpenPeriod,
-) -> Option<(DateTime<Local>, DateTime<Local>)> {
-  if let Some(start_of_week) = get_start_of_week(now) {
-    let start = from_instant(&start_of_week, &period.start);
-    let end = from_instant(&start_of_week, &period.end);
+/// Counts usage since the last run and decides whether each user should be
+/// locked, saving the state if that changed anything. This holds the shared
+/// state lock, so it mustn't do anything that might block for long.
+fn evaluate(
+  run_state: &mut RunState,
+  logged_in: &[String],
+  elapsed_seconds: u64,
+) -> HashMap<String, verdict::Verdict> {
+  let mut verdicts = HashMap::new();
+  let mut persisted = run_state.state.lock().unwrap();
+  let mut persisted_changed = false;
+
+  persisted_changed |= persisted.prune_pause(Local::now());
+
+  // Usage and quotas go by the days in the schedule's timezone.
+  let zone = run_state.config.as_ref().map_or(schedule::Zone::Local, schedule::Zone::for_config);
+  if let Some(config) = &run_state.config {
+    for (user, user_config) in &config.user_config {
+      info!("Checking config for {}", user);
+      let state = run_state
+        .user_state
+        .entry(user.to_owned())
+        .or_insert_with(UserInMemoryState::new);
+
+      let now = Local::now();
+      let today = zone.date(now);
+      if state.is_locked == Some(false) && logged_in.contains(user) && elapsed_seconds > 0 {
+        persisted.add_usage(user, today, elapsed_seconds);
+        if state.spending_bank {
+          let (_, session) = persisted.ledger(user).spend(now, elapsed_seconds, state.bank_session);
+          state.bank_session = session;
+        }
+        persisted_changed = true;
+      }
+      persisted_changed |= persisted.roll_over(user, user_config, zone, now);
+      persisted_changed |= persisted.prune_overrides(now);
+      persisted_changed |= persisted.prune_flags(now);
+
+      let feed = run_state.feeds.occurrences(user);
+      let policy = schedule::Policy::for_user(config, user_config).with_feed(&feed);
+      let verdict = verdict::decide(user, user_config, &policy, &persisted, now);
+      info!("{} verdict: locked={} ({:?})", user, verdict.locked, verdict.reason);
+      state.locked_until = verdict.manual.as_ref().filter(|m| m.locked).map(|m| m.until);
+      state.spending_bank = verdict.spending_bank;
+      if !verdict.spending_bank {
+        state.bank_session = None;
+      }
+      verdicts.insert(user.to_owned(), verdict);
+    }
+  }
 
-    if let (Some(start), Some(end)) = (start, end) {
-      return Some((start, end));
+  if persisted_changed {
+    persisted.prune_usage(zone.date(Local::now()));
+    persisted.prune_ledger(Local::now());
+    // Still enforce what we've decided, even if it can't be saved.
+    if let Err(e) = state::save(&persisted) {
+      error!("Failed to save state: {}", e);
     }
   }
 
-  None
+  verdicts
 }
 
-/// Given a schedule, returns the longest open period containing now.
-fn find_max_open_period(now: DateTime<Local>, schedule: &config::Schedule) -> Option<&OpenPeriod> {
-  let mut max_period_duration = chrono::Duration::zero();
-  let mut max_period = None;
-
-  for period in &schedule.open_periods {
-    if let Some((start, end)) = get_local_period(&now, &period) {
-      if now >= start && now < end {
-        let period_duration = end - start;
-        if period_duration > max_period_duration {
-          max_period_duration = period_duration;
-          max_period = Some(period)
+fn run_with_result(run_state: &mut RunState) -> Result<()> {
+  info!("Run loop started");
+  check_config_loaded(run_state)?;
+  prune_expired_exceptions(run_state)?;
+  if let Some(config) = &run_state.config {
+    run_state.feeds.follow(config);
+  }
+
+  let logged_in = os::get_logged_in_users()?;
+  let elapsed_seconds = get_elapsed_seconds(run_state.last_run, Local::now());
+  run_state.last_run = Some(Local::now());
+
+  // Work out every verdict while holding the shared state, and only then do
+  // anything slow, so the API isn't kept waiting on a webhook or a logout.
+  let verdicts = evaluate(run_state, &logged_in, elapsed_seconds);
+
+  let agents = run_state.agents.as_deref();
+  if let Some(config) = &run_state.config {
+    for (user, user_config) in &config.user_config {
+      let verdict = match verdicts.get(user) {
+        Some(verdict) => verdict,
+        None => continue,
+      };
+      let state = run_state
+        .user_state
+        .entry(user.to_owned())
+        .or_insert_with(UserInMemoryState::new);
+
+      let now = Local::now();
+      let mut should_lock = verdict.locked;
+      let mut period_end = verdict.lock_at;
+      let is_locked = state.is_locked.unwrap_or(!should_lock);
+
+      // Only the runloop knows about meetings, so it postpones locks itself,
+      // unless a parent has overridden the verdict or paused everyone.
+      if !should_lock {
+        state.deferred_until = None;
+        state.deferred_minutes = 0;
+      } else if let (false, None, None, Some(deferral)) =
+        (is_locked, &verdict.pause, &verdict.manual, &user_config.meeting_deferral)
+      {
+        if state.deferred_until.map_or(false, |until| now < until) {
+          should_lock = false;
+        } else if let Some(reason) = defer_lock(user, deferral, state, now) {
+          should_lock = false;
+          let event = Event::LockDeferred {
+            user: user.to_owned(),
+            minutes: deferral.increment_minutes,
+            reason,
+          };
+          notify::dispatch(&user_config.notifications, &event, agents);
+        }
+        if !should_lock {
+          period_end = state.deferred_until;
+        }
+      }
+
+      info!("should_lock={}, is_locked={}", should_lock, is_locked);
+
+      if should_lock != is_locked {
+        if let Err(e) = set_locked(user, should_lock) {
+          // Carry on with everybody else; we'll try again next time.
+          error!("Failed to {} {}: {}", if should_lock { "lock" } else { "unlock" }, user, e);
+          if !state.enforcement_failed {
+            state.enforcement_failed = true;
+            let event = Event::EnforcementFailed {
+              user: user.to_owned(),
+              error: e.to_string(),
+            };
+            notify::dispatch(&user_config.notifications, &event, agents);
+          }
+          continue;
+        }
+        state.is_locked = Some(should_lock);
+        state.enforcement_failed = false;
+        state.deferred_until = None;
+        state.deferred_minutes = 0;
+
+        let event = match should_lock {
+          true => Event::Locked { user: user.to_owned() },
+          false => Event::Unlocked { user: user.to_owned() },
+        };
+        notify::dispatch(&user_config.notifications, &event, agents);
+      }
+
+      if let Some(end) = period_end {
+        if let Some(minutes) = check_warnings(state, now, end, &config.warning_minutes) {
+          // Don't interrupt a meeting with a warning for a lock that will
+          // probably be postponed.
+          let in_meeting = match &user_config.meeting_deferral {
+            Some(deferral) if can_defer(deferral, state) => {
+              meeting::get_active_meeting(user, deferral).is_some()
+            }
+            _ => false,
+          };
+
+          if in_meeting {
+            info!("Not warning {} during a meeting", user);
+          } else {
+            let event = Event::WarningUpcomingLock {
+              user: user.to_owned(),
+              minutes,
+            };
+            notify::dispatch(&user_config.notifications, &event, agents);
+          }
+        }
+      }
+
+      if let Some(agents) = agents {
+        let status = DaemonMessage::Status {
+          locked: should_lock,
+          // A lock that's overdue, e.g. because it failed, has no time left.
+          minutes_remaining: period_end.map(|end| u32::try_from((end - now).num_minutes()).unwrap_or(0)),
+        };
+        if let Err(e) = agents.send(user, &status) {
+          error!("Failed to send status to agent for {}: {}", user, e);
         }
       }
     }
   }
 
-  max_period
+  update_loginwindow(run_state, &logged_in)
 }
 
 fn get_config_file_metadata() -> Result<Option<(SystemTime, u64)>> {
@@ -199,21 +506,53 @@ fn check_config_loaded(run_state: &mut RunState) -> Result<()> {
   };
 
   if load {
-    let config = config::load()?;
-
+    // The API reports the errors too, but only the runloop writes anything.
+    let (config, errors) = config::load_enforced()?;
+    match &errors {
+      Some(errors) => error!("Not enforcing the config file: {}", errors),
+      None => {
+        if let Err(e) = config::remember_good(&config) {
+          error!("Couldn't keep a copy of the config: {}", e);
+        }
+      }
+    }
     run_state.config = Some(config);
-    if let Some((modified, len)) = get_config_file_metadata()? {
-      run_state.config_last_modified = Some(modified);
-      run_state.config_len = Some(len);
+    run_state.config_is_fallback = errors.is_some();
+    remember_config_file_metadata(run_state)?;
+  }
+
+  Ok(())
+}
+
+fn remember_config_file_metadata(run_state: &mut RunState) -> Result<()> {
+  if let Some((modified, len)) = get_config_file_metadata()? {
+    run_state.config_last_modified = Some(modified);
+    run_state.config_len = Some(len);
+  }
+
+  Ok(())
+}
+
+/// Removes schedule exceptions that are over from the config file.
+fn prune_expired_exceptions(run_state: &mut RunState) -> Result<()> {
+  if run_state.config_is_fallback {
+    return Ok(());
+  }
+  if let Some(config) = &mut run_state.config {
+    if config.prune_expired_exceptions(schedule::Zone::for_config(config).date(Local::now())) {
+      info!("Removing expired schedule exceptions");
+      config::save(config)?;
+      // We already have what we just saved, so there's no need to reload it.
+      remember_config_file_metadata(run_state)?;
     }
   }
 
   Ok(())
 }
 
-pub fn start() -> ScheduleHandle {
+pub fn start(agents: Option<Arc<AgentServer>>, state: SharedState, feeds: Feeds) -> ScheduleHandle {
   info!("Starting run loop");
-  let run_state = Arc::new(Mutex::new(RunState::new()));
+  let run_state = Arc::new(Mutex::new(RunState::new(agents, state, feeds)));
 
   let mut scheduler = Scheduler::new();
 
@@ -227,40 +566,131 @@ pub fn start() -> ScheduleHandle {
 #[cfg(test)]
 mod tests {
   use chrono::TimeZone;
-  use config::{OpenPeriod, Schedule};
 
   use super::*;
   #[test]
-  fn test_start_of_week() {
-    println!("{:?}", get_start_of_week(&Local::now()));
+  fn test_loginwindow_message() -> Result<()> {
+    let config: Config = serde_json::from_str(
+      r#"{
+        "user_config": {
+          "alice": {
+            "username": "alice",
+            "schedule": { "open_periods": [
+              { "start": { "weekday": 3, "hour": 15, "minute": 0 },
+                "end": { "weekday": 3, "hour": 16, "minute": 0 },
+                "note": "" }
+            ] }
+          },
+          "bob": { "username": "bob", "schedule": { "open_periods": [] } },
+          "carol": { "username": "carol", "schedule": { "open_periods": [] } },
+          "dave": {
+            "username": "dave",
+            "schedule": { "open_periods": [] },
+            "notifications": [ { "channel": { "type": "desktop" } } ]
+          }
+        }
+      }"#,
+    )?;
+
+    let mut user_state = HashMap::new();
+    for (user, locked) in &[("alice", true), ("bob", true), ("carol", false), ("dave", true)] {
+      let mut state = UserInMemoryState::new();
+      state.is_locked = Some(*locked);
+      user_state.insert(user.to_string(), state);
+    }
+
+    // Every locked user is listed, whatever channels their events go to.
+    let now = Local.ymd(2020, 1, 1).and_hms(14, 30, 0);
+    assert_eq!(
+      "alice: unlocks 15:00 Wed\nbob: locked\ndave: locked",
+      loginwindow_message(now, &config, &user_state, &Feeds::default(), None)
+    );
+
+    // Locked by hand until part way through her open period.
+    user_state.get_mut("alice").unwrap().locked_until = Some(Local.ymd(2020, 1, 1).and_hms(15, 30, 0));
+    assert_eq!(
+      "alice: unlocks 15:30 Wed\nbob: locked\ndave: locked",
+      loginwindow_message(now, &config, &user_state, &Feeds::default(), None)
+    );
+
+    // A longer pause wins, and an open-ended one hides the schedule.
+    let mut pause = Pause {
+      since: now,
+      until: Some(Local.ymd(2020, 1, 1).and_hms(15, 45, 0)),
+      reason: String::new(),
+    };
+    assert_eq!(
+      "alice: unlocks 15:45 Wed\nbob: locked\ndave: locked",
+      loginwindow_message(now, &config, &user_state, &Feeds::default(), Some(&pause))
+    );
+    pause.until = None;
+    assert_eq!(
+      "alice: locked\nbob: locked\ndave: locked",
+      loginwindow_message(now, &config, &user_state, &Feeds::default(), Some(&pause))
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_can_defer() {
+    let deferral = MeetingDeferral {
+      meetings: vec![],
+      increment_minutes: 5,
+      max_minutes: 10,
+    };
+    let mut state = UserInMemoryState::new();
+
+    assert!(can_defer(&deferral, &state));
+    state.deferred_minutes = 5;
+    assert!(can_defer(&deferral, &state));
+    state.deferred_minutes = 10;
+    assert!(!can_defer(&deferral, &state));
   }
 
-  fn create_schedule(start: (u8, u8, u8), end: (u8, u8, u8)) -> Schedule {
-    Schedule {
-      open_periods: vec![OpenPeriod {
-        start: Instant {
-          weekday: start.0,
-          hour: start.1,
-          minute: start.2,
-        },
-        end: Instant {
-          weekday: end.0,
-          hour: end.1,
-          minute: end.2,
-        },
-        note: "".to_owned(),
-      }],
-    }
+  #[test]
+  fn test_get_elapsed_seconds() {
+    let now = Local.ymd(2020, 1, 1).and_hms(15, 0, 0);
+
+    assert_eq!(0, get_elapsed_seconds(None, now));
+    assert_eq!(15, get_elapsed_seconds(Some(now - chrono::Duration::seconds(15)), now));
+    // Asleep for an hour.
+    assert_eq!(30, get_elapsed_seconds(Some(now - chrono::Duration::hours(1)), now));
+    // The clock went backwards.
+    assert_eq!(0, get_elapsed_seconds(Some(now + chrono::Duration::seconds(15)), now));
   }
 
   #[test]
-  fn test_find_open_period() {
-    let schedule = create_schedule((3, 14, 45), (3, 15, 0));
+  fn test_due_warning() {
+    let thresholds = [15, 5, 1];
+    let minutes = chrono::Duration::minutes;
+
+    assert_eq!(None, due_warning(minutes(20), &thresholds, &[]));
+    assert_eq!(Some(15), due_warning(minutes(15), &thresholds, &[]));
+    assert_eq!(None, due_warning(minutes(14), &thresholds, &[15]));
+    assert_eq!(Some(5), due_warning(minutes(4), &thresholds, &[15]));
+    assert_eq!(Some(1), due_warning(chrono::Duration::seconds(30), &thresholds, &[15, 5]));
+    assert_eq!(None, due_warning(chrono::Duration::seconds(15), &thresholds, &[15, 5, 1]));
+
+    // Starting late skips straight to the most urgent warning.
+    assert_eq!(Some(5), due_warning(minutes(3), &thresholds, &[]));
+  }
 
-    let now = Local.ymd(2020, 1, 1).and_hms(14, 30, 0);
-    assert_eq!(false, find_max_open_period(now, &schedule).is_some());
+  #[test]
+  fn test_check_warnings_fires_once_per_period() {
+    let mut state = UserInMemoryState::new();
+    let end = Local.ymd(2020, 1, 1).and_hms(15, 0, 0);
+
+    let before = |m| end - chrono::Duration::minutes(m);
+
+    assert_eq!(Some(15), check_warnings(&mut state, before(10), end, &[15, 5]));
+    assert_eq!(None, check_warnings(&mut state, before(9), end, &[15, 5]));
+    assert_eq!(Some(5), check_warnings(&mut state, before(4), end, &[15, 5]));
+    assert_eq!(None, check_warnings(&mut state, before(3), end, &[15, 5]));
 
-    let now = Local.ymd(2020, 1, 1).and_hms(14, 45, 0);
-    assert_eq!(true, find_max_open_period(now, &schedule).is_some());
+    // A new period starts over.
+    let next_end = end + chrono::Duration::days(1);
+    assert_eq!(None, check_warnings(&mut state, next_end - chrono::Duration::minutes(30), next_end, &[15, 5]));
+    assert!(state.warnings_sent.is_empty());
   }
 }
@@ -0,0 +1,1197 @@
+//! Works out when a schedule's open periods actually happen.
+//!
+//! The weekly schedule is circular: a period whose end comes before its start
+//! in the week (e.g. Saturday 22:00 to Sunday 01:00) wraps around into the
+//! following week. Rather than anchoring periods to the start of the current
+//! week, we expand them day by day, so a period is always evaluated against
+//! the date it started on.
+//!
+//! Periods are in wall clock time in the configured timezone. When a period
+//! starts or ends in a daylight saving gap it starts or ends at the first
+//! valid instant after the gap, and a time that happens twice when the clocks
+//! go back means the first of the two, so nothing runs twice.
+
+use crate::config::{
+  Config, CronRule, DayPeriod, ExceptionKind, Group, Hysteresis, Instant, OpenPeriod, Schedule, ScheduleException,
+  Template, TimeOfDay, UserConfig,
+};
+use chrono::{DateTime, Datelike, Local, LocalResult, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Timelike};
+use chrono_tz::Tz;
+use serde::Serialize;
+
+pub const MINUTES_PER_WEEK: i64 = 7 * 24 * 60;
+
+/// Where a period in a user's weekly schedule comes from.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Source<'a> {
+  User,
+  Group { name: &'a str },
+  Template { name: &'a str },
+}
+
+/// The timezone schedules are written in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Zone {
+  /// Whatever the computer is set to.
+  Local,
+  Named(Tz),
+}
+
+/// The most a daylight saving gap can skip.
+const MAX_GAP_MINUTES: i64 = 3 * 60;
+
+/// The instant for a local time, dealing with gaps and folds as described
+/// above.
+fn resolve<T: TimeZone>(zone: &T, time: &NaiveDateTime) -> Option<DateTime<Local>> {
+  for minutes in 0..=MAX_GAP_MINUTES {
+    match zone.from_local_datetime(&(*time + chrono::Duration::minutes(minutes))) {
+      LocalResult::Single(t) | LocalResult::Ambiguous(t, _) => return Some(t.with_timezone(&Local)),
+      LocalResult::None => {}
+    }
+  }
+
+  None
+}
+
+impl Zone {
+  pub fn for_config(config: &Config) -> Zone {
+    match config.timezone.as_deref().map(str::parse::<Tz>) {
+      Some(Ok(tz)) => Zone::Named(tz),
+      _ => Zone::Local,
+    }
+  }
+
+  pub fn localize(&self, time: &NaiveDateTime) -> Option<DateTime<Local>> {
+    match self {
+      Zone::Local => resolve(&Local, time),
+      Zone::Named(tz) => resolve(tz, time),
+    }
+  }
+
+  /// The time on the wall clock at the given instant.
+  pub fn naive(&self, time: DateTime<Local>) -> NaiveDateTime {
+    match self {
+      Zone::Local => time.naive_local(),
+      Zone::Named(tz) => time.with_timezone(tz).naive_local(),
+    }
+  }
+
+  /// The date on the wall calendar at the given instant.
+  pub fn date(&self, time: DateTime<Local>) -> NaiveDate {
+    self.naive(time).date()
+  }
+}
+
+/// Everything that decides when a user is allowed to be unlocked.
+pub struct Policy<'a> {
+  pub zone: Zone,
+  pub schedule: &'a Schedule,
+  /// The group the user inherits a schedule from, less the removed periods.
+  pub group: Option<(&'a str, &'a Group)>,
+  pub removed: &'a [OpenPeriod],
+  /// Named templates that replace the schedule when active, in order of
+  /// priority.
+  pub templates: Vec<(&'a str, &'a Template)>,
+  pub exceptions: &'a [ScheduleException],
+  /// Dated occurrences from a calendar feed, opened on top of everything
+  /// else except locked days.
+  pub feed: &'a [Occurrence],
+  pub hysteresis: Hysteresis,
+}
+
+impl<'a> Policy<'a> {
+  pub fn for_user(config: &'a Config, user_config: &'a UserConfig) -> Policy<'a> {
+    Policy {
+      zone: Zone::for_config(config),
+      schedule: &user_config.schedule,
+      group: user_config
+        .group
+        .as_ref()
+        .and_then(|name| config.groups.get_key_value(name))
+        .map(|(name, group)| (name.as_str(), group)),
+      removed: &user_config.removed_periods,
+      templates: user_config
+        .templates
+        .iter()
+        .filter_map(|name| config.templates.get_key_value(name))
+        .map(|(name, template)| (name.as_str(), template))
+        .collect(),
+      exceptions: &user_config.exceptions,
+      feed: &[],
+      hysteresis: user_config.hysteresis,
+    }
+  }
+
+  pub fn with_feed(self, feed: &'a [Occurrence]) -> Policy<'a> {
+    Policy { feed, ..self }
+  }
+
+  /// The name of the template that applies on the date, if any.
+  pub fn template_on(&self, date: NaiveDate) -> Option<&'a str> {
+    self.templates.iter().find(|(_, t)| t.is_active(date)).map(|(name, _)| *name)
+  }
+
+  /// The user's usual weekly periods, and where each came from.
+  pub fn weekly_periods(&self) -> Vec<(Source<'a>, &'a OpenPeriod)> {
+    let mut periods = Vec::new();
+    if let Some((name, group)) = self.group {
+      periods.extend(
+        group
+          .schedule
+          .open_periods
+          .iter()
+          .filter(|p| !self.removed.contains(p))
+          .map(|p| (Source::Group { name }, p)),
+      );
+    }
+    periods.extend(self.schedule.open_periods.iter().map(|p| (Source::User, p)));
+
+    periods
+  }
+
+  /// The weekly periods that apply on the date.
+  pub fn periods_on(&self, date: NaiveDate) -> Vec<(Source<'a>, &'a OpenPeriod)> {
+    match self.templates.iter().find(|(_, t)| t.is_active(date)) {
+      Some((name, template)) => template
+        .schedule
+        .open_periods
+        .iter()
+        .map(|p| (Source::Template { name }, p))
+        .collect(),
+      None => self.weekly_periods(),
+    }
+  }
+
+  /// The schedules that apply on the date: an active template's, or else the
+  /// group's and the user's.
+  fn schedules_on(&self, date: NaiveDate) -> Vec<&'a Schedule> {
+    match self.templates.iter().find(|(_, t)| t.is_active(date)) {
+      Some((_, template)) => vec![&template.schedule],
+      None => self.group.iter().map(|(_, group)| &group.schedule).chain(Some(self.schedule)).collect(),
+    }
+  }
+
+  /// The cron rules that apply on the date.
+  pub fn cron_rules_on(&self, date: NaiveDate) -> Vec<&'a CronRule> {
+    self.schedules_on(date).into_iter().flat_map(|s| &s.cron_rules).collect()
+  }
+
+  /// The blackout periods that apply on the date.
+  pub fn blackouts_on(&self, date: NaiveDate) -> Vec<&'a OpenPeriod> {
+    self.schedules_on(date).into_iter().flat_map(|s| &s.blackout_periods).collect()
+  }
+}
+
+/// Whether a transition locks or unlocks the user.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TransitionKind {
+  Lock,
+  Unlock,
+}
+
+/// A time the schedule changes between open and locked, and the period that
+/// ends or starts then.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Transition {
+  pub at: DateTime<Local>,
+  pub kind: TransitionKind,
+  pub period: OpenPeriod,
+}
+
+/// A stretch of time the schedule has the user locked or unlocked.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Interval {
+  pub start: DateTime<Local>,
+  pub end: DateTime<Local>,
+  pub locked: bool,
+  /// The note of the period that unlocks it.
+  pub note: Option<String>,
+}
+
+/// A single occurrence of an open period.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Occurrence {
+  pub start: DateTime<Local>,
+  pub end: DateTime<Local>,
+  pub period: OpenPeriod,
+}
+
+impl Occurrence {
+  pub fn contains(&self, time: DateTime<Local>) -> bool {
+    self.start <= time && time < self.end
+  }
+
+  pub fn duration(&self) -> chrono::Duration {
+    self.end - self.start
+  }
+}
+
+pub fn minute_of_week(instant: &Instant) -> i64 {
+  (instant.weekday as i64 * 24 + instant.hour as i64) * 60 + instant.minute as i64
+}
+
+/// How long a period lasts, wrapping around the end of the week if it ends
+/// before it starts.
+pub fn period_length(period: &OpenPeriod) -> chrono::Duration {
+  let minutes = (minute_of_week(&period.end) - minute_of_week(&period.start)).rem_euclid(MINUTES_PER_WEEK);
+
+  chrono::Duration::minutes(minutes)
+}
+
+fn weekday(date: NaiveDate) -> u8 {
+  date.weekday().num_days_from_sunday() as u8
+}
+
+fn at_time(date: NaiveDate, time: &TimeOfDay) -> Option<NaiveDateTime> {
+  NaiveTime::from_hms_opt(time.hour.into(), time.minute.into(), 0).map(|t| date.and_time(t))
+}
+
+/// Returns the occurrence of the period starting on the given date, or None if
+/// the period doesn't start on that day of the week or its times are invalid.
+fn occurrence_on(zone: Zone, date: NaiveDate, period: &OpenPeriod) -> Option<Occurrence> {
+  if weekday(date) != period.start.weekday {
+    return None;
+  }
+
+  let time = NaiveTime::from_hms_opt(period.start.hour.into(), period.start.minute.into(), 0)?;
+  let start = date.and_time(time);
+  let end = start + period_length(period);
+
+  Some(Occurrence {
+    start: zone.localize(&start)?,
+    end: zone.localize(&end)?,
+    period: period.clone(),
+  })
+}
+
+fn naive_occurrence(zone: Zone, start: NaiveDateTime, end: NaiveDateTime, note: &str) -> Option<Occurrence> {
+  let instant = |time: NaiveDateTime| Instant {
+    weekday: weekday(time.date()),
+    hour: time.hour() as u8,
+    minute: time.minute() as u8,
+  };
+
+  Some(Occurrence {
+    start: zone.localize(&start)?,
+    end: zone.localize(&end)?,
+    period: OpenPeriod {
+      start: instant(start),
+      end: instant(end),
+      note: note.to_owned(),
+    },
+  })
+}
+
+/// Returns the occurrence of an exception's period on the given date.
+fn day_occurrence(zone: Zone, date: NaiveDate, period: &DayPeriod) -> Option<Occurrence> {
+  let start = at_time(date, &period.start)?;
+  let mut end = at_time(date, &period.end)?;
+  if end <= start {
+    end += chrono::Duration::days(1);
+  }
+
+  naive_occurrence(zone, start, end, &period.note)
+}
+
+fn whole_day(date: NaiveDate) -> (NaiveDateTime, NaiveDateTime) {
+  let start = date.and_hms(0, 0, 0);
+
+  (start, start + chrono::Duration::days(1))
+}
+
+/// Returns the occurrences of the cron rules starting on the given date.
+fn cron_occurrences_on(policy: &Policy, date: NaiveDate) -> Vec<Occurrence> {
+  let mut occurrences = Vec::new();
+  for rule in policy.cron_rules_on(date) {
+    for start in rule.cron.times_on(date) {
+      let end = start + chrono::Duration::minutes(rule.duration_minutes.into());
+      occurrences.extend(naive_occurrence(policy.zone, start, end, &rule.note));
+    }
+  }
+
+  occurrences
+}
+
+/// Returns the occurrences starting on the date from the usual schedule, if
+/// no exception replaces it, and from the exceptions.
+fn occurrences_on(policy: &Policy, date: NaiveDate) -> (Vec<Occurrence>, Vec<Occurrence>) {
+  let exceptions: Vec<&ScheduleException> = policy.exceptions.iter().filter(|e| e.applies_to(date)).collect();
+  let replaced = exceptions
+    .iter()
+    .any(|e| matches!(e.kind, ExceptionKind::Replace { .. } | ExceptionKind::Locked));
+
+  let usual: Vec<Occurrence> = match replaced {
+    true => Vec::new(),
+    false => policy
+      .periods_on(date)
+      .into_iter()
+      .filter_map(|(_, p)| occurrence_on(policy.zone, date, p))
+      .chain(cron_occurrences_on(policy, date))
+      .collect(),
+  };
+
+  let mut occurrences = Vec::new();
+  for exception in exceptions {
+    match &exception.kind {
+      ExceptionKind::Replace { periods } | ExceptionKind::Add { periods } => {
+        occurrences.extend(periods.iter().filter_map(|p| day_occurrence(policy.zone, date, p)))
+      }
+      ExceptionKind::Open => {
+        let (start, end) = whole_day(date);
+        occurrences.extend(naive_occurrence(policy.zone, start, end, &exception.note));
+      }
+      ExceptionKind::Locked => {}
+    }
+  }
+
+  (usual, occurrences)
+}
+
+/// Removes the time between start and end from the occurrences, splitting any
+/// that straddle it.
+fn subtract(occurrences: Vec<Occurrence>, start: DateTime<Local>, end: DateTime<Local>) -> Vec<Occurrence> {
+  let mut result = Vec::new();
+  for occurrence in occurrences {
+    if occurrence.end <= start || occurrence.start >= end {
+      result.push(occurrence);
+      continue;
+    }
+    if occurrence.start < start {
+      result.push(Occurrence {
+        end: start,
+        ..occurrence.clone()
+      });
+    }
+    if occurrence.end > end {
+      result.push(Occurrence { start: end, ..occurrence });
+    }
+  }
+
+  result
+}
+
+/// Returns the occurrences of the policy's periods that start on any date from
+/// first to last inclusive, in order of when they start.
+fn occurrences_starting(policy: &Policy, first: NaiveDate, last: NaiveDate) -> Vec<Occurrence> {
+  expand(policy, first, last).0
+}
+
+/// A time that's deliberately locked, from its start to its end.
+type Closed = (DateTime<Local>, DateTime<Local>);
+
+/// Returns the occurrences starting from first to last, as above, and the
+/// times blackouts and locked days deliberately lock.
+fn expand(policy: &Policy, first: NaiveDate, last: NaiveDate) -> (Vec<Occurrence>, Vec<Closed>) {
+  let mut closed = Vec::new();
+  let mut usual = Vec::new();
+  let mut occurrences = Vec::new();
+  let mut date = first;
+  while date <= last {
+    let (usual_on, exceptional_on) = occurrences_on(policy, date);
+    usual.extend(usual_on);
+    occurrences.extend(exceptional_on);
+    date = date.succ();
+  }
+  usual.extend(
+    policy
+      .feed
+      .iter()
+      .filter(|o| (first..=last).contains(&policy.zone.date(o.start)))
+      .cloned(),
+  );
+
+  // Occurrences can run up to a week past the last day they start on, and
+  // blackouts up to a week past theirs.
+  let horizon = last + chrono::Duration::weeks(1);
+  let mut date = first - chrono::Duration::weeks(1);
+  while date <= horizon {
+    for blackout in policy.blackouts_on(date) {
+      if let Some(blackout) = occurrence_on(policy.zone, date, blackout) {
+        usual = subtract(usual, blackout.start, blackout.end);
+        closed.push((blackout.start, blackout.end));
+      }
+    }
+    date = date.succ();
+  }
+  occurrences.extend(usual);
+
+  // Locked days also cut short anything running into them from another day.
+  for exception in policy.exceptions {
+    if exception.kind != ExceptionKind::Locked {
+      continue;
+    }
+    let mut date = exception.start_date.max(first);
+    while date <= exception.last_date().min(horizon) {
+      let (start, end) = whole_day(date);
+      if let (Some(start), Some(end)) = (policy.zone.localize(&start), policy.zone.localize(&end)) {
+        occurrences = subtract(occurrences, start, end);
+        closed.push((start, end));
+      }
+      date = date.succ();
+    }
+  }
+
+  occurrences.sort_by_key(|o| o.start);
+
+  (occurrences, closed)
+}
+
+/// A continuous stretch of open time, made of occurrences that overlap, meet
+/// or are too close together to be worth locking between.
+#[derive(Debug, Clone, PartialEq)]
+struct Stretch {
+  start: DateTime<Local>,
+  end: DateTime<Local>,
+  /// The period the stretch starts with, and the one it ends with.
+  first: OpenPeriod,
+  last: OpenPeriod,
+}
+
+impl Stretch {
+  fn contains(&self, time: DateTime<Local>) -> bool {
+    self.start <= time && time < self.end
+  }
+}
+
+/// Merges the occurrences starting from first to last into stretches, so the
+/// lock doesn't flap where periods meet. Gaps shorter than the user's minimum
+/// locked time are bridged, unless a blackout or locked day falls in them, and
+/// then stretches shorter than the minimum unlocked time are dropped.
+fn stretches(policy: &Policy, first: NaiveDate, last: NaiveDate) -> Vec<Stretch> {
+  let (occurrences, closed) = expand(policy, first, last);
+  let min_locked = chrono::Duration::minutes(policy.hysteresis.min_locked_minutes.into());
+  let min_unlocked = chrono::Duration::minutes(policy.hysteresis.min_unlocked_minutes.into());
+
+  let mut stretches: Vec<Stretch> = Vec::new();
+  for occurrence in occurrences {
+    match stretches.last_mut() {
+      Some(stretch)
+        if occurrence.start <= stretch.end
+          || (occurrence.start - stretch.end < min_locked
+            && !closed.iter().any(|(start, end)| *start < occurrence.start && *end > stretch.end)) =>
+      {
+        if occurrence.end > stretch.end {
+          stretch.end = occurrence.end;
+          stretch.last = occurrence.period;
+        }
+      }
+      _ => stretches.push(Stretch {
+        start: occurrence.start,
+        end: occurrence.end,
+        first: occurrence.period.clone(),
+        last: occurrence.period,
+      }),
+    }
+  }
+  stretches.retain(|s| s.end - s.start >= min_unlocked);
+
+  stretches
+}
+
+/// Returns every occurrence of the policy's open periods that overlaps the
+/// time between from and to.
+pub fn occurrences(policy: &Policy, from: DateTime<Local>, to: DateTime<Local>) -> Vec<Occurrence> {
+  // No period lasts longer than a week, so anything overlapping must have
+  // started at most a week before.
+  let first = policy.zone.date(from - chrono::Duration::weeks(1));
+
+  occurrences_starting(policy, first, policy.zone.date(to))
+    .into_iter()
+    .filter(|o| o.end > from && o.start < to)
+    .collect()
+}
+
+/// Given a policy, returns the open time containing now. Periods that overlap
+/// or meet count as one, starting with the first of them.
+pub fn find_max_open_period(now: DateTime<Local>, policy: &Policy) -> Option<Occurrence> {
+  let first = policy.zone.date(now - chrono::Duration::weeks(1));
+  let last = policy.zone.date(now) + chrono::Duration::weeks(1);
+
+  stretches(policy, first, last)
+    .into_iter()
+    .find(|s| s.contains(now))
+    .map(|s| Occurrence {
+      start: s.start,
+      end: s.end,
+      period: s.first,
+    })
+}
+
+/// Given a policy, returns the next time the schedule unlocks after now.
+pub fn find_next_unlock(now: DateTime<Local>, policy: &Policy) -> Option<DateTime<Local>> {
+  // Start from a week back, so a stretch that's already under way isn't
+  // mistaken for one that starts later.
+  let first = policy.zone.date(now - chrono::Duration::weeks(1));
+
+  stretches(policy, first, policy.zone.date(now) + chrono::Duration::weeks(1))
+    .into_iter()
+    .map(|s| s.start)
+    .find(|start| *start > now)
+}
+
+/// Given a policy, returns the next time after now that the schedule locks or
+/// unlocks. Open periods that overlap or meet count as one long period, so
+/// the lock comes at the end of the last of them. Looks up to a week ahead.
+pub fn next_transition(now: DateTime<Local>, policy: &Policy) -> Option<Transition> {
+  let today = policy.zone.date(now);
+  let first = policy.zone.date(now - chrono::Duration::weeks(1));
+  let stretches = stretches(policy, first, today + chrono::Duration::weeks(1));
+
+  match stretches.iter().find(|s| s.contains(now)) {
+    Some(stretch) => Some(Transition {
+      at: stretch.end,
+      kind: TransitionKind::Lock,
+      period: stretch.last.clone(),
+    }),
+    None => stretches.iter().find(|s| s.start > now).map(|s| Transition {
+      at: s.start,
+      kind: TransitionKind::Unlock,
+      period: s.first.clone(),
+    }),
+  }
+}
+
+/// Splits the time between from and to into locked and unlocked intervals.
+/// Where periods overlap, the one that started first labels the interval.
+pub fn timeline(policy: &Policy, from: DateTime<Local>, to: DateTime<Local>) -> Vec<Interval> {
+  let occurrences = occurrences(policy, from, to);
+  let first = policy.zone.date(from - chrono::Duration::weeks(1));
+  let stretches = stretches(policy, first, policy.zone.date(to) + chrono::Duration::weeks(1));
+  let mut times = vec![from, to];
+  for (start, end) in occurrences
+    .iter()
+    .map(|o| (o.start, o.end))
+    .chain(stretches.iter().map(|s| (s.start, s.end)))
+  {
+    times.push(start.max(from).min(to));
+    times.push(end.max(from).min(to));
+  }
+  times.sort();
+  times.dedup();
+
+  let mut intervals: Vec<Interval> = Vec::new();
+  for pair in times.windows(2) {
+    let (start, end) = (pair[0], pair[1]);
+    // A bridged gap takes the note of the stretch it's in.
+    let note = stretches.iter().find(|s| s.contains(start)).map(|s| {
+      match occurrences.iter().find(|o| o.contains(start)) {
+        Some(occurrence) => occurrence.period.note.clone(),
+        None => s.first.note.clone(),
+      }
+    });
+    match intervals.last_mut() {
+      Some(last) if last.note == note => last.end = end,
+      _ => intervals.push(Interval {
+        start,
+        end,
+        locked: note.is_none(),
+        note,
+      }),
+    }
+  }
+
+  intervals
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::config::DateRange;
+  use chrono_tz::America::New_York;
+
+  fn create_schedule(start: (u8, u8, u8), end: (u8, u8, u8)) -> Schedule {
+    Schedule {
+      open_periods: vec![OpenPeriod {
+        start: Instant {
+          weekday: start.0,
+          hour: start.1,
+          minute: start.2,
+        },
+        end: Instant {
+          weekday: end.0,
+          hour: end.1,
+          minute: end.2,
+        },
+        note: "".to_owned(),
+      }],
+      cron_rules: vec![],
+      blackout_periods: vec![],
+    }
+  }
+
+  // 2020-01-01 is a Wednesday, 2020-01-04 a Saturday and 2020-01-05 a Sunday.
+  fn at(day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+    Local.ymd(2020, 1, day).and_hms(hour, minute, 0)
+  }
+
+  fn policy(schedule: &Schedule) -> Policy<'_> {
+    Policy {
+      zone: Zone::Local,
+      schedule,
+      group: None,
+      removed: &[],
+      templates: vec![],
+      exceptions: &[],
+      feed: &[],
+      hysteresis: Hysteresis::default(),
+    }
+  }
+
+  fn is_open(schedule: &Schedule, now: DateTime<Local>) -> bool {
+    find_max_open_period(now, &policy(schedule)).is_some()
+  }
+
+  fn exception(start: u32, end: Option<u32>, kind: ExceptionKind) -> ScheduleException {
+    ScheduleException {
+      start_date: NaiveDate::from_ymd(2020, 1, start),
+      end_date: end.map(|d| NaiveDate::from_ymd(2020, 1, d)),
+      kind,
+      note: "".to_owned(),
+    }
+  }
+
+  fn day_period(start: (u8, u8), end: (u8, u8)) -> DayPeriod {
+    DayPeriod {
+      start: TimeOfDay {
+        hour: start.0,
+        minute: start.1,
+      },
+      end: TimeOfDay {
+        hour: end.0,
+        minute: end.1,
+      },
+      note: "".to_owned(),
+    }
+  }
+
+  #[test]
+  fn test_find_open_period() {
+    let schedule = create_schedule((3, 14, 45), (3, 15, 0));
+
+    assert_eq!(false, is_open(&schedule, at(1, 14, 30)));
+    assert_eq!(true, is_open(&schedule, at(1, 14, 45)));
+    assert_eq!(true, is_open(&schedule, at(1, 14, 59)));
+    assert_eq!(false, is_open(&schedule, at(1, 15, 0)));
+  }
+
+  #[test]
+  fn test_period_spanning_midnight() {
+    // Wednesday 22:00 to Thursday 07:00
+    let schedule = create_schedule((3, 22, 0), (4, 7, 0));
+
+    assert!(!is_open(&schedule, at(1, 21, 59)));
+    assert!(is_open(&schedule, at(1, 22, 0)));
+    assert!(is_open(&schedule, at(1, 23, 59)));
+    assert!(is_open(&schedule, at(2, 0, 0)));
+    assert!(is_open(&schedule, at(2, 6, 59)));
+    assert!(!is_open(&schedule, at(2, 7, 0)));
+  }
+
+  #[test]
+  fn test_period_spanning_end_of_week() {
+    // Saturday 22:00 to Sunday 01:00
+    let schedule = create_schedule((6, 22, 0), (0, 1, 0));
+
+    assert!(!is_open(&schedule, at(4, 21, 59)));
+    assert!(is_open(&schedule, at(4, 22, 0)));
+    assert!(is_open(&schedule, at(4, 23, 59)));
+    // Sunday belongs to the previous week's occurrence.
+    assert!(is_open(&schedule, at(5, 0, 0)));
+    assert!(is_open(&schedule, at(5, 0, 30)));
+    assert!(!is_open(&schedule, at(5, 1, 0)));
+
+    let period = find_max_open_period(at(5, 0, 30), &policy(&schedule)).unwrap();
+    assert_eq!(at(4, 22, 0), period.start);
+    assert_eq!(at(5, 1, 0), period.end);
+  }
+
+  #[test]
+  fn test_period_ending_before_start_on_same_day() {
+    // Monday 15:00 until Monday 14:00 the following week.
+    let schedule = create_schedule((1, 15, 0), (1, 14, 0));
+
+    assert_eq!(chrono::Duration::minutes(MINUTES_PER_WEEK - 60), period_length(&schedule.open_periods[0]));
+    assert!(is_open(&schedule, at(5, 12, 0)));
+    assert!(!is_open(&schedule, at(6, 14, 30)));
+    assert!(is_open(&schedule, at(6, 15, 0)));
+  }
+
+  #[test]
+  fn test_find_longest_period() {
+    let mut schedule = create_schedule((3, 14, 0), (3, 15, 0));
+    schedule.open_periods.extend(create_schedule((3, 13, 0), (3, 16, 0)).open_periods);
+
+    let period = find_max_open_period(at(1, 14, 30), &policy(&schedule)).unwrap();
+    assert_eq!(at(1, 13, 0), period.start);
+  }
+
+  #[test]
+  fn test_occurrences() {
+    let schedule = create_schedule((6, 22, 0), (0, 1, 0));
+
+    // The occurrence from the Saturday before overlaps the start of the week.
+    let found = occurrences(&policy(&schedule), at(5, 0, 0), at(11, 22, 0));
+    assert_eq!(1, found.len());
+    assert_eq!(at(4, 22, 0), found[0].start);
+
+    let found = occurrences(&policy(&schedule), at(5, 0, 0), at(12, 0, 0));
+    assert_eq!(2, found.len());
+    assert_eq!(at(11, 22, 0), found[1].start);
+  }
+
+  #[test]
+  fn test_find_next_unlock() {
+    // Wednesday 14:45
+    let schedule = create_schedule((3, 14, 45), (3, 15, 0));
+
+    assert_eq!(Some(at(1, 14, 45)), find_next_unlock(at(1, 14, 30), &policy(&schedule)));
+
+    // Once it's started, the next one is a week away.
+    assert_eq!(Some(at(8, 14, 45)), find_next_unlock(at(1, 14, 50), &policy(&schedule)));
+
+    // Across the end of the week.
+    let schedule = create_schedule((0, 9, 0), (0, 12, 0));
+    assert_eq!(Some(at(5, 9, 0)), find_next_unlock(at(4, 23, 0), &policy(&schedule)));
+
+    let empty = Schedule {
+      open_periods: vec![],
+      cron_rules: vec![],
+      blackout_periods: vec![],
+    };
+    assert_eq!(None, find_next_unlock(at(1, 14, 50), &policy(&empty)));
+  }
+
+  #[test]
+  fn test_find_next_unlock_across_midnight() -> anyhow::Result<()> {
+    let schedule = Schedule {
+      open_periods: crate::dsl::parse("Wed 22:00-1:00; Thu 01:00-03:00")?,
+      cron_rules: vec![],
+      blackout_periods: vec![],
+    };
+
+    // Thursday's period carries on from Wednesday's, so it isn't an unlock.
+    assert_eq!(Some(at(8, 22, 0)), find_next_unlock(at(2, 0, 30), &policy(&schedule)));
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_next_transition() {
+    // Wednesday 15:00 to 17:00, then 17:00 to 18:00 and Friday 16:00 to 17:00.
+    let mut schedule = create_schedule((3, 15, 0), (3, 17, 0));
+    schedule.open_periods.push(create_schedule((3, 17, 0), (3, 18, 0)).open_periods.remove(0));
+    schedule.open_periods.push(create_schedule((5, 16, 0), (5, 17, 0)).open_periods.remove(0));
+    let weekly = policy(&schedule);
+
+    let transition = next_transition(at(1, 12, 0), &weekly).unwrap();
+    assert_eq!((at(1, 15, 0), TransitionKind::Unlock), (transition.at, transition.kind));
+    assert_eq!(schedule.open_periods[0], transition.period);
+
+    // The periods meet, so it doesn't lock until the second one ends.
+    let transition = next_transition(at(1, 16, 0), &weekly).unwrap();
+    assert_eq!((at(1, 18, 0), TransitionKind::Lock), (transition.at, transition.kind));
+    assert_eq!(schedule.open_periods[1], transition.period);
+
+    let transition = next_transition(at(1, 18, 0), &weekly).unwrap();
+    assert_eq!((at(3, 16, 0), TransitionKind::Unlock), (transition.at, transition.kind));
+
+    let empty = Schedule {
+      open_periods: vec![],
+      cron_rules: vec![],
+      blackout_periods: vec![],
+    };
+    assert_eq!(None, next_transition(at(1, 12, 0), &policy(&empty)));
+  }
+
+  #[test]
+  fn test_timeline() {
+    // Wednesday 15:00 to 17:00 and 16:00 to 18:00, but Thursday is locked.
+    let mut schedule = create_schedule((3, 15, 0), (3, 17, 0));
+    schedule.open_periods[0].note = "homework".to_owned();
+    schedule.open_periods.push(create_schedule((3, 16, 0), (3, 18, 0)).open_periods.remove(0));
+    schedule.open_periods.push(create_schedule((4, 15, 0), (4, 17, 0)).open_periods.remove(0));
+    let exceptions = [exception(2, None, ExceptionKind::Locked)];
+    let policy = Policy {
+      exceptions: &exceptions,
+      ..policy(&schedule)
+    };
+
+    let interval = |start, end, note: Option<&str>| Interval {
+      start,
+      end,
+      locked: note.is_none(),
+      note: note.map(str::to_owned),
+    };
+    assert_eq!(
+      vec![
+        interval(at(1, 12, 0), at(1, 15, 0), None),
+        interval(at(1, 15, 0), at(1, 17, 0), Some("homework")),
+        interval(at(1, 17, 0), at(1, 18, 0), Some("")),
+        interval(at(1, 18, 0), at(3, 0, 0), None),
+      ],
+      timeline(&policy, at(1, 12, 0), at(3, 0, 0))
+    );
+  }
+
+  #[test]
+  fn test_replace_exception() {
+    let schedule = create_schedule((3, 15, 0), (3, 16, 0));
+    let exceptions = [exception(
+      1,
+      None,
+      ExceptionKind::Replace {
+        periods: vec![day_period((9, 0), (12, 0))],
+      },
+    )];
+    let policy = Policy {
+      exceptions: &exceptions,
+      ..policy(&schedule)
+    };
+
+    assert!(find_max_open_period(at(1, 10, 0), &policy).is_some());
+    assert!(find_max_open_period(at(1, 15, 30), &policy).is_none());
+    // The following week is back to normal.
+    assert!(find_max_open_period(at(8, 10, 0), &policy).is_none());
+    assert!(find_max_open_period(at(8, 15, 30), &policy).is_some());
+  }
+
+  #[test]
+  fn test_add_exception() {
+    let schedule = create_schedule((3, 15, 0), (3, 16, 0));
+    let exceptions = [exception(
+      1,
+      None,
+      ExceptionKind::Add {
+        periods: vec![day_period((22, 0), (1, 0))],
+      },
+    )];
+    let policy = Policy {
+      exceptions: &exceptions,
+      ..policy(&schedule)
+    };
+
+    assert!(find_max_open_period(at(1, 15, 30), &policy).is_some());
+    assert!(find_max_open_period(at(1, 23, 0), &policy).is_some());
+    assert!(find_max_open_period(at(2, 0, 30), &policy).is_some());
+    assert!(find_max_open_period(at(2, 1, 0), &policy).is_none());
+  }
+
+  #[test]
+  fn test_locked_exception() {
+    // Wednesday 22:00 to Thursday 07:00
+    let schedule = create_schedule((3, 22, 0), (4, 7, 0));
+    let exceptions = [exception(2, Some(3), ExceptionKind::Locked)];
+    let policy = Policy {
+      exceptions: &exceptions,
+      ..policy(&schedule)
+    };
+
+    // Wednesday's period is cut short at midnight by Thursday being locked.
+    assert!(find_max_open_period(at(1, 23, 0), &policy).is_some());
+    assert_eq!(at(2, 0, 0), find_max_open_period(at(1, 23, 0), &policy).unwrap().end);
+    assert!(find_max_open_period(at(2, 1, 0), &policy).is_none());
+    assert_eq!(Some(at(8, 22, 0)), find_next_unlock(at(2, 1, 0), &policy));
+  }
+
+  #[test]
+  fn test_open_exception() {
+    let schedule = create_schedule((3, 15, 0), (3, 16, 0));
+    let exceptions = [exception(4, Some(5), ExceptionKind::Open)];
+    let policy = Policy {
+      exceptions: &exceptions,
+      ..policy(&schedule)
+    };
+
+    assert!(find_max_open_period(at(3, 23, 59), &policy).is_none());
+    assert!(find_max_open_period(at(4, 0, 0), &policy).is_some());
+    assert!(find_max_open_period(at(5, 23, 59), &policy).is_some());
+    assert!(find_max_open_period(at(6, 0, 0), &policy).is_none());
+  }
+
+  #[test]
+  fn test_feed() {
+    // Wednesday 15:00 to 16:00, and the feed opens Saturday 10:00 to 12:00.
+    let schedule = create_schedule((3, 15, 0), (3, 16, 0));
+    let feed = [Occurrence {
+      start: at(4, 10, 0),
+      end: at(4, 12, 0),
+      period: create_schedule((6, 10, 0), (6, 12, 0)).open_periods[0].clone(),
+    }];
+    let exceptions = [exception(11, None, ExceptionKind::Locked)];
+    let policy = Policy {
+      exceptions: &exceptions,
+      feed: &feed,
+      ..policy(&schedule)
+    };
+
+    assert_eq!(Some(at(4, 10, 0)), find_next_unlock(at(2, 0, 0), &policy));
+    assert_eq!(at(4, 12, 0), find_max_open_period(at(4, 11, 0), &policy).unwrap().end);
+    assert!(find_max_open_period(at(4, 12, 0), &policy).is_none());
+
+    // A locked day wins over the feed.
+    let feed = [Occurrence {
+      start: at(11, 10, 0),
+      end: at(11, 12, 0),
+      ..feed[0].clone()
+    }];
+    let policy = policy.with_feed(&feed);
+    assert!(find_max_open_period(at(11, 11, 0), &policy).is_none());
+  }
+
+  #[test]
+  fn test_cron_rules() -> anyhow::Result<()> {
+    let schedule = Schedule {
+      open_periods: vec![],
+      cron_rules: vec![CronRule {
+        cron: crate::cron::Cron::parse("0 7 * * Mon-Fri")?,
+        duration_minutes: 45,
+        note: "Breakfast".to_owned(),
+      }],
+      blackout_periods: vec![],
+    };
+    let policy = policy(&schedule);
+
+    let open = find_max_open_period(at(1, 7, 30), &policy).unwrap();
+    assert_eq!((at(1, 7, 0), at(1, 7, 45)), (open.start, open.end));
+    assert_eq!("Breakfast", open.period.note);
+    assert!(find_max_open_period(at(1, 7, 45), &policy).is_none());
+    // Friday is the 3rd, so the next is Monday the 6th.
+    assert_eq!(Some(at(6, 7, 0)), find_next_unlock(at(3, 8, 0), &policy));
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_adjacent_periods() -> anyhow::Result<()> {
+    let schedule = Schedule {
+      open_periods: crate::dsl::parse("Wed 15:00-16:00; Wed 16:00-17:00")?,
+      cron_rules: vec![],
+      blackout_periods: vec![],
+    };
+    let policy = policy(&schedule);
+
+    // 16:00 isn't the end of anything.
+    for now in [at(1, 15, 30), at(1, 16, 0), at(1, 16, 30)].iter() {
+      let open = find_max_open_period(*now, &policy).unwrap();
+      assert_eq!((at(1, 15, 0), at(1, 17, 0)), (open.start, open.end));
+    }
+    let next = next_transition(at(1, 15, 59), &policy).unwrap();
+    assert_eq!((at(1, 17, 0), TransitionKind::Lock), (next.at, next.kind));
+    assert_eq!(Some(at(8, 15, 0)), find_next_unlock(at(1, 15, 30), &policy));
+    assert_eq!(
+      vec![(at(1, 14, 0), true), (at(1, 15, 0), false), (at(1, 17, 0), true)],
+      timeline(&policy, at(1, 14, 0), at(1, 18, 0))
+        .iter()
+        .map(|i| (i.start, i.locked))
+        .collect::<Vec<_>>()
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_hysteresis() -> anyhow::Result<()> {
+    let schedule = Schedule {
+      open_periods: crate::dsl::parse("Wed 15:00-16:00; Wed 16:05-17:00; Wed 17:30-17:35; Wed 19:00-21:00")?,
+      cron_rules: vec![],
+      blackout_periods: crate::dsl::parse("Wed 20:00-20:03")?,
+    };
+    let policy = Policy {
+      hysteresis: Hysteresis {
+        min_locked_minutes: 10,
+        min_unlocked_minutes: 10,
+      },
+      ..policy(&schedule)
+    };
+
+    // Five minutes locked isn't worth it.
+    let open = find_max_open_period(at(1, 16, 2), &policy).unwrap();
+    assert_eq!((at(1, 15, 0), at(1, 17, 0)), (open.start, open.end));
+    let next = next_transition(at(1, 15, 30), &policy).unwrap();
+    assert_eq!((at(1, 17, 0), TransitionKind::Lock), (next.at, next.kind));
+
+    // Nor is five minutes unlocked.
+    assert!(find_max_open_period(at(1, 17, 32), &policy).is_none());
+    assert_eq!(Some(at(1, 19, 0)), find_next_unlock(at(1, 17, 0), &policy));
+
+    // But a blackout still locks, however short.
+    assert!(find_max_open_period(at(1, 20, 1), &policy).is_none());
+    let next = next_transition(at(1, 19, 30), &policy).unwrap();
+    assert_eq!((at(1, 20, 0), TransitionKind::Lock), (next.at, next.kind));
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_blackouts() -> anyhow::Result<()> {
+    let schedule = Schedule {
+      open_periods: crate::dsl::parse("Sat 09:00-21:00")?,
+      cron_rules: vec![],
+      blackout_periods: crate::dsl::parse("Fri 23:00-Sat 10:00; Sat 18:00-19:00")?,
+    };
+    let policy = policy(&schedule);
+
+    // Saturday is the 4th.
+    assert!(find_max_open_period(at(4, 9, 30), &policy).is_none());
+    let open = find_max_open_period(at(4, 12, 0), &policy).unwrap();
+    assert_eq!((at(4, 10, 0), at(4, 18, 0)), (open.start, open.end));
+    assert!(find_max_open_period(at(4, 18, 30), &policy).is_none());
+
+    let next = next_transition(at(4, 12, 0), &policy).unwrap();
+    assert_eq!((at(4, 18, 0), TransitionKind::Lock), (next.at, next.kind));
+    let next = next_transition(at(4, 18, 30), &policy).unwrap();
+    assert_eq!((at(4, 19, 0), TransitionKind::Unlock), (next.at, next.kind));
+
+    // Dated exceptions aren't blacked out.
+    let exceptions = [ScheduleException {
+      start_date: NaiveDate::from_ymd(2020, 1, 4),
+      end_date: None,
+      kind: ExceptionKind::Open,
+      note: "Birthday".to_owned(),
+    }];
+    let birthday = Policy {
+      exceptions: &exceptions,
+      ..policy
+    };
+    assert!(find_max_open_period(at(4, 18, 30), &birthday).is_some());
+
+    // Nor are periods added for the day, even where they overlap a blackout.
+    let exceptions = [exception(
+      4,
+      None,
+      ExceptionKind::Add {
+        periods: vec![day_period((17, 30), (19, 30))],
+      },
+    )];
+    let party = Policy {
+      exceptions: &exceptions,
+      ..birthday
+    };
+    // It bridges the blackout, so the day is open from 10:00 right through.
+    let open = find_max_open_period(at(4, 18, 30), &party).unwrap();
+    assert_eq!((at(4, 10, 0), at(4, 21, 0)), (open.start, open.end));
+    // The usual period is still blacked out around it.
+    assert!(find_max_open_period(at(4, 9, 30), &party).is_none());
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_templates() {
+    let schedule = create_schedule((6, 15, 0), (6, 16, 0));
+    let holidays = Template {
+      schedule: create_schedule((6, 10, 0), (6, 12, 0)),
+      active: vec![DateRange {
+        start: NaiveDate::from_ymd(2020, 1, 4),
+        end: NaiveDate::from_ymd(2020, 1, 5),
+        yearly: false,
+      }],
+    };
+    let policy = Policy {
+      templates: vec![("holidays", &holidays)],
+      ..policy(&schedule)
+    };
+
+    assert_eq!(Some("holidays"), policy.template_on(NaiveDate::from_ymd(2020, 1, 4)));
+    assert!(find_max_open_period(at(4, 10, 30), &policy).is_some());
+    assert!(find_max_open_period(at(4, 15, 30), &policy).is_none());
+
+    // The week after, the user's own schedule applies again.
+    assert_eq!(None, policy.template_on(NaiveDate::from_ymd(2020, 1, 11)));
+    assert!(find_max_open_period(at(11, 10, 30), &policy).is_none());
+    assert!(find_max_open_period(at(11, 15, 30), &policy).is_some());
+  }
+
+  #[test]
+  fn test_group() {
+    let group = Group {
+      schedule: Schedule {
+        open_periods: vec![
+          create_schedule((6, 10, 0), (6, 12, 0)).open_periods.remove(0),
+          create_schedule((6, 15, 0), (6, 16, 0)).open_periods.remove(0),
+        ],
+        cron_rules: vec![],
+        blackout_periods: vec![],
+      },
+    };
+    let own = create_schedule((6, 18, 0), (6, 19, 0));
+    let removed = create_schedule((6, 10, 0), (6, 12, 0)).open_periods;
+    let policy = Policy {
+      group: Some(("kids", &group)),
+      removed: &removed,
+      ..policy(&own)
+    };
+
+    let sources: Vec<Source> = policy.weekly_periods().into_iter().map(|(s, _)| s).collect();
+    assert_eq!(vec![Source::Group { name: "kids" }, Source::User], sources);
+
+    assert!(find_max_open_period(at(4, 10, 30), &policy).is_none());
+    assert!(find_max_open_period(at(4, 15, 30), &policy).is_some());
+    assert!(find_max_open_period(at(4, 18, 30), &policy).is_some());
+  }
+
+  // In New York in 2021 the clocks went forward from 02:00 to 03:00 on
+  // Sunday March 14th, and back from 02:00 to 01:00 on Sunday November 7th.
+  fn ny(month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+    New_York.ymd(2021, month, day).and_hms(hour, minute, 0).with_timezone(&Local)
+  }
+
+  fn utc(month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+    chrono::Utc.ymd(2021, month, day).and_hms(hour, minute, 0).with_timezone(&Local)
+  }
+
+  fn ny_policy(schedule: &Schedule) -> Policy<'_> {
+    Policy {
+      zone: Zone::Named(New_York),
+      ..policy(schedule)
+    }
+  }
+
+  #[test]
+  fn test_dst_start_in_gap() {
+    // 02:30 doesn't exist, so the period starts at 03:00 EDT.
+    let schedule = create_schedule((0, 2, 30), (0, 4, 0));
+    let policy = ny_policy(&schedule);
+
+    let occurrence = find_max_open_period(ny(3, 14, 3, 30), &policy).unwrap();
+    assert_eq!(utc(3, 14, 7, 0), occurrence.start);
+    assert_eq!(utc(3, 14, 8, 0), occurrence.end);
+    assert_eq!(Some(utc(3, 14, 7, 0)), find_next_unlock(ny(3, 14, 0, 0), &policy));
+  }
+
+  #[test]
+  fn test_dst_spanning_gap() {
+    // Saturday 23:00 to Sunday 04:00 is only four hours long that night.
+    let schedule = create_schedule((6, 23, 0), (0, 4, 0));
+    let policy = ny_policy(&schedule);
+
+    let occurrence = find_max_open_period(ny(3, 14, 1, 0), &policy).unwrap();
+    assert_eq!(chrono::Duration::hours(4), occurrence.duration());
+  }
+
+  #[test]
+  fn test_dst_fold() {
+    // 01:00 to 01:30 happens twice, but the period only runs the first time.
+    let schedule = create_schedule((0, 1, 0), (0, 1, 30));
+    let policy = ny_policy(&schedule);
+
+    assert!(find_max_open_period(utc(11, 7, 5, 15), &policy).is_some());
+    assert!(find_max_open_period(utc(11, 7, 6, 15), &policy).is_none());
+
+    let day = occurrences(&policy, ny(11, 7, 0, 0), ny(11, 8, 0, 0));
+    assert_eq!(1, day.len());
+    assert_eq!(utc(11, 7, 5, 0), day[0].start);
+  }
+
+  #[test]
+  fn test_dst_spanning_fold() {
+    // Saturday 23:00 to Sunday 03:00 is five hours long that night.
+    let schedule = create_schedule((6, 23, 0), (0, 3, 0));
+    let policy = ny_policy(&schedule);
+
+    let occurrence = find_max_open_period(ny(11, 7, 0, 0), &policy).unwrap();
+    assert_eq!(chrono::Duration::hours(5), occurrence.duration());
+  }
+}
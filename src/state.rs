@@ -0,0 +1,399 @@
+//! State that the daemon builds up while running and that has to survive a
+//! restart. Unlike the config, this is written by the daemon itself, so the
+//! runloop and the API share a single copy in memory.
+
+use crate::config::UserConfig;
+use crate::ledger::{self, Ledger};
+use crate::os;
+use crate::schedule::Zone;
+use anyhow::{bail, Result};
+use chrono::{DateTime, Local, NaiveDate};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::{
+  collections::{BTreeMap, BTreeSet, HashMap},
+  fs::{self, File},
+  io::BufReader,
+  path::PathBuf,
+  sync::{Arc, Mutex},
+};
+
+#[cfg(not(debug_assertions))]
+static STATE_FILE: &str = "/usr/local/etc/heimdall/state.json";
+#[cfg(debug_assertions)]
+static STATE_FILE: &str = "/tmp/heimdall/state.json";
+
+/// How many days of usage history to keep.
+const USAGE_HISTORY_DAYS: i64 = 14;
+
+const ROLLOVER_REASON: &str = "Unused time rolled over";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct State {
+  /// Seconds each user has spent logged in and unlocked, per day.
+  #[serde(default)]
+  pub usage: HashMap<String, BTreeMap<NaiveDate, u64>>,
+  /// Each user's bank of extra time.
+  #[serde(default)]
+  pub ledger: HashMap<String, Ledger>,
+  /// The last day whose unused quota has been rolled over, per user.
+  #[serde(default)]
+  pub rolled_over: HashMap<String, NaiveDate>,
+  /// Manual overrides of the schedule, per user.
+  #[serde(default)]
+  pub overrides: HashMap<String, Override>,
+  /// Whether the whole household is paused.
+  #[serde(default)]
+  pub pause: Option<Pause>,
+  /// Flags parents have set for each user's rules to look at.
+  #[serde(default)]
+  pub flags: HashMap<String, BTreeMap<String, Flag>>,
+  /// The config's pause codes that have been used, since each only works
+  /// once.
+  #[serde(default)]
+  pub used_pause_codes: BTreeSet<String>,
+}
+
+/// A flag on a user, e.g. "homework", until it's cleared or runs out.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Flag {
+  pub since: DateTime<Local>,
+  #[serde(default)]
+  pub until: Option<DateTime<Local>>,
+}
+
+impl Flag {
+  pub fn is_set(&self, now: DateTime<Local>) -> bool {
+    self.until.map_or(true, |until| now < until)
+  }
+}
+
+/// Locks every user at once, until resumed or until a given time.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Pause {
+  pub since: DateTime<Local>,
+  #[serde(default)]
+  pub until: Option<DateTime<Local>>,
+  #[serde(default)]
+  pub reason: String,
+}
+
+/// Locks or unlocks a user regardless of their schedule until a given time.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Override {
+  pub locked: bool,
+  pub until: DateTime<Local>,
+  #[serde(default)]
+  pub reason: String,
+}
+
+pub type SharedState = Arc<Mutex<State>>;
+
+impl State {
+  pub fn add_usage(&mut self, user: &str, date: NaiveDate, seconds: u64) {
+    *self
+      .usage
+      .entry(user.to_owned())
+      .or_default()
+      .entry(date)
+      .or_insert(0) += seconds;
+  }
+
+  pub fn get_usage(&self, user: &str, date: NaiveDate) -> u64 {
+    self
+      .usage
+      .get(user)
+      .and_then(|days| days.get(&date))
+      .copied()
+      .unwrap_or(0)
+  }
+
+  pub fn ledger(&mut self, user: &str) -> &mut Ledger {
+    self.ledger.entry(user.to_owned()).or_default()
+  }
+
+  /// The seconds in the user's time bank that can be spent now.
+  pub fn get_balance(&self, user: &str, now: DateTime<Local>) -> u64 {
+    self.ledger.get(user).map_or(0, |ledger| ledger.balance(now))
+  }
+
+  /// The user's override, if there's one in effect at `now`.
+  pub fn get_override(&self, user: &str, now: DateTime<Local>) -> Option<&Override> {
+    self.overrides.get(user).filter(|o| now < o.until)
+  }
+
+  /// The household pause, if it's in effect at `now`.
+  pub fn get_pause(&self, now: DateTime<Local>) -> Option<&Pause> {
+    self.pause.as_ref().filter(|p| p.until.map_or(true, |until| now < until))
+  }
+
+  /// Forgets the pause if it's run out. Returns whether it had.
+  pub fn prune_pause(&mut self, now: DateTime<Local>) -> bool {
+    let expired = self.pause.is_some() && self.get_pause(now).is_none();
+    if expired {
+      self.pause = None;
+    }
+    expired
+  }
+
+  /// Forgets overrides that have run out. Returns whether there were any.
+  pub fn prune_overrides(&mut self, now: DateTime<Local>) -> bool {
+    let before = self.overrides.len();
+    self.overrides.retain(|_, o| now < o.until);
+    self.overrides.len() != before
+  }
+
+  /// Marks a pause code as used. Returns false if it already was.
+  pub fn use_pause_code(&mut self, code: &str) -> bool {
+    self.used_pause_codes.insert(code.to_owned())
+  }
+
+  pub fn set_flag(&mut self, user: &str, flag: &str, now: DateTime<Local>, until: Option<DateTime<Local>>) -> Flag {
+    let set = Flag { since: now, until };
+    self.flags.entry(user.to_owned()).or_default().insert(flag.to_owned(), set.clone());
+    set
+  }
+
+  /// Clears the flag. Returns whether it was set.
+  pub fn clear_flag(&mut self, user: &str, flag: &str) -> bool {
+    self.flags.get_mut(user).map_or(false, |flags| flags.remove(flag).is_some())
+  }
+
+  /// The user's flags that are set at `now`, by name.
+  pub fn get_flags(&self, user: &str, now: DateTime<Local>) -> Vec<&str> {
+    self.flags.get(user).map_or_else(Vec::new, |flags| {
+      flags
+        .iter()
+        .filter(|(_, flag)| flag.is_set(now))
+        .map(|(name, _)| name.as_str())
+        .collect()
+    })
+  }
+
+  /// Forgets flags that have run out. Returns whether there were any.
+  pub fn prune_flags(&mut self, now: DateTime<Local>) -> bool {
+    let mut pruned = false;
+    for flags in self.flags.values_mut() {
+      let before = flags.len();
+      flags.retain(|_, flag| flag.is_set(now));
+      pruned |= flags.len() != before;
+    }
+    self.flags.retain(|_, flags| !flags.is_empty());
+    pruned
+  }
+
+  /// Forgets usage older than we care about.
+  pub fn prune_usage(&mut self, today: NaiveDate) {
+    let oldest = today - chrono::Duration::days(USAGE_HISTORY_DAYS);
+    for days in self.usage.values_mut() {
+      days.retain(|date, _| *date >= oldest);
+    }
+  }
+
+  /// Banks whatever the user didn't use of yesterday's quota, keeping the total
+  /// rolled over time within the limit. Returns whether anything changed.
+  pub fn roll_over(&mut self, user: &str, user_config: &UserConfig, zone: Zone, now: DateTime<Local>) -> bool {
+    let (quota_minutes, rollover) = match (user_config.daily_quota_minutes, &user_config.rollover) {
+      (Some(quota_minutes), Some(rollover)) => (quota_minutes, rollover),
+      _ => return false,
+    };
+
+    let yesterday = zone.date(now).pred();
+    if self.rolled_over.get(user).map_or(false, |date| *date >= yesterday) {
+      return false;
+    }
+    self.rolled_over.insert(user.to_owned(), yesterday);
+
+    let unused = (u64::from(quota_minutes) * 60).saturating_sub(self.get_usage(user, yesterday));
+    let ledger = self.ledger(user);
+    let banked: u64 = ledger
+      .transactions
+      .iter()
+      .filter(|t| t.reason == ROLLOVER_REASON && t.author == ledger::DAEMON_AUTHOR)
+      .map(|t| t.available(now))
+      .sum();
+    let seconds = unused.min((u64::from(rollover.max_minutes) * 60).saturating_sub(banked));
+
+    if seconds > 0 {
+      info!("Rolling over {} unused seconds for {}", seconds, user);
+      let expires = now + chrono::Duration::days(rollover.expiry_days.into());
+      ledger.grant(now, seconds, ROLLOVER_REASON, ledger::DAEMON_AUTHOR, Some(expires));
+    }
+
+    true
+  }
+
+  pub fn prune_ledger(&mut self, now: DateTime<Local>) {
+    for ledger in self.ledger.values_mut() {
+      ledger.prune(now);
+    }
+  }
+}
+
+pub fn get_state_path() -> PathBuf {
+  PathBuf::from(STATE_FILE)
+}
+
+pub fn load() -> Result<State> {
+  let path = get_state_path();
+  match path.exists() {
+    true => {
+      info!("Loading state from {}", STATE_FILE);
+      let reader = BufReader::new(File::open(path)?);
+
+      Ok(serde_json::from_reader(reader)?)
+    }
+    false => Ok(State::default()),
+  }
+}
+
+pub fn save(state: &State) -> Result<()> {
+  let path = get_state_path();
+  match path.parent() {
+    Some(parent) => {
+      if !parent.is_dir() {
+        fs::create_dir_all(parent)?
+      }
+    }
+    None => bail!("Invalid state dir: {}", STATE_FILE),
+  }
+
+  os::write_atomically(&path, &serde_json::to_vec_pretty(state)?)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono::TimeZone;
+
+  #[test]
+  fn test_usage() {
+    let mut state = State::default();
+    let today = NaiveDate::from_ymd(2021, 3, 1);
+
+    assert_eq!(0, state.get_usage("test", today));
+    state.add_usage("test", today, 15);
+    state.add_usage("test", today, 15);
+    state.add_usage("test", today.pred(), 60);
+    assert_eq!(30, state.get_usage("test", today));
+    assert_eq!(60, state.get_usage("test", today.pred()));
+    assert_eq!(0, state.get_usage("other", today));
+  }
+
+  #[test]
+  fn test_overrides() {
+    let mut state = State::default();
+    let now = Local.ymd(2021, 3, 1).and_hms(17, 0, 0);
+    state.overrides.insert(
+      "test".to_owned(),
+      Override {
+        locked: false,
+        until: now + chrono::Duration::minutes(90),
+        reason: "Homework".to_owned(),
+      },
+    );
+
+    assert!(state.get_override("test", now).is_some());
+    assert!(state.get_override("other", now).is_none());
+    assert!(!state.prune_overrides(now));
+
+    let later = now + chrono::Duration::minutes(90);
+    assert!(state.get_override("test", later).is_none());
+    assert!(state.prune_overrides(later));
+    assert!(state.overrides.is_empty());
+  }
+
+  #[test]
+  fn test_pause() {
+    let mut state = State::default();
+    let now = Local.ymd(2021, 3, 1).and_hms(18, 0, 0);
+    assert!(state.get_pause(now).is_none());
+
+    state.pause = Some(Pause {
+      since: now,
+      until: Some(now + chrono::Duration::hours(1)),
+      reason: "Dinner".to_owned(),
+    });
+    assert!(state.get_pause(now).is_some());
+    assert!(!state.prune_pause(now));
+    assert!(state.prune_pause(now + chrono::Duration::hours(1)));
+    assert!(state.pause.is_none());
+
+    state.pause = Some(Pause {
+      since: now,
+      until: None,
+      reason: String::new(),
+    });
+    assert!(state.get_pause(now + chrono::Duration::weeks(1)).is_some());
+  }
+
+  #[test]
+  fn test_pause_codes() {
+    let mut state = State::default();
+    assert!(state.use_pause_code("1234"));
+    assert!(!state.use_pause_code("1234"));
+    assert!(state.use_pause_code("5678"));
+  }
+
+  #[test]
+  fn test_flags() {
+    let mut state = State::default();
+    let now = Local.ymd(2021, 3, 1).and_hms(16, 0, 0);
+    state.set_flag("test", "homework", now, Some(now + chrono::Duration::hours(2)));
+    state.set_flag("test", "chores", now, None);
+
+    assert_eq!(vec!["chores", "homework"], state.get_flags("test", now));
+    assert!(state.get_flags("other", now).is_empty());
+    assert!(!state.prune_flags(now));
+
+    let later = now + chrono::Duration::hours(2);
+    assert_eq!(vec!["chores"], state.get_flags("test", later));
+    assert!(state.prune_flags(later));
+    assert!(state.clear_flag("test", "chores"));
+    assert!(!state.clear_flag("test", "chores"));
+    assert!(state.get_flags("test", later).is_empty());
+  }
+
+  #[test]
+  fn test_prune_usage() {
+    let mut state = State::default();
+    let today = NaiveDate::from_ymd(2021, 3, 1);
+    state.add_usage("test", today - chrono::Duration::days(USAGE_HISTORY_DAYS), 1);
+    state.add_usage("test", today - chrono::Duration::days(USAGE_HISTORY_DAYS + 1), 1);
+
+    state.prune_usage(today);
+    assert_eq!(1, state.usage["test"].len());
+  }
+
+  #[test]
+  fn test_roll_over() -> anyhow::Result<()> {
+    let user_config: UserConfig = serde_json::from_str(
+      r#"{
+        "username": "alice",
+        "schedule": { "open_periods": [] },
+        "daily_quota_minutes": 60,
+        "rollover": { "max_minutes": 45 }
+      }"#,
+    )?;
+    let now = Local.ymd(2020, 1, 2).and_hms(9, 0, 0);
+    let yesterday = now.naive_local().date().pred();
+
+    let mut state = State::default();
+    state.add_usage("alice", yesterday, 40 * 60);
+    assert!(state.roll_over("alice", &user_config, Zone::Local, now));
+    assert_eq!(20 * 60, state.get_balance("alice", now));
+
+    // Only once a day.
+    assert!(!state.roll_over("alice", &user_config, Zone::Local, now));
+
+    // Capped at the limit.
+    let tomorrow = now + chrono::Duration::days(1);
+    assert!(state.roll_over("alice", &user_config, Zone::Local, tomorrow));
+    assert_eq!(45 * 60, state.get_balance("alice", tomorrow));
+
+    // And it expires.
+    assert_eq!(0, state.get_balance("alice", tomorrow + chrono::Duration::days(8)));
+
+    Ok(())
+  }
+}
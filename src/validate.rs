@@ -0,0 +1,578 @@
+//! Checks a config for mistakes that would otherwise be silently ignored by the
+//! schedule engine, like an hour of 25 or two periods that overlap.
+
+use crate::config::{Channel, Config, DateRange, DayPeriod, ExceptionKind, Instant, OpenPeriod, Schedule, TimeOfDay, UserConfig};
+use crate::rules;
+use crate::schedule::{self, MINUTES_PER_WEEK};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+const MAX_HYSTERESIS_MINUTES: u32 = 24 * 60;
+
+/// A single problem with the config.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ValidationError {
+  /// Where the problem is, e.g. `user_config.alice.schedule.open_periods[1]`.
+  pub path: String,
+  pub message: String,
+}
+
+/// Every problem found with a config.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ValidationErrors(pub Vec<ValidationError>);
+
+impl fmt::Display for ValidationErrors {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Invalid config:")?;
+    for error in &self.0 {
+      write!(f, "\n  {}: {}", error.path, error.message)?;
+    }
+    Ok(())
+  }
+}
+
+impl std::error::Error for ValidationErrors {}
+
+struct Checker {
+  errors: Vec<ValidationError>,
+}
+
+impl Checker {
+  fn finish(self) -> Result<(), ValidationErrors> {
+    match self.errors.is_empty() {
+      true => Ok(()),
+      false => Err(ValidationErrors(self.errors)),
+    }
+  }
+
+  fn error(&mut self, path: &str, message: String) {
+    self.errors.push(ValidationError {
+      path: path.to_owned(),
+      message,
+    });
+  }
+
+  fn time(&mut self, path: &str, hour: u8, minute: u8) {
+    if hour > 23 {
+      self.error(path, format!("hour {} is not between 0 and 23", hour));
+    }
+    if minute > 59 {
+      self.error(path, format!("minute {} is not between 0 and 59", minute));
+    }
+  }
+
+  fn instant(&mut self, path: &str, instant: &Instant) {
+    if instant.weekday > 6 {
+      self.error(path, format!("weekday {} is not between 0 (Sunday) and 6", instant.weekday));
+    }
+    self.time(path, instant.hour, instant.minute);
+  }
+
+  fn period(&mut self, path: &str, period: &OpenPeriod) {
+    self.instant(&format!("{}.start", path), &period.start);
+    self.instant(&format!("{}.end", path), &period.end);
+
+    let (start, end) = (schedule::minute_of_week(&period.start), schedule::minute_of_week(&period.end));
+    if start == end {
+      self.error(path, "starts and ends at the same time".to_owned());
+    } else if period.start.weekday == period.end.weekday && end < start {
+      // Probably a typo rather than a period lasting almost a week.
+      self.error(path, "ends before it starts on the same day".to_owned());
+    }
+  }
+
+  fn schedule(&mut self, path: &str, schedule: &Schedule) {
+    let periods = &schedule.open_periods;
+    for (i, period) in periods.iter().enumerate() {
+      self.period(&format!("{}.open_periods[{}]", path, i), period);
+    }
+
+    for (i, period) in schedule.blackout_periods.iter().enumerate() {
+      self.period(&format!("{}.blackout_periods[{}]", path, i), period);
+    }
+
+    for (i, rule) in schedule.cron_rules.iter().enumerate() {
+      if rule.duration_minutes == 0 || i64::from(rule.duration_minutes) > MINUTES_PER_WEEK {
+        self.error(
+          &format!("{}.cron_rules[{}].duration_minutes", path, i),
+          format!("{} is not between 1 and {}", rule.duration_minutes, MINUTES_PER_WEEK),
+        );
+      }
+    }
+
+    for (i, a) in periods.iter().enumerate() {
+      for (j, b) in periods.iter().enumerate().skip(i + 1) {
+        if overlaps(a, b) {
+          self.error(
+            &format!("{}.open_periods[{}]", path, j),
+            format!("overlaps open_periods[{}]", i),
+          );
+        }
+      }
+    }
+  }
+
+  fn url(&mut self, path: &str, url: &str) {
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+      self.error(path, format!("{} is not an http(s) URL", url));
+    }
+  }
+
+  fn time_of_day(&mut self, path: &str, time: &TimeOfDay) {
+    self.time(path, time.hour, time.minute);
+  }
+
+  fn day_period(&mut self, path: &str, period: &DayPeriod) {
+    self.time_of_day(&format!("{}.start", path), &period.start);
+    self.time_of_day(&format!("{}.end", path), &period.end);
+    if period.start == period.end {
+      self.error(path, "starts and ends at the same time".to_owned());
+    }
+  }
+
+  fn date_range(&mut self, path: &str, range: &DateRange) {
+    if !range.yearly && range.end < range.start {
+      self.error(path, format!("ends on {} before it starts on {}", range.end, range.start));
+    }
+  }
+
+  fn user(&mut self, config: &Config, user: &UserConfig) {
+    let path = format!("user_config.{}", user.username);
+    self.schedule(&format!("{}.schedule", path), &user.schedule);
+
+    match user.group.as_ref().map(|name| (name, config.groups.get(name))) {
+      Some((name, None)) => self.error(&format!("{}.group", path), format!("no group called {}", name)),
+      Some((_, Some(group))) => {
+        for (i, period) in user.removed_periods.iter().enumerate() {
+          if !group.schedule.open_periods.contains(period) {
+            self.error(
+              &format!("{}.removed_periods[{}]", path, i),
+              "is not one of the group's periods".to_owned(),
+            );
+          }
+        }
+      }
+      None if !user.removed_periods.is_empty() => {
+        self.error(
+          &format!("{}.removed_periods", path),
+          "removes periods without a group".to_owned(),
+        );
+      }
+      None => {}
+    }
+
+    for (i, name) in user.templates.iter().enumerate() {
+      if !config.templates.contains_key(name) {
+        self.error(&format!("{}.templates[{}]", path, i), format!("no template called {}", name));
+      }
+    }
+
+    if let Some(feed) = &user.calendar_feed {
+      self.url(&format!("{}.calendar_feed.url", path), &feed.url);
+      if feed.poll_minutes == 0 {
+        self.error(&format!("{}.calendar_feed.poll_minutes", path), "must be at least 1".to_owned());
+      }
+    }
+
+    // Any longer and a single setting could lock or unlock whole days.
+    let hysteresis = [
+      ("min_locked_minutes", user.hysteresis.min_locked_minutes),
+      ("min_unlocked_minutes", user.hysteresis.min_unlocked_minutes),
+    ];
+    for (name, minutes) in hysteresis.iter() {
+      if *minutes > MAX_HYSTERESIS_MINUTES {
+        self.error(
+          &format!("{}.hysteresis.{}", path, name),
+          format!("{} is more than {}", minutes, MAX_HYSTERESIS_MINUTES),
+        );
+      }
+    }
+
+    // With no increment, a meeting would postpone the lock forever.
+    if let Some(deferral) = &user.meeting_deferral {
+      let path = format!("{}.meeting_deferral", path);
+      if deferral.increment_minutes == 0 || deferral.increment_minutes > deferral.max_minutes {
+        self.error(
+          &format!("{}.increment_minutes", path),
+          format!("{} is not between 1 and max_minutes ({})", deferral.increment_minutes, deferral.max_minutes),
+        );
+      }
+      for (i, meeting) in deferral.meetings.iter().enumerate() {
+        // An empty string would match every process.
+        if meeting.process.is_empty() {
+          self.error(&format!("{}.meetings[{}].process", path, i), "is empty".to_owned());
+        }
+        if meeting.call_process.as_deref() == Some("") {
+          self.error(&format!("{}.meetings[{}].call_process", path, i), "is empty".to_owned());
+        }
+      }
+    }
+
+    for (i, route) in user.notifications.iter().enumerate() {
+      if let Channel::Webhook { url } = &route.channel {
+        self.url(&format!("{}.notifications[{}].channel.url", path, i), url);
+      }
+    }
+
+    for (i, rule) in user.rules.iter().enumerate() {
+      if let Err(e) = rules::Expr::parse(&rule.when) {
+        self.error(&format!("{}.rules[{}].when", path, i), e.to_string());
+      }
+    }
+
+    for (i, exception) in user.exceptions.iter().enumerate() {
+      let path = format!("{}.exceptions[{}]", path, i);
+      if exception.last_date() < exception.start_date {
+        self.error(
+          &path,
+          format!("ends on {} before it starts on {}", exception.last_date(), exception.start_date),
+        );
+      }
+      if let ExceptionKind::Replace { periods } | ExceptionKind::Add { periods } = &exception.kind {
+        for (j, period) in periods.iter().enumerate() {
+          self.day_period(&format!("{}.periods[{}]", path, j), period);
+        }
+      }
+    }
+  }
+}
+
+/// Do two periods overlap anywhere in the (circular) week?
+fn overlaps(a: &OpenPeriod, b: &OpenPeriod) -> bool {
+  let start = |p: &OpenPeriod| schedule::minute_of_week(&p.start);
+  let length = |p: &OpenPeriod| schedule::period_length(p).num_minutes();
+
+  (start(b) - start(a)).rem_euclid(MINUTES_PER_WEEK) < length(a)
+    || (start(a) - start(b)).rem_euclid(MINUTES_PER_WEEK) < length(b)
+}
+
+/// Checks a single user against the rest of the config.
+pub fn check_user(config: &Config, user: &UserConfig) -> Result<(), ValidationErrors> {
+  let mut checker = Checker { errors: Vec::new() };
+  checker.user(config, user);
+
+  checker.finish()
+}
+
+/// Checks the whole config.
+pub fn check(config: &Config) -> Result<(), ValidationErrors> {
+  let mut checker = Checker { errors: Vec::new() };
+
+  if let Some(timezone) = &config.timezone {
+    if timezone.parse::<Tz>().is_err() {
+      checker.error("timezone", format!("unknown timezone {}", timezone));
+    }
+  }
+
+  for (i, minutes) in config.warning_minutes.iter().enumerate() {
+    if *minutes == 0 {
+      checker.error(&format!("warning_minutes[{}]", i), "must be at least 1".to_owned());
+    } else if config.warning_minutes[..i].contains(minutes) {
+      checker.error(&format!("warning_minutes[{}]", i), format!("{} is listed twice", minutes));
+    }
+  }
+
+  for (i, code) in config.pause_codes.iter().enumerate() {
+    let path = format!("pause_codes[{}]", i);
+    if code.code.is_empty() {
+      checker.error(&format!("{}.code", path), "is empty".to_owned());
+    } else if config.pause_codes[..i].iter().any(|c| c.code == code.code) {
+      checker.error(&format!("{}.code", path), format!("{} is listed twice", code.code));
+    }
+    if code.minutes == Some(0) {
+      checker.error(&format!("{}.minutes", path), "must be at least 1".to_owned());
+    }
+  }
+
+  let mut names: Vec<&String> = config.user_config.keys().collect();
+  names.sort();
+  for name in names {
+    let user = &config.user_config[name];
+    if &user.username != name {
+      checker.error(
+        &format!("user_config.{}.username", name),
+        format!("{} doesn't match {}", user.username, name),
+      );
+    }
+    checker.user(config, user);
+  }
+
+  let mut names: Vec<&String> = config.groups.keys().collect();
+  names.sort();
+  for name in names {
+    checker.schedule(&format!("groups.{}.schedule", name), &config.groups[name].schedule);
+  }
+
+  let mut names: Vec<&String> = config.templates.keys().collect();
+  names.sort();
+  for name in names {
+    let template = &config.templates[name];
+    checker.schedule(&format!("templates.{}.schedule", name), &template.schedule);
+    for (i, range) in template.active.iter().enumerate() {
+      checker.date_range(&format!("templates.{}.active[{}]", name, i), range);
+    }
+  }
+
+  checker.finish()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use anyhow::Result;
+
+  fn errors(json: &str) -> Result<Vec<String>> {
+    let config: Config = serde_json::from_str(json)?;
+
+    Ok(match check(&config) {
+      Ok(()) => vec![],
+      Err(errors) => errors.0.into_iter().map(|e| format!("{}: {}", e.path, e.message)).collect(),
+    })
+  }
+
+  fn user_with_periods(periods: &str) -> String {
+    format!(
+      r#"{{ "user_config": {{ "alice": {{ "username": "alice", "schedule": {{ "open_periods": [{}] }} }} }} }}"#,
+      periods
+    )
+  }
+
+  fn period(start: (u8, u8, u8), end: (u8, u8, u8)) -> String {
+    format!(
+      r#"{{ "start": {{ "weekday": {}, "hour": {}, "minute": {} }},
+           "end": {{ "weekday": {}, "hour": {}, "minute": {} }}, "note": "" }}"#,
+      start.0, start.1, start.2, end.0, end.1, end.2
+    )
+  }
+
+  #[test]
+  fn test_valid() -> Result<()> {
+    let periods = [
+      period((1, 15, 0), (1, 17, 0)),
+      period((1, 17, 0), (1, 18, 0)),
+      // Wraps around the end of the week.
+      period((6, 22, 0), (0, 1, 0)),
+    ];
+    assert_eq!(Vec::<String>::new(), errors(&user_with_periods(&periods.join(",")))?);
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_ranges() -> Result<()> {
+    assert_eq!(
+      vec![
+        "user_config.alice.schedule.open_periods[0].start: weekday 7 is not between 0 (Sunday) and 6",
+        "user_config.alice.schedule.open_periods[0].end: hour 25 is not between 0 and 23",
+        "user_config.alice.schedule.open_periods[0].end: minute 60 is not between 0 and 59",
+      ],
+      errors(&user_with_periods(&period((7, 15, 0), (1, 25, 60))))?
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_lengths() -> Result<()> {
+    assert_eq!(
+      vec!["user_config.alice.schedule.open_periods[0]: starts and ends at the same time"],
+      errors(&user_with_periods(&period((1, 15, 0), (1, 15, 0))))?
+    );
+    assert_eq!(
+      vec!["user_config.alice.schedule.open_periods[0]: ends before it starts on the same day"],
+      errors(&user_with_periods(&period((1, 15, 0), (1, 14, 0))))?
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_cron_rules() -> Result<()> {
+    assert_eq!(
+      vec!["user_config.alice.schedule.cron_rules[0].duration_minutes: 0 is not between 1 and 10080"],
+      errors(
+        r#"{ "user_config": { "alice": { "username": "alice", "schedule": {
+          "open_periods": [], "cron_rules": [ { "cron": "0 7 * * Mon-Fri", "duration_minutes": 0 } ]
+        } } } }"#
+      )?
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_blackout_periods() -> Result<()> {
+    assert_eq!(
+      vec!["user_config.alice.schedule.blackout_periods[0]: starts and ends at the same time"],
+      errors(&format!(
+        r#"{{ "user_config": {{ "alice": {{ "username": "alice", "schedule": {{
+          "open_periods": [], "blackout_periods": [{}]
+        }} }} }} }}"#,
+        period((6, 18, 0), (6, 18, 0))
+      ))?
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_hysteresis() -> Result<()> {
+    assert_eq!(
+      vec!["user_config.alice.hysteresis.min_unlocked_minutes: 1500 is more than 1440"],
+      errors(
+        r#"{ "user_config": { "alice": { "username": "alice", "schedule": { "open_periods": [] },
+          "hysteresis": { "min_locked_minutes": 240, "min_unlocked_minutes": 1500 }
+        } } }"#
+      )?
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_meeting_deferral() -> Result<()> {
+    let deferral = |increment: u32, max: u32| {
+      format!(
+        r#"{{ "user_config": {{ "alice": {{ "username": "alice", "schedule": {{ "open_periods": [] }},
+          "meeting_deferral": {{ "meetings": [], "increment_minutes": {}, "max_minutes": {} }}
+        }} }} }}"#,
+        increment, max
+      )
+    };
+
+    assert!(errors(&deferral(5, 30))?.is_empty());
+    assert!(errors(&deferral(30, 30))?.is_empty());
+    assert_eq!(
+      vec!["user_config.alice.meeting_deferral.increment_minutes: 0 is not between 1 and max_minutes (30)"],
+      errors(&deferral(0, 30))?
+    );
+    assert_eq!(
+      vec!["user_config.alice.meeting_deferral.increment_minutes: 10 is not between 1 and max_minutes (5)"],
+      errors(&deferral(10, 5))?
+    );
+
+    assert_eq!(
+      vec![
+        "user_config.alice.meeting_deferral.meetings[1].process: is empty",
+        "user_config.alice.meeting_deferral.meetings[1].call_process: is empty",
+      ],
+      errors(
+        r#"{ "user_config": { "alice": { "username": "alice", "schedule": { "open_periods": [] },
+          "meeting_deferral": { "max_minutes": 30, "meetings": [
+            { "name": "Zoom", "process": "zoom.us", "call_process": "CptHost" },
+            { "name": "Nothing", "process": "", "call_process": "" }
+          ] }
+        } } }"#
+      )?
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_rules() -> Result<()> {
+    assert_eq!(
+      vec!["user_config.alice.rules[1].when: column 1: unknown fact bedtime"],
+      errors(
+        r#"{ "user_config": { "alice": { "username": "alice", "schedule": { "open_periods": [] },
+          "rules": [
+            { "when": "weekend && used_weekdays > 5h", "action": "lock" },
+            { "when": "bedtime", "action": "lock" }
+          ]
+        } } }"#
+      )?
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_overlaps() -> Result<()> {
+    let periods = [period((6, 22, 0), (0, 2, 0)), period((0, 1, 0), (0, 3, 0))];
+    assert_eq!(
+      vec!["user_config.alice.schedule.open_periods[1]: overlaps open_periods[0]"],
+      errors(&user_with_periods(&periods.join(",")))?
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_timezone() -> Result<()> {
+    assert_eq!(
+      vec!["timezone: unknown timezone Mars/Olympus_Mons"],
+      errors(r#"{ "user_config": {}, "timezone": "Mars/Olympus_Mons" }"#)?
+    );
+    assert_eq!(
+      Vec::<String>::new(),
+      errors(r#"{ "user_config": {}, "timezone": "America/New_York" }"#)?
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_notifications() -> Result<()> {
+    assert_eq!(
+      vec!["user_config.alice.notifications[1].channel.url: example.com/hook is not an http(s) URL"],
+      errors(
+        r#"{ "user_config": { "alice": { "username": "alice", "schedule": { "open_periods": [] },
+          "notifications": [
+            { "channel": { "type": "webhook", "url": "https://example.com/hook" } },
+            { "channel": { "type": "webhook", "url": "example.com/hook" } }
+          ]
+        } } }"#
+      )?
+    );
+    assert_eq!(
+      vec!["warning_minutes[1]: must be at least 1", "warning_minutes[2]: 15 is listed twice"],
+      errors(r#"{ "user_config": {}, "warning_minutes": [15, 0, 15, 5] }"#)?
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_pause_codes() -> Result<()> {
+    assert_eq!(
+      vec![
+        "pause_codes[1].code: is empty",
+        "pause_codes[2].code: 1234 is listed twice",
+        "pause_codes[3].minutes: must be at least 1",
+      ],
+      errors(
+        r#"{ "user_config": {}, "pause_codes": [
+          { "code": "1234" }, { "code": "" }, { "code": "1234", "minutes": 30 }, { "code": "5678", "minutes": 0 }
+        ] }"#
+      )?
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_references() -> Result<()> {
+    assert_eq!(
+      vec![
+        "user_config.alice.group: no group called kids",
+        "user_config.alice.templates[0]: no template called summer",
+        "user_config.alice.calendar_feed.url: webcal://example.com/family.ics is not an http(s) URL",
+        "user_config.alice.calendar_feed.poll_minutes: must be at least 1",
+        "user_config.alice.exceptions[0]: ends on 2021-01-01 before it starts on 2021-01-02",
+      ],
+      errors(
+        r#"{ "user_config": { "alice": {
+          "username": "alice",
+          "schedule": { "open_periods": [] },
+          "group": "kids",
+          "templates": ["summer"],
+          "calendar_feed": { "url": "webcal://example.com/family.ics", "poll_minutes": 0 },
+          "exceptions": [ { "start_date": "2021-01-02", "end_date": "2021-01-01", "type": "locked" } ]
+        } } }"#
+      )?
+    );
+
+    Ok(())
+  }
+}
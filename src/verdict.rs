@@ -0,0 +1,352 @@
+//! Whether a user should be locked right now. The runloop enforces this and
+//! the API reports it, so they can't disagree. Each of these beats the ones
+//! before it:
+//!
+//! 1. the schedule, including the calendar feed
+//! 2. the daily quota
+//! 3. banked time, which unlocks the user when neither of those would
+//! 4. the parent's rules
+//! 5. an override
+//! 6. a household pause
+//!
+//! Postponing a lock for a meeting depends on what's running, so that's left
+//! to the runloop.
+
+use crate::config::{RuleAction, UserConfig};
+use crate::rules::{self, Decision};
+use crate::schedule::{self, Policy, TransitionKind, Zone};
+use crate::state::{Override, Pause, State};
+use chrono::{DateTime, Duration, Local};
+use serde::Serialize;
+
+/// What decided a verdict.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Reason {
+  Schedule,
+  Quota,
+  Bank,
+  Rule { rule: usize, reason: String },
+  Override { reason: String },
+  Pause { reason: String },
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Verdict {
+  pub locked: bool,
+  pub reason: Reason,
+  /// When the user will be locked, if they're unlocked and it's known.
+  pub lock_at: Option<DateTime<Local>>,
+  /// Whether they're unlocked on banked time.
+  pub spending_bank: bool,
+  /// What the rules said, even if something beat them.
+  pub rule: Option<Decision>,
+  #[serde(rename = "override")]
+  pub manual: Option<Override>,
+  pub pause: Option<Pause>,
+}
+
+/// A stretch of time the user is expected to be locked or unlocked.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Span {
+  pub start: DateTime<Local>,
+  pub end: DateTime<Local>,
+  pub locked: bool,
+  pub reason: Reason,
+  /// The note of the schedule's open period, if it's open.
+  pub note: Option<String>,
+}
+
+/// How much of the daily quota is left, if there is one.
+pub fn get_quota_remaining(quota_minutes: Option<u32>, used_seconds: u64) -> Option<Duration> {
+  quota_minutes.map(|q| Duration::minutes(q.into()) - Duration::seconds(used_seconds as i64))
+}
+
+/// The verdict for the user at `now`, with the schedule worked out from the
+/// policy.
+pub fn decide(user: &str, user_config: &UserConfig, policy: &Policy, state: &State, now: DateTime<Local>) -> Verdict {
+  let open = schedule::find_max_open_period(now, policy);
+  // Warnings count down to the end of the open time, including any periods
+  // that follow straight on.
+  let lock_at = match schedule::next_transition(now, policy) {
+    Some(t) if open.is_some() && t.kind == TransitionKind::Lock => Some(t.at),
+    _ => None,
+  };
+
+  let note = open.as_ref().map(|o| o.period.note.as_str());
+  judge(user, user_config, policy.zone, note, lock_at, state, now)
+}
+
+/// The verdict for the user at `now`, given the note of the schedule's open
+/// period (if it's open) and when it locks.
+pub fn judge(
+  user: &str,
+  user_config: &UserConfig,
+  zone: Zone,
+  period: Option<&str>,
+  mut lock_at: Option<DateTime<Local>>,
+  state: &State,
+  now: DateTime<Local>,
+) -> Verdict {
+  let mut locked = period.is_none();
+  let mut reason = Reason::Schedule;
+
+  let used_seconds = state.get_usage(user, zone.date(now));
+  if let Some(remaining) = get_quota_remaining(user_config.daily_quota_minutes, used_seconds) {
+    if remaining <= Duration::zero() {
+      locked = true;
+      reason = Reason::Quota;
+      lock_at = None;
+    } else {
+      lock_at = lock_at.map(|end| end.min(now + remaining));
+    }
+  }
+
+  let manual = state.get_override(user, now).cloned();
+  let pause = state.get_pause(now).cloned();
+  let balance = state.get_balance(user, now);
+  let mut spending_bank = pause.is_none() && manual.is_none() && locked && balance > 0;
+  if spending_bank {
+    locked = false;
+    reason = Reason::Bank;
+    lock_at = Some(now + Duration::seconds(balance as i64));
+  }
+
+  let facts = rules::Facts::gather(user, zone, now, period, state);
+  let rule = rules::decide(&user_config.rules, &facts);
+  if let Some(decision) = &rule {
+    let lock = decision.action == RuleAction::Lock;
+    if lock != locked || spending_bank {
+      // Neither the schedule nor the bank says when this ends any more.
+      lock_at = None;
+      spending_bank = false;
+    }
+    locked = lock;
+    reason = Reason::Rule {
+      rule: decision.rule,
+      reason: decision.reason.clone(),
+    };
+  }
+
+  if let Some(manual) = &manual {
+    lock_at = match manual.locked {
+      true => None,
+      false => Some(lock_at.filter(|_| !locked).map_or(manual.until, |end| end.max(manual.until))),
+    };
+    locked = manual.locked;
+    reason = Reason::Override {
+      reason: manual.reason.clone(),
+    };
+  }
+
+  if let Some(pause) = &pause {
+    locked = true;
+    lock_at = None;
+    reason = Reason::Pause {
+      reason: pause.reason.clone(),
+    };
+  }
+
+  Verdict {
+    locked,
+    reason,
+    lock_at,
+    spending_bank,
+    rule,
+    manual,
+    pause,
+  }
+}
+
+/// The verdicts from `from` until `to`, a minute at a time. From `now` on,
+/// the user is assumed to be logged in whenever they're unlocked, so their
+/// quota and bank run down as fast as they can.
+pub fn timeline(
+  user: &str,
+  user_config: &UserConfig,
+  policy: &Policy,
+  state: &State,
+  now: DateTime<Local>,
+  from: DateTime<Local>,
+  to: DateTime<Local>,
+) -> Vec<Span> {
+  let schedule = schedule::timeline(policy, from, to);
+  let mut intervals = schedule.iter().peekable();
+  let mut state = state.clone();
+  let mut session = None;
+
+  let mut spans: Vec<Span> = Vec::new();
+  let mut time = from;
+  while time < to {
+    while intervals.peek().map_or(false, |i| i.end <= time) {
+      intervals.next();
+    }
+    let note = intervals.peek().and_then(|i| i.note.as_deref());
+    let end = (time + Duration::minutes(1)).min(to);
+
+    if time >= now {
+      state.roll_over(user, user_config, policy.zone, time);
+    }
+    let verdict = judge(user, user_config, policy.zone, note, None, &state, time);
+    let seconds = (end - time).num_seconds() as u64;
+    if time >= now && !verdict.locked {
+      state.add_usage(user, policy.zone.date(time), seconds);
+    }
+    session = match time >= now && verdict.spending_bank {
+      true => state.ledger(user).spend(time, seconds, session).1,
+      false => None,
+    };
+
+    match spans.last_mut() {
+      Some(last) if last.locked == verdict.locked && last.reason == verdict.reason && last.note.as_deref() == note => {
+        last.end = end
+      }
+      _ => spans.push(Span {
+        start: time,
+        end,
+        locked: verdict.locked,
+        reason: verdict.reason,
+        note: note.map(str::to_owned),
+      }),
+    }
+    time = end;
+  }
+
+  spans
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono::TimeZone;
+
+  fn user_config(extra: &str) -> UserConfig {
+    serde_json::from_str(&format!(
+      r#"{{
+        "username": "alice",
+        "schedule": {{ "open_periods": [
+          {{ "start": {{ "weekday": 3, "hour": 15, "minute": 0 }},
+            "end": {{ "weekday": 3, "hour": 17, "minute": 0 }},
+            "note": "" }}
+        ] }}
+        {}
+      }}"#,
+      extra
+    ))
+    .unwrap()
+  }
+
+  fn verdict(user_config: &UserConfig, state: &State, now: DateTime<Local>) -> Verdict {
+    let config = serde_json::from_str(r#"{ "user_config": {} }"#).unwrap();
+    decide("alice", user_config, &Policy::for_user(&config, user_config), state, now)
+  }
+
+  #[test]
+  fn test_get_quota_remaining() {
+    assert_eq!(None, get_quota_remaining(None, 100));
+    assert_eq!(Some(Duration::minutes(89)), get_quota_remaining(Some(90), 60));
+    assert_eq!(Some(Duration::seconds(-30)), get_quota_remaining(Some(1), 90));
+  }
+
+  #[test]
+  fn test_schedule_and_quota() {
+    // 2020-01-01 is a Wednesday.
+    let now = Local.ymd(2020, 1, 1).and_hms(16, 0, 0);
+    let mut state = State::default();
+    let user_config = user_config(r#", "daily_quota_minutes": 90"#);
+
+    let verdict = verdict(&user_config, &state, now);
+    assert_eq!((false, Reason::Schedule), (verdict.locked, verdict.reason));
+    assert_eq!(Some(Local.ymd(2020, 1, 1).and_hms(17, 0, 0)), verdict.lock_at);
+
+    // Less quota left than open time.
+    state.add_usage("alice", now.naive_local().date(), 60 * 60);
+    assert_eq!(Some(now + Duration::minutes(30)), self::verdict(&user_config, &state, now).lock_at);
+
+    state.add_usage("alice", now.naive_local().date(), 30 * 60);
+    let verdict = self::verdict(&user_config, &state, now);
+    assert_eq!((true, Reason::Quota, None), (verdict.locked, verdict.reason, verdict.lock_at));
+  }
+
+  #[test]
+  fn test_bank_and_rules() {
+    let now = Local.ymd(2020, 1, 1).and_hms(18, 0, 0);
+    let mut state = State::default();
+    state.ledger("alice").grant(now, 600, "Chores", "parent", None);
+
+    // The bank unlocks the user once the schedule has locked them.
+    let user_config = user_config("");
+    let verdict = verdict(&user_config, &state, now);
+    assert_eq!((false, Reason::Bank, true), (verdict.locked, verdict.reason, verdict.spending_bank));
+    assert_eq!(Some(now + Duration::minutes(10)), verdict.lock_at);
+
+    // But it can't undo a rule that locks them.
+    let user_config = self::user_config(r#", "rules": [ { "when": "time >= 17:30", "action": "lock" } ]"#);
+    let verdict = self::verdict(&user_config, &state, now);
+    assert!(verdict.locked && !verdict.spending_bank);
+    assert_eq!(Reason::Rule { rule: 0, reason: String::new() }, verdict.reason);
+
+    // And a rule that unlocks them saves the bank for later.
+    let user_config = self::user_config(r#", "rules": [ { "when": "time >= 17:30", "action": "unlock" } ]"#);
+    let verdict = self::verdict(&user_config, &state, now);
+    assert!(!verdict.locked && !verdict.spending_bank);
+  }
+
+  #[test]
+  fn test_override_and_pause() {
+    let now = Local.ymd(2020, 1, 1).and_hms(16, 0, 0);
+    let mut state = State::default();
+    let user_config = user_config(r#", "rules": [ { "when": "open", "action": "lock" } ]"#);
+
+    state.overrides.insert(
+      "alice".to_owned(),
+      Override {
+        locked: false,
+        until: now + Duration::hours(2),
+        reason: "Film night".to_owned(),
+      },
+    );
+    let verdict = verdict(&user_config, &state, now);
+    assert!(!verdict.locked);
+    assert_eq!(Some(now + Duration::hours(2)), verdict.lock_at);
+    assert_eq!(Reason::Override { reason: "Film night".to_owned() }, verdict.reason);
+
+    state.pause = Some(Pause {
+      since: now,
+      until: None,
+      reason: "Dinner".to_owned(),
+    });
+    let verdict = self::verdict(&user_config, &state, now);
+    assert_eq!((true, None), (verdict.locked, verdict.lock_at));
+    assert_eq!(Reason::Pause { reason: "Dinner".to_owned() }, verdict.reason);
+  }
+
+  #[test]
+  fn test_timeline() {
+    let day = |hour| Local.ymd(2020, 1, 1).and_hms(hour, 0, 0);
+    let mut state = State::default();
+    state.ledger("alice").grant(day(0), 30 * 60, "Chores", "parent", None);
+    let user_config = user_config(r#", "daily_quota_minutes": 60"#);
+    let config = serde_json::from_str(r#"{ "user_config": {} }"#).unwrap();
+    let policy = Policy::for_user(&config, &user_config);
+
+    let spans: Vec<_> = timeline("alice", &user_config, &policy, &state, day(0), day(0), day(23))
+      .into_iter()
+      .map(|s| (s.start, s.end, s.locked, s.reason))
+      .collect();
+    let half_past = |hour| day(hour) + Duration::minutes(30);
+    assert_eq!(
+      vec![
+        // The bank unlocks them whenever the schedule doesn't, until it runs out.
+        (day(0), half_past(0), false, Reason::Bank),
+        (half_past(0), day(15), true, Reason::Schedule),
+        // Banked time counts towards the quota too, and once that's used up
+        // it lasts all day, whatever the schedule says.
+        (day(15), half_past(15), false, Reason::Schedule),
+        (half_past(15), day(17), true, Reason::Quota),
+        (day(17), day(23), true, Reason::Quota),
+      ],
+      spans
+    );
+  }
+}